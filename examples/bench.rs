@@ -0,0 +1,260 @@
+//! Measures the throughput of `/api/validate` and of the authentication ceremony
+//! (`GET /api/authenticate` followed by `POST /api/authenticate`) of a running webauthn-tiny
+//! instance under concurrency.
+//!
+//! The user must exist in the server's password file and must not have any credentials yet, since
+//! the benchmark registers one soft token per worker. For example:
+//!
+//! ```console
+//! webauthn-tiny --rp-id=localhost --rp-origin=https://localhost:8080 --database-url=memory: ...
+//! cargo run --release --example bench -- --username=user --password=password
+//! ```
+
+use anyhow::{bail, Context};
+use clap::Parser;
+use reqwest::{header, Client, RequestBuilder, Response, StatusCode};
+use serde_json::json;
+use std::time::{Duration, Instant};
+use webauthn_authenticator_rs::{prelude::Url, softtoken::SoftToken, WebauthnAuthenticator};
+use webauthn_rs_proto::{CreationChallengeResponse, RequestChallengeResponse};
+
+#[derive(Parser)]
+struct Cli {
+    #[clap(long, help = "URL of the server", default_value = "http://[::1]:8080")]
+    url: String,
+    #[clap(
+        long,
+        help = "Relying Party origin the server is configured with",
+        default_value = "https://localhost:8080"
+    )]
+    origin: Url,
+    #[clap(long, help = "Username from the password file")]
+    username: String,
+    #[clap(long, help = "Password of the user")]
+    password: String,
+    #[clap(long, help = "Number of concurrent workers", default_value_t = 16)]
+    concurrency: usize,
+    #[clap(long, help = "Seconds to run each benchmark for", default_value_t = 10)]
+    duration: u64,
+}
+
+/// A client session that tracks the session cookie itself, since the cookie is marked as secure
+/// while the benchmark talks plain HTTP.
+struct Session {
+    client: Client,
+    url: String,
+    cookie: Option<String>,
+}
+
+impl Session {
+    async fn login(cli: &Cli) -> anyhow::Result<Self> {
+        let mut session = Self {
+            client: Client::new(),
+            url: cli.url.clone(),
+            cookie: None,
+        };
+
+        let response = session
+            .send(
+                session
+                    .client
+                    .get(format!("{}/authenticate", session.url))
+                    .basic_auth(&cli.username, Some(&cli.password)),
+            )
+            .await?;
+        if !response.status().is_success() {
+            bail!("password authentication failed: {}", response.status());
+        }
+
+        Ok(session)
+    }
+
+    async fn send(&mut self, mut request: RequestBuilder) -> anyhow::Result<Response> {
+        if let Some(cookie) = &self.cookie {
+            request = request.header(header::COOKIE, cookie);
+        }
+
+        let response = request.send().await?;
+        if let Some(cookie) = response
+            .headers()
+            .get(header::SET_COOKIE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+        {
+            self.cookie = Some(cookie.to_string());
+        }
+
+        Ok(response)
+    }
+
+    async fn get(&mut self, path: &str) -> anyhow::Result<Response> {
+        let request = self.client.get(format!("{}{path}", self.url));
+        self.send(request).await
+    }
+
+    async fn post(&mut self, path: &str, body: &impl serde::Serialize) -> anyhow::Result<Response> {
+        let request = self.client.post(format!("{}{path}", self.url)).json(body);
+        self.send(request).await
+    }
+
+    async fn register(
+        &mut self,
+        origin: &Url,
+        name: String,
+        authenticator: &mut WebauthnAuthenticator<SoftToken>,
+    ) -> anyhow::Result<()> {
+        let challenge: CreationChallengeResponse = self
+            .get("/api/register")
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let credential = authenticator
+            .do_registration(origin.clone(), challenge)
+            .map_err(|err| anyhow::anyhow!("registration failed: {err:?}"))?;
+        self.post(
+            "/api/register",
+            &json!({ "name": name, "credential": credential }),
+        )
+        .await?
+        .error_for_status()?;
+
+        Ok(())
+    }
+
+    async fn authenticate(
+        &mut self,
+        origin: &Url,
+        authenticator: &mut WebauthnAuthenticator<SoftToken>,
+    ) -> anyhow::Result<()> {
+        let challenge: RequestChallengeResponse = self
+            .get("/api/authenticate")
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let credential = authenticator
+            .do_authentication(origin.clone(), challenge)
+            .map_err(|err| anyhow::anyhow!("authentication failed: {err:?}"))?;
+        self.post("/api/authenticate", &credential)
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
+
+struct Worker {
+    session: Session,
+    authenticator: WebauthnAuthenticator<SoftToken>,
+}
+
+/// Runs `iteration` on every worker concurrently for the given duration, returning the workers
+/// and the total number of completed iterations.
+async fn run<F>(
+    workers: Vec<Worker>,
+    duration: Duration,
+    origin: &Url,
+    iteration: F,
+) -> anyhow::Result<(Vec<Worker>, usize)>
+where
+    F: for<'a> Fn(
+            &'a mut Worker,
+            &'a Url,
+        ) -> std::pin::Pin<
+            Box<dyn std::future::Future<Output = anyhow::Result<()>> + Send + 'a>,
+        > + Copy
+        + Send
+        + 'static,
+{
+    let handles: Vec<_> = workers
+        .into_iter()
+        .map(|mut worker| {
+            let origin = origin.clone();
+            tokio::spawn(async move {
+                let start = Instant::now();
+                let mut count = 0;
+                while start.elapsed() < duration {
+                    iteration(&mut worker, &origin).await?;
+                    count += 1;
+                }
+                Ok::<_, anyhow::Error>((worker, count))
+            })
+        })
+        .collect();
+
+    let mut workers = Vec::new();
+    let mut total = 0;
+    for handle in handles {
+        let (worker, count) = handle.await??;
+        workers.push(worker);
+        total += count;
+    }
+
+    Ok((workers, total))
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let duration = Duration::from_secs(cli.duration);
+
+    // A user without credentials is logged in by starting an authentication, which allows
+    // registering a soft token for every worker.
+    let mut admin = Session::login(&cli).await?;
+    if admin.get("/api/authenticate").await?.status() != StatusCode::NO_CONTENT {
+        bail!("user {} must not have any credentials", cli.username);
+    }
+
+    let mut workers = Vec::new();
+    for i in 0..cli.concurrency {
+        let (token, _) = SoftToken::new(true).context("creating soft token")?;
+        let mut authenticator = WebauthnAuthenticator::new(token);
+        admin
+            .register(&cli.origin, format!("bench-{i}"), &mut authenticator)
+            .await?;
+
+        let mut session = Session::login(&cli).await?;
+        session
+            .authenticate(&cli.origin, &mut authenticator)
+            .await?;
+        workers.push(Worker {
+            session,
+            authenticator,
+        });
+    }
+
+    let (workers, validations) = run(workers, duration, &cli.origin, |worker, _| {
+        Box::pin(async move {
+            worker
+                .session
+                .get("/api/validate")
+                .await?
+                .error_for_status()?;
+            Ok(())
+        })
+    })
+    .await?;
+    println!(
+        "/api/validate: {validations} requests in {}s ({:.1} requests/s)",
+        cli.duration,
+        validations as f64 / duration.as_secs_f64()
+    );
+
+    let (_, authentications) = run(workers, duration, &cli.origin, |worker, origin| {
+        Box::pin(async move {
+            worker
+                .session
+                .authenticate(origin, &mut worker.authenticator)
+                .await
+        })
+    })
+    .await?;
+    println!(
+        "/api/authenticate: {authentications} ceremonies in {}s ({:.1} ceremonies/s)",
+        cli.duration,
+        authentications as f64 / duration.as_secs_f64()
+    );
+
+    Ok(())
+}
@@ -14,14 +14,79 @@ let
       (pkgs.runCommand "generated-password-file" { } (
         ''
           touch $out
-          salt=$(${pkgs.openssl}/bin/openssl rand -hex 16)
         ''
         + (concatStringsSep ";" (
           mapAttrsToList (username: password: ''
-            echo ${username}:$(printf "${password}" | ${pkgs.libargon2}/bin/argon2 $salt -id -e) >> $out
+            printf "${password}" | ${lib.getExe pkgs.webauthn-tiny} hash-password ${username} >> $out
           '') cfg.basicAuth
         ))
       ));
+  configFile = (pkgs.formats.toml { }).generate "webauthn-tiny.toml" {
+    admin-user = cfg.admins.users;
+    admin-group = cfg.admins.groups;
+    hosts = mapAttrs (_: host: {
+      device-bound = host.deviceBound;
+      allowed-groups = host.allowedGroups;
+    }) cfg.hosts;
+    relying-parties = mapAttrs (
+      name: rp:
+      {
+        rp-id = rp.id;
+        rp-origin = rp.origin;
+        extra-allowed-origin = rp.extraAllowedOrigins;
+      }
+      // optionalAttrs (rp.cookieDomain != null) { cookie-domain = rp.cookieDomain; }
+      // optionalAttrs (rp.passwordFile != null) {
+        # The credentials directory of the service, since the file cannot use
+        # $CREDENTIALS_DIRECTORY.
+        password-file = "/run/credentials/webauthn-tiny.service/password-file-${name}";
+      }
+    ) cfg.relyingParties;
+  };
+  relyingPartiesWithPasswordFile = filterAttrs (_: rp: rp.passwordFile != null) cfg.relyingParties;
+  socketPath = "/run/webauthn-tiny.sock";
+  upstream = if cfg.unixSocket then "http://unix:${socketPath}:" else "http://[::1]:8080";
+  protectedVirtualHost = authVirtualHost: {
+    extraConfig = ''
+      auth_request /auth;
+      error_page 401 = @error401;
+      auth_request_set $set_cookie $upstream_http_set_cookie;
+      auth_request_set $remote_user $upstream_http_remote_user;
+      auth_request_set $remote_name $upstream_http_remote_name;
+      auth_request_set $remote_email $upstream_http_remote_email;
+      auth_request_set $remote_groups $upstream_http_remote_groups;
+      more_set_headers "Set-Cookie: $set_cookie";
+    '';
+    locations."= /auth" = {
+      proxyPass = "${upstream}/api/validate";
+      extraConfig = ''
+        internal;
+        proxy_pass_request_body off;
+        proxy_set_header Content-Length "";
+        proxy_set_header Host $host;
+        proxy_set_header X-Forwarded-Host $host;
+      '';
+    };
+    locations."@error401".return =
+      "307 $scheme://${authVirtualHost}/authenticate?redirect_url=https://$http_host";
+  };
+  authVirtualHost = {
+    inherit (cfg.nginx) enableACME useACMEHost;
+    forceSSL = true; # webauthn is only available over HTTPS
+    locations."/" = {
+      proxyPass = upstream;
+      extraConfig = ''
+        proxy_set_header Host            $host;
+        proxy_set_header X-Forwarded-For $proxy_add_x_forwarded_for;
+      '';
+    };
+  };
+  virtualHostsOf =
+    nginx:
+    genAttrs nginx.protectedVirtualHosts (_: protectedVirtualHost nginx.virtualHost)
+    // {
+      ${nginx.virtualHost} = authVirtualHost;
+    };
   sessionSecretFile =
     if (cfg.sessionSecretFile != null) then
       cfg.sessionSecretFile
@@ -51,8 +116,8 @@ in
         default = null;
         description = ''
           The path to a password file. This file must contain lines in the form
-          of "<username>:<argon2_hash>". A valid Argon2 hash can be generated
-          using the `libargon2` package like so: `argon2 <salt> -id -e`.
+          of "<username>:<argon2_hash>". A valid line can be generated with
+          `webauthn-tiny hash-password <username>`.
         '';
       };
       sessionSecretFile = mkOption {
@@ -63,6 +128,92 @@ in
           You can use `openssl rand -hex 64` to generate a session secret.
         '';
       };
+      databaseUrl = mkOption {
+        type = types.nullOr types.str;
+        default = null;
+        description = ''
+          The URL of the database to store state in. By default a SQLite
+          database in the state directory is used. A PostgreSQL URL requires
+          the package to be built with the `postgres` cargo feature.
+        '';
+        example = "postgres://webauthn-tiny@db.example.com/webauthn-tiny";
+      };
+      storageKeyFile = mkOption {
+        type = types.nullOr types.path;
+        default = null;
+        description = ''
+          The path to a file containing a key (64 hex digits) to encrypt stored
+          credentials and sessions with. You can use `openssl rand -hex 32` to
+          generate a storage key.
+        '';
+      };
+      previousStorageKeyFiles = mkOption {
+        type = types.listOf types.path;
+        default = [ ];
+        description = ''
+          Storage keys that were replaced by `storageKeyFile`. Values
+          encrypted with them are re-encrypted with the current key in the
+          background.
+        '';
+      };
+      backups = {
+        enable = mkEnableOption "scheduled backups of the SQLite database into the backups directory of the state directory";
+        interval = mkOption {
+          type = types.ints.positive;
+          default = 86400;
+          description = "Seconds between backups.";
+        };
+        retention = mkOption {
+          type = types.ints.positive;
+          default = 7;
+          description = "Number of backups to keep.";
+        };
+      };
+      unixSocket = mkOption {
+        type = types.bool;
+        default = false;
+        description = ''
+          Whether nginx should reach the service through a Unix socket passed
+          by systemd socket activation, instead of over TCP on [::1]:8080.
+          The socket is only accessible to nginx and root, who can reach the
+          metrics and the localhost admin APIs through it.
+        '';
+      };
+      purgeRemovedUsers = mkOption {
+        type = types.bool;
+        default = false;
+        description = ''
+          Whether to delete users, along with their credentials, that are no
+          longer present in the password file on startup.
+        '';
+      };
+      admins = {
+        users = mkOption {
+          type = types.listOf types.str;
+          default = [ ];
+          description = "Users allowed to use the admin console at /admin.";
+        };
+        groups = mkOption {
+          type = types.listOf types.str;
+          default = [ ];
+          description = ''
+            Groups whose users are allowed to use the admin console at /admin.
+          '';
+        };
+      };
+      counterRegressionAction = mkOption {
+        type = types.enum [
+          "log"
+          "metric"
+          "disable"
+        ];
+        default = "log";
+        description = ''
+          Action taken when a credential's signature counter regresses, which
+          may indicate a cloned authenticator. Each action includes the
+          previous ones; "disable" disables the credential.
+        '';
+      };
       relyingParty = {
         id = mkOption {
           type = types.str;
@@ -89,6 +240,116 @@ in
           '';
           example = [ "https://subdomain.mywebsite.com" ];
         };
+        rejectBackupEligible = mkOption {
+          type = types.bool;
+          default = false;
+          description = ''
+            Whether to reject registration of backup-eligible (synced)
+            credentials.
+          '';
+        };
+        deviceBoundHosts = mkOption {
+          type = types.listOf types.str;
+          default = [ ];
+          description = ''
+            Protected hosts that only accept sessions authenticated with a
+            device-bound (non-synced) credential.
+          '';
+          example = [ "secure.mywebsite.com" ];
+        };
+      };
+      relyingParties = mkOption {
+        type = types.attrsOf (
+          types.submodule {
+            options = {
+              id = mkOption {
+                type = types.str;
+                description = "The ID of the Relying Party, like `relyingParty.id`.";
+                example = "otherwebsite.com";
+              };
+              origin = mkOption {
+                type = types.str;
+                description = "The origin of the Relying Party, like `relyingParty.origin`.";
+                example = "https://auth.otherwebsite.com";
+              };
+              extraAllowedOrigins = mkOption {
+                type = types.listOf types.str;
+                default = [ ];
+                description = "Extra allowed origins of the Relying Party.";
+              };
+              cookieDomain = mkOption {
+                type = types.nullOr types.str;
+                default = null;
+                description = ''
+                  The domain of the session cookie, the ID of the Relying Party by
+                  default. Requests are served by the Relying Party whose cookie
+                  domain covers their host.
+                '';
+              };
+              passwordFile = mkOption {
+                type = types.nullOr types.path;
+                default = null;
+                description = ''
+                  The password file of the users that may log in to this Relying
+                  Party, in the format of `basicAuthFile`. By default the users of
+                  `basicAuthFile` or `basicAuth` may log in.
+                '';
+              };
+              nginx = {
+                virtualHost = mkOption {
+                  type = types.str;
+                  description = ''
+                    The virtual host that this Relying Party will serve on.
+                  '';
+                };
+                protectedVirtualHosts = mkOption {
+                  type = types.listOf types.str;
+                  default = [ ];
+                  description = ''
+                    A list of virtual hosts that will be protected by this Relying
+                    Party.
+                  '';
+                };
+              };
+            };
+          }
+        );
+        default = { };
+        description = ''
+          Relying Parties on other domains served in addition to
+          `relyingParty`, keyed by a name of lowercase letters, digits and
+          dashes. Credentials and sessions are separate for each Relying Party.
+        '';
+      };
+      hosts = mkOption {
+        type = types.attrsOf (
+          types.submodule {
+            options = {
+              deviceBound = mkOption {
+                type = types.bool;
+                default = false;
+                description = ''
+                  Whether to only accept sessions authenticated with a
+                  device-bound (non-synced) credential on this host.
+                '';
+              };
+              allowedGroups = mkOption {
+                type = types.listOf types.str;
+                default = [ ];
+                description = ''
+                  Groups whose users may access this host, all users if empty.
+                '';
+              };
+            };
+          }
+        );
+        default = { };
+        description = ''
+          Policies of protected hosts, keyed by lowercase host name.
+        '';
+        example = {
+          "git.mywebsite.com".allowedGroups = [ "developers" ];
+        };
       };
       nginx = {
         enable = mkEnableOption "nginx support";
@@ -122,38 +383,19 @@ in
   config = mkIf cfg.enable {
     services.nginx = mkIf cfg.nginx.enable {
       enable = true;
-      virtualHosts =
-        genAttrs cfg.nginx.protectedVirtualHosts (_: {
-          extraConfig = ''
-            auth_request /auth;
-            error_page 401 = @error401;
-            auth_request_set $set_cookie $upstream_http_set_cookie;
-            more_set_headers "Set-Cookie: $set_cookie";
-          '';
-          locations."= /auth" = {
-            proxyPass = "http://[::1]:8080/api/validate";
-            extraConfig = ''
-              internal;
-              proxy_pass_request_body off;
-              proxy_set_header Content-Length "";
-            '';
-          };
-          locations."@error401".return =
-            "307 $scheme://${cfg.nginx.virtualHost}/authenticate?redirect_url=https://$http_host";
-        })
-        // {
-          ${cfg.nginx.virtualHost} = {
-            inherit (cfg.nginx) enableACME useACMEHost;
-            forceSSL = true; # webauthn is only available over HTTPS
-            locations."/" = {
-              proxyPass = "http://[::1]:8080";
-              extraConfig = ''
-                proxy_set_header Host            $host;
-                proxy_set_header X-Forwarded-For $proxy_add_x_forwarded_for;
-              '';
-            };
-          };
-        };
+      virtualHosts = foldl' (hosts: nginx: hosts // virtualHostsOf nginx) (virtualHostsOf cfg.nginx) (
+        mapAttrsToList (_: rp: rp.nginx) cfg.relyingParties
+      );
+    };
+
+    systemd.sockets.webauthn-tiny = mkIf cfg.unixSocket {
+      description = "webauthn-tiny socket";
+      listenStreams = [ socketPath ];
+      socketConfig = {
+        SocketUser = config.services.nginx.user;
+        SocketMode = "0600";
+      };
+      wantedBy = [ "sockets.target" ];
     };
 
     systemd.services.webauthn-tiny = {
@@ -165,17 +407,43 @@ in
         LoadCredential = [
           "password-file:${passwordFile}"
           "session-secret-file:${sessionSecretFile}"
-        ];
+        ]
+        ++ (mapAttrsToList (
+          name: rp: "password-file-${name}:${rp.passwordFile}"
+        ) relyingPartiesWithPasswordFile)
+        ++ optional (cfg.storageKeyFile != null) "storage-key-file:${cfg.storageKeyFile}"
+        ++ (imap0 (i: file: "previous-storage-key-file-${toString i}:${file}") cfg.previousStorageKeyFiles);
         ExecStart = escapeShellArgs (
           [
             (lib.getExe pkgs.webauthn-tiny)
+            "--config=${configFile}"
             "--rp-id=${cfg.relyingParty.id}"
             "--rp-origin=${cfg.relyingParty.origin}"
             "--password-file=\${CREDENTIALS_DIRECTORY}/password-file"
             "--session-secret-file=\${CREDENTIALS_DIRECTORY}/session-secret-file"
+            "--counter-regression-action=${cfg.counterRegressionAction}"
           ]
           ++ (map (origin: "--extra-allowed-origin=${origin}") cfg.relyingParty.extraAllowedOrigins)
+          ++ (map (host: "--device-bound-host=${host}") cfg.relyingParty.deviceBoundHosts)
+          ++ optional (cfg.databaseUrl != null) "--database-url=${cfg.databaseUrl}"
+          ++ optional (
+            cfg.storageKeyFile != null
+          ) "--storage-key-file=\${CREDENTIALS_DIRECTORY}/storage-key-file"
+          ++ (imap0 (
+            i: _: "--previous-storage-key-file=\${CREDENTIALS_DIRECTORY}/previous-storage-key-file-${toString i}"
+          ) cfg.previousStorageKeyFiles)
+          ++ optional cfg.relyingParty.rejectBackupEligible "--reject-backup-eligible"
+          ++ optional cfg.purgeRemovedUsers "--purge-removed-users"
+          ++ optionals cfg.backups.enable [
+            "--backup-directory=\${STATE_DIRECTORY}/backups"
+            "--backup-interval=${toString cfg.backups.interval}"
+            "--backup-retention=${toString cfg.backups.retention}"
+          ]
         );
+        ExecReload = "${pkgs.coreutils}/bin/kill -HUP $MAINPID";
+        Type = "notify";
+        WatchdogSec = 30;
+        Restart = "on-failure";
         CapabilityBoundingSet = [ ];
         DeviceAllow = [ ];
         DynamicUser = true;
@@ -195,6 +463,7 @@ in
         RestrictAddressFamilies = [
           "AF_INET"
           "AF_INET6"
+          "AF_UNIX"
         ];
         RestrictNamespaces = true;
         RestrictRealtime = true;
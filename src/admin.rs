@@ -0,0 +1,219 @@
+//! Offline administration commands, operating directly on the database. Their actions are recorded
+//! in the audit log like those made through the web UI, without client information.
+
+use crate::{
+    app::{encode_cred_id, App, AppError},
+    audit::{AuditEvent, AuditEventKind, ClientInfo},
+    handlers::CredentialIDWithName,
+};
+use clap::ValueEnum;
+use serde::Serialize;
+use webauthn_rs::prelude::CredentialID;
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum OutputFormat {
+    Table,
+    Json,
+}
+
+/// Prints rows as columns aligned to their widest value.
+fn print_table<const N: usize>(header: [&str; N], rows: &[[String; N]]) {
+    let mut widths = header.map(str::len);
+    for row in rows {
+        for (width, value) in widths.iter_mut().zip(row) {
+            *width = (*width).max(value.chars().count());
+        }
+    }
+
+    let print_row = |row: &[&str]| {
+        let line = row
+            .iter()
+            .zip(widths)
+            .map(|(value, width)| format!("{value:width$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    };
+
+    print_row(&header);
+    for row in rows {
+        print_row(&row.each_ref().map(String::as_str));
+    }
+}
+
+fn print_json(value: &impl Serialize) -> anyhow::Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+
+    Ok(())
+}
+
+fn parse_cred_id(cred_id: &str) -> anyhow::Result<CredentialID> {
+    serde_json::from_value(serde_json::Value::String(cred_id.to_string()))
+        .map_err(|_| anyhow::anyhow!("{cred_id} is not a base64url credential ID"))
+}
+
+#[derive(Serialize)]
+struct UserOutput {
+    username: String,
+    display_name: Option<String>,
+    email: Option<String>,
+    groups: Vec<String>,
+    credentials: usize,
+}
+
+pub async fn list_users(app: &App, format: OutputFormat) -> anyhow::Result<()> {
+    let users = app
+        .list_users()
+        .await?
+        .into_iter()
+        .map(|user| UserOutput {
+            credentials: user.credentials.len(),
+            username: user.username,
+            display_name: user.profile.display_name,
+            email: user.profile.email,
+            groups: user.profile.groups,
+        })
+        .collect::<Vec<_>>();
+
+    match format {
+        OutputFormat::Json => print_json(&users),
+        OutputFormat::Table => {
+            print_table(
+                ["USERNAME", "DISPLAY NAME", "EMAIL", "GROUPS", "CREDENTIALS"],
+                &users
+                    .iter()
+                    .map(|user| {
+                        [
+                            user.username.clone(),
+                            user.display_name.clone().unwrap_or_default(),
+                            user.email.clone().unwrap_or_default(),
+                            user.groups.join(","),
+                            user.credentials.to_string(),
+                        ]
+                    })
+                    .collect::<Vec<_>>(),
+            );
+            Ok(())
+        }
+    }
+}
+
+pub async fn delete_user(app: &App, username: &str) -> anyhow::Result<()> {
+    let result = app.delete_user(username).await;
+    app.record(
+        AuditEvent::new(AuditEventKind::UserDeleted, &ClientInfo::default())
+            .outcome(&result)
+            .username(username.to_string()),
+    )
+    .await;
+    result?;
+
+    println!("deleted user {username} with their credentials and sessions");
+
+    Ok(())
+}
+
+pub async fn list_credentials(
+    app: &App,
+    username: &str,
+    format: OutputFormat,
+) -> anyhow::Result<()> {
+    let user = app
+        .find_user(username)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("user {username} not found"))?;
+
+    match format {
+        OutputFormat::Json => print_json(
+            &user
+                .credentials
+                .iter()
+                .map(CredentialIDWithName::from)
+                .collect::<Vec<_>>(),
+        ),
+        OutputFormat::Table => {
+            print_table(
+                ["ID", "NAME", "TYPE", "STATUS"],
+                &user
+                    .credentials
+                    .iter()
+                    .map(|credential| {
+                        [
+                            encode_cred_id(credential.credential.cred_id()),
+                            credential.name.clone(),
+                            if credential.backup_eligible() {
+                                "synced"
+                            } else {
+                                "device-bound"
+                            }
+                            .to_string(),
+                            if credential.disabled {
+                                "disabled"
+                            } else {
+                                "enabled"
+                            }
+                            .to_string(),
+                        ]
+                    })
+                    .collect::<Vec<_>>(),
+            );
+            Ok(())
+        }
+    }
+}
+
+pub async fn delete_credential(app: &App, cred_id: &str) -> anyhow::Result<()> {
+    let result = app.delete_credential(parse_cred_id(cred_id)?).await;
+    app.record(
+        AuditEvent::new(AuditEventKind::CredentialDeleted, &ClientInfo::default())
+            .outcome(&result)
+            .credential(cred_id.to_string()),
+    )
+    .await;
+    result?;
+
+    println!("deleted credential {cred_id}");
+
+    Ok(())
+}
+
+pub async fn rename_credential(app: &App, cred_id: &str, name: &str) -> anyhow::Result<()> {
+    let result = app.rename_credential(parse_cred_id(cred_id)?, name).await;
+    app.record(
+        AuditEvent::new(AuditEventKind::CredentialRenamed, &ClientInfo::default())
+            .outcome(&result)
+            .credential(cred_id.to_string()),
+    )
+    .await;
+    match result {
+        Err(AppError::BadInput) => anyhow::bail!(
+            "cannot rename credential {cred_id} to {name:?}, the name is empty or already taken"
+        ),
+        result => result?,
+    }
+
+    println!("renamed credential {cred_id} to {name}");
+
+    Ok(())
+}
+
+pub async fn revoke_sessions(app: &App, username: Option<&str>) -> anyhow::Result<()> {
+    let mut event = AuditEvent::new(AuditEventKind::SessionsRevoked, &ClientInfo::default());
+    let result = match username {
+        Some(username) => {
+            event = event.username(username.to_string());
+            app.revoke_user_sessions(username)
+                .await
+                .map(|n| format!("revoked {n} sessions of {username}"))
+        }
+        None => app
+            .revoke_sessions()
+            .await
+            .map(|_| "revoked all sessions".to_string()),
+    };
+    app.record(event.outcome(&result)).await;
+
+    println!("{}", result?);
+
+    Ok(())
+}
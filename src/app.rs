@@ -1,21 +1,22 @@
+use crate::{
+    audit::{self, AuditEvent, AuditSigningKey, ChainReport, ChainVerifier},
+    session::{SessionCache, StorageSessionStore, SESSIONKEY_USERNAME},
+    storage::{
+        AuditEventQuery, Storage, StorageError, StoredCounts, StoredCredential, StoredUser,
+        DEFAULT_RELYING_PARTY,
+    },
+};
 use axum::{
     http::StatusCode,
     response::{IntoResponse, Response},
     Json,
 };
-use libsqlite3_sys::ErrorCode::ConstraintViolation;
-use rusqlite::Error::{QueryReturnedNoRows, SqliteFailure};
+use base64::{engine::general_purpose, Engine as _};
 use serde::{Deserialize, Serialize};
 use std::{fmt::Display, sync::Arc};
-use tokio::sync::RwLock;
-use tokio_rusqlite::Connection;
-use webauthn_rs::prelude::{AuthenticationResult, CredentialID, Passkey, Uuid};
-
-#[derive(Deserialize, Serialize, Debug)]
-pub struct CredentialState {
-    pub id: Uuid,
-    pub credentials: Vec<Passkey>,
-}
+use tower_sessions::{cookie::time::OffsetDateTime, session::Record};
+use tracing::{error, info};
+use webauthn_rs::prelude::{AuthenticationResult, Credential, CredentialID, Passkey, Uuid};
 
 #[derive(Debug, Copy, Clone, Default)]
 pub enum AppError {
@@ -33,6 +34,13 @@ pub enum AppError {
     #[default]
     UnknownError,
     NoUserCredentials,
+    BackupEligibleCredential,
+    DeviceBoundCredentialRequired,
+    CounterRegression,
+    NoEnabledCredentials,
+    GroupNotAllowed,
+    NotAdmin,
+    AssertionExpired,
 }
 
 impl Display for AppError {
@@ -47,6 +55,13 @@ impl Display for AppError {
             AppError::CredentialNotFound => "credential not found",
             AppError::WebauthnFailed => "webauthn process failed",
             AppError::UserNotFound => "user not found",
+            AppError::BackupEligibleCredential => "synced credentials are not allowed",
+            AppError::DeviceBoundCredentialRequired => "device-bound credential required",
+            AppError::CounterRegression => "credential signature counter regressed",
+            AppError::NoEnabledCredentials => "all credentials are disabled",
+            AppError::GroupNotAllowed => "user is not in a group allowed on this host",
+            AppError::NotAdmin => "user is not an admin",
+            AppError::AssertionExpired => "passkey confirmation expired, try again",
             _ => "unknown error",
         };
         write!(f, "{msg}")
@@ -80,6 +95,13 @@ impl From<AppError> for StatusCode {
             AppError::UserNotFound => StatusCode::NOT_FOUND,
             AppError::CredentialNotFound => StatusCode::NOT_FOUND,
             AppError::NoUserCredentials => StatusCode::NO_CONTENT,
+            AppError::BackupEligibleCredential => StatusCode::FORBIDDEN,
+            AppError::DeviceBoundCredentialRequired => StatusCode::FORBIDDEN,
+            AppError::CounterRegression => StatusCode::FORBIDDEN,
+            AppError::NoEnabledCredentials => StatusCode::FORBIDDEN,
+            AppError::GroupNotAllowed => StatusCode::FORBIDDEN,
+            AppError::NotAdmin => StatusCode::FORBIDDEN,
+            AppError::AssertionExpired => StatusCode::FORBIDDEN,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
@@ -91,24 +113,15 @@ impl From<tower_sessions::session::Error> for AppError {
     }
 }
 
-impl From<rusqlite::Error> for AppError {
-    fn from(error: rusqlite::Error) -> Self {
+impl From<StorageError> for AppError {
+    fn from(error: StorageError) -> Self {
         match error {
-            SqliteFailure(err, _) => match err.code {
-                ConstraintViolation => AppError::BadInput,
-                _ => AppError::UnknownError,
-            },
-            QueryReturnedNoRows => AppError::EntityNotFound,
-            _ => AppError::UnknownError,
-        }
-    }
-}
-
-impl From<tokio_rusqlite::Error> for AppError {
-    fn from(error: tokio_rusqlite::Error) -> Self {
-        match error {
-            tokio_rusqlite::Error::Rusqlite(error) => error.into(),
-            _ => AppError::UnknownError,
+            StorageError::DuplicateCredential => AppError::DuplicateCredential,
+            StorageError::Constraint => AppError::BadInput,
+            StorageError::Backend(err) => {
+                error!("storage: {err}");
+                AppError::UnknownError
+            }
         }
     }
 }
@@ -125,139 +138,420 @@ impl From<uuid::Error> for AppError {
     }
 }
 
+/// The state of one relying party. Users and the audit log are shared by all relying parties,
+/// while each only sees the credentials registered with it and its own sessions.
 pub struct App {
-    db: Connection,
+    storage: Arc<dyn Storage>,
+    sessions: StorageSessionStore,
+    relying_party: String,
+}
+
+fn session_belongs_to(record: &Record, username: &str) -> bool {
+    record
+        .data
+        .get(SESSIONKEY_USERNAME)
+        .and_then(|value| value.as_str())
+        == Some(username)
+}
+
+/// Encodes a credential ID the same way webauthn-rs serializes it, as unpadded base64url. This is
+/// the format of the credential ID stored by the storage backends.
+pub fn encode_cred_id(cred_id: &CredentialID) -> String {
+    general_purpose::URL_SAFE_NO_PAD.encode(cred_id)
 }
 
-pub type SharedAppState = Arc<RwLock<App>>;
+pub type SharedAppState = Arc<App>;
 
 #[derive(Clone, Debug)]
 pub struct CredentialWithName {
     pub name: String,
     pub credential: Passkey,
+    pub disabled: bool,
+}
+
+impl CredentialWithName {
+    /// Whether the authenticator indicated that this credential may be synced or backed up
+    /// outside of a single hardware device.
+    pub fn backup_eligible(&self) -> bool {
+        Credential::from(self.credential.clone()).backup_eligible
+    }
+
+    /// Whether the credential is currently backed up or shared between multiple devices.
+    pub fn backup_state(&self) -> bool {
+        Credential::from(self.credential.clone()).backup_state
+    }
+}
+
+/// Admin-editable profile information that is passed on to authenticators, protected services and
+/// templates.
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserProfile {
+    pub display_name: Option<String>,
+    pub email: Option<String>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+impl UserProfile {
+    fn from_stored(user: &StoredUser) -> Self {
+        Self {
+            display_name: user.display_name.clone(),
+            email: user.email.clone(),
+            groups: user.groups.clone(),
+        }
+    }
+
+    /// Profile values end up in HTTP headers, where groups are joined with commas, so control
+    /// characters and commas in group names are rejected.
+    fn validate(&self) -> Result<(), AppError> {
+        let is_valid = |value: &str| !value.is_empty() && !value.chars().any(char::is_control);
+
+        if self.display_name.as_deref().is_some_and(|v| !is_valid(v))
+            || self
+                .email
+                .as_deref()
+                .is_some_and(|v| !is_valid(v) || !v.contains('@'))
+            || self
+                .groups
+                .iter()
+                .any(|g| !is_valid(g) || g.contains(',') || g.trim() != g)
+        {
+            return Err(AppError::BadInput);
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Default, Debug, Clone)]
 pub struct UserWithCredentials {
     pub id: Uuid,
     pub username: String,
+    pub profile: UserProfile,
     pub credentials: Vec<CredentialWithName>,
 }
 
 impl UserWithCredentials {
-    fn exists(&self) -> bool {
-        self.id != Uuid::default()
+    /// The display name, falling back to the username if none is set.
+    pub fn display_name(&self) -> &str {
+        self.profile
+            .display_name
+            .as_deref()
+            .unwrap_or(&self.username)
     }
 }
 
+/// The WebAuthn user handle has always been derived from the first 16 bytes of the stored user
+/// ID's text, so keep doing that to stay compatible with handles held by existing authenticators.
+fn user_handle(user_id: &str) -> Result<Uuid, AppError> {
+    Ok(Uuid::from_slice(
+        user_id.as_bytes().get(..16).ok_or(AppError::BadInput)?,
+    )?)
+}
+
 impl App {
-    pub fn new(db: Connection) -> Self {
-        Self { db }
-    }
-
-    pub async fn init(&self) -> Result<(), AppError> {
-        self.db
-            .call(|conn| {
-                conn.execute(
-                    r#"create table if not exists users (
-                         id uuid primary key not null,
-                         username text not null unique
-                       )"#,
-                    [],
-                )?;
-
-                conn.execute(
-                    r#"create table if not exists credentials (
-                         name text not null,
-                         user uuid not null,
-                         value json not null,
-                         foreign key(user) references users(id),
-                         unique(name, user)
-                       )"#,
-                    [],
-                )?;
-
-                Ok(())
-            })
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        Self {
+            sessions: StorageSessionStore::new(storage.clone()),
+            storage,
+            relying_party: DEFAULT_RELYING_PARTY.to_string(),
+        }
+    }
+
+    /// Returns the state of another relying party, sharing the storage and session cache. The
+    /// default relying party keeps its sessions outside of any namespace, as they were stored
+    /// before several relying parties were supported.
+    pub fn for_relying_party(&self, name: &str) -> Self {
+        Self {
+            storage: self.storage.clone(),
+            sessions: match name {
+                DEFAULT_RELYING_PARTY => self.sessions.clone(),
+                name => self.sessions.clone().with_namespace(name),
+            },
+            relying_party: name.to_string(),
+        }
+    }
+
+    pub fn with_session_cache(self, cache: SessionCache) -> Self {
+        Self {
+            sessions: self.sessions.with_cache(cache),
+            ..self
+        }
+    }
+
+    /// The session store, which has to be used for all session access so that the cache stays
+    /// consistent.
+    pub fn session_store(&self) -> StorageSessionStore {
+        self.sessions.clone()
+    }
+
+    /// Looks up a user and their credentials without creating the user if they do not exist.
+    pub async fn find_user(&self, username: &str) -> Result<Option<UserWithCredentials>, AppError> {
+        match self.storage.find_user(username).await? {
+            Some(user) => Ok(Some(self.with_credentials(user).await?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the user with the given name, creating them if they do not exist yet.
+    pub async fn create_user(&self, username: &str) -> Result<UserWithCredentials, AppError> {
+        if let Some(user) = self.find_user(username).await? {
+            return Ok(user);
+        }
+
+        let user = StoredUser {
+            id: Uuid::new_v4().to_string(),
+            username: username.to_string(),
+            ..Default::default()
+        };
+        self.storage.insert_user(&user).await?;
+
+        self.with_credentials(user).await
+    }
+
+    /// Creates a user for every name in `usernames` that does not have one yet. With `purge`,
+    /// users that are no longer listed are deleted together with their credentials.
+    pub async fn sync_users(&self, usernames: &[&str], purge: bool) -> Result<(), AppError> {
+        for username in usernames {
+            if self.storage.find_user(username).await?.is_none() {
+                info!("creating user {username}");
+                self.create_user(username).await?;
+            }
+        }
+
+        if purge {
+            for user in self.storage.list_users().await? {
+                if !usernames.contains(&user.username.as_str()) {
+                    info!("deleting user {} not found in password file", user.username);
+                    self.storage.delete_user(&user.id).await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the users of all relying parties.
+    pub async fn list_users(&self) -> Result<Vec<UserWithCredentials>, AppError> {
+        let mut users = Vec::new();
+        for user in self.storage.list_users().await? {
+            users.push(self.with_credentials(user).await?);
+        }
+
+        Ok(users)
+    }
+
+    /// Returns the users named in `usernames`, i.e. in the password file of the relying party,
+    /// since the users of all relying parties share the storage.
+    pub async fn list_users_in(
+        &self,
+        usernames: &[&str],
+    ) -> Result<Vec<UserWithCredentials>, AppError> {
+        let mut users = Vec::new();
+        for user in self.storage.list_users().await? {
+            if usernames.contains(&user.username.as_str()) {
+                users.push(self.with_credentials(user).await?);
+            }
+        }
+
+        Ok(users)
+    }
+
+    /// Deletes a user together with their credentials and sessions. A user that is still listed in
+    /// the password file is created again, without credentials, on the next start.
+    pub async fn delete_user(&self, username: &str) -> Result<(), AppError> {
+        let Some(user) = self.storage.find_user(username).await? else {
+            return Err(AppError::UserNotFound);
+        };
+
+        self.storage.delete_user(&user.id).await?;
+        // The user is deleted from all relying parties, so they are logged out of all of them.
+        self.sessions
+            .clone()
+            .across_namespaces()
+            .delete_matching(|record| session_belongs_to(record, username))
             .await?;
 
         Ok(())
     }
 
-    pub async fn get_user_with_credentials(
+    /// Looks up only the profile of a user, without loading their credentials.
+    pub async fn find_profile(&self, username: &str) -> Result<Option<UserProfile>, AppError> {
+        Ok(self
+            .storage
+            .find_user(username)
+            .await?
+            .as_ref()
+            .map(UserProfile::from_stored))
+    }
+
+    pub async fn list_profiles(&self) -> Result<Vec<(String, UserProfile)>, AppError> {
+        Ok(self
+            .storage
+            .list_users()
+            .await?
+            .iter()
+            .map(|user| (user.username.clone(), UserProfile::from_stored(user)))
+            .collect())
+    }
+
+    pub async fn update_profile(
         &self,
-        username: String,
-    ) -> Result<UserWithCredentials, AppError> {
-        let username_ = username.clone();
-
-        let users = self
-            .db
-            .call(move |conn| {
-                Ok(conn
-                    .prepare(
-                        r#"select u.id, u.username, c.name, c.value
-                           from users u
-                           left join credentials c on u.id = c.user
-                           where username = ?1"#,
-                    )?
-                    .query_map((&username_,), |row| {
-                        Ok((
-                            row.get::<_, String>(0)?,
-                            row.get::<_, String>(1)?,
-                            row.get::<_, Option<String>>(2)?,
-                            row.get::<_, Option<String>>(3)?,
-                        ))
-                    })?
-                    .filter_map(|v| if let Ok(v_ok) = v { Some(v_ok) } else { None })
-                    .fold(Vec::new(), |mut accumulator, current| {
-                        accumulator.push(current);
-                        accumulator
-                    }))
+        username: &str,
+        profile: UserProfile,
+    ) -> Result<(), AppError> {
+        profile.validate()?;
+
+        let Some(user) = self.storage.find_user(username).await? else {
+            return Err(AppError::UserNotFound);
+        };
+
+        self.storage
+            .update_user(&StoredUser {
+                display_name: profile.display_name,
+                email: profile.email,
+                groups: profile.groups,
+                ..user
             })
             .await?;
 
-        let user = users
+        Ok(())
+    }
+
+    /// Appends an event to the audit log. A failure to do so is logged but does not fail the
+    /// operation the event describes.
+    pub async fn record(&self, event: AuditEvent) {
+        if let Err(err) = self
+            .storage
+            .insert_audit_event(&event.to_stored(), audit::chain_hash)
+            .await
+        {
+            error!("failed to record {} audit event: {err}", event.kind);
+        }
+    }
+
+    pub async fn audit_events(&self, query: &AuditEventQuery) -> Result<Vec<AuditEvent>, AppError> {
+        self.storage
+            .list_audit_events(query)
+            .await?
             .into_iter()
-            .fold(UserWithCredentials::default(), |mut user, u| {
-                if let Ok(id) = Uuid::from_slice(&u.0.as_bytes()[..16]) {
-                    user.id = id;
-                }
+            .map(AuditEvent::from_stored)
+            .collect()
+    }
 
-                if u.2.is_some() && u.3.is_some() {
-                    if let Ok(passkey) =
-                        serde_json::from_str::<Passkey>(&u.3.expect("is_some guard"))
-                    {
-                        user.credentials.push(CredentialWithName {
-                            name: u.2.expect("is_some guard"),
-                            credential: passkey,
-                        });
-                    }
+    /// Signs a checkpoint for the latest event of the audit chain, unless it already has one.
+    /// Returns the ID of the checkpointed event.
+    pub async fn checkpoint_audit_log(&self, key: &AuditSigningKey) -> anyhow::Result<Option<i64>> {
+        let latest = self
+            .storage
+            .list_audit_events(&AuditEventQuery {
+                limit: 1,
+                ..Default::default()
+            })
+            .await?;
+        let Some((event_id, Some(hash))) = latest.into_iter().next().map(|e| (e.id, e.hash)) else {
+            return Ok(None);
+        };
+
+        if self
+            .storage
+            .list_audit_checkpoints()
+            .await?
+            .last()
+            .is_some_and(|checkpoint| checkpoint.event_id == event_id)
+        {
+            return Ok(None);
+        }
+
+        self.storage
+            .insert_audit_checkpoint(&key.sign(event_id, &hash)?)
+            .await?;
+
+        Ok(Some(event_id))
+    }
+
+    /// Walks the whole audit chain, checking every link and signed checkpoint.
+    pub async fn verify_audit_log(&self, key: &AuditSigningKey) -> anyhow::Result<ChainReport> {
+        const PAGE_SIZE: usize = 1000;
+
+        let mut verifier = ChainVerifier::new(key, &self.storage.list_audit_checkpoints().await?);
+        let mut after = 0;
+        loop {
+            let events = self
+                .storage
+                .list_audit_events_after(after, PAGE_SIZE)
+                .await?;
+            for event in &events {
+                if !verifier.push(event) {
+                    return Ok(verifier.finish());
                 }
-                user.username = u.1;
-                user
-            });
+            }
 
-        if user.exists() {
-            return Ok::<_, AppError>(user);
+            match events.last() {
+                Some(event) if events.len() == PAGE_SIZE => after = event.id,
+                _ => return Ok(verifier.finish()),
+            }
         }
+    }
 
-        let new_user = self
-            .db
-            .call(move |conn| {
-                Ok(conn.query_row(
-                    r#"insert into users (id, username)
-                       values (?1, ?2)
-                       returning id, username"#,
-                    (&Uuid::new_v4().to_string(), &username),
-                    |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
-                ))
+    /// Logs out every user of this relying party by deleting its sessions.
+    pub async fn revoke_sessions(&self) -> Result<(), AppError> {
+        Ok(self.sessions.clear().await?)
+    }
+
+    /// Logs a single user out by deleting their sessions. Returns the number of deleted sessions.
+    pub async fn revoke_user_sessions(&self, username: &str) -> Result<usize, AppError> {
+        Ok(self
+            .sessions
+            .delete_matching(|record| session_belongs_to(record, username))
+            .await?)
+    }
+
+    /// Returns the expiry dates of the user's sessions, soonest first.
+    pub async fn user_sessions(&self, username: &str) -> Result<Vec<OffsetDateTime>, AppError> {
+        let mut expiry_dates = self
+            .sessions
+            .find_matching(|record| session_belongs_to(record, username))
+            .await?
+            .into_iter()
+            .map(|record| record.expiry_date)
+            .collect::<Vec<_>>();
+        expiry_dates.sort();
+
+        Ok(expiry_dates)
+    }
+
+    /// Counts the stored sessions, users and credentials of all relying parties.
+    pub async fn stats(&self) -> Result<StoredCounts, AppError> {
+        Ok(self
+            .storage
+            .count(OffsetDateTime::now_utc().unix_timestamp())
+            .await?)
+    }
+
+    async fn with_credentials(&self, user: StoredUser) -> Result<UserWithCredentials, AppError> {
+        let credentials = self
+            .storage
+            .list_credentials(&user.id)
+            .await?
+            .into_iter()
+            .filter(|c| c.relying_party == self.relying_party)
+            .filter_map(|c| {
+                serde_json::from_str::<Passkey>(&c.value)
+                    .ok()
+                    .map(|passkey| CredentialWithName {
+                        name: c.name,
+                        credential: passkey,
+                        disabled: c.disabled,
+                    })
             })
-            .await??;
+            .collect();
 
-        Ok::<_, AppError>(UserWithCredentials {
-            id: Uuid::from_slice(&new_user.0.as_bytes()[..16])?,
-            username: new_user.1,
-            credentials: vec![],
+        Ok(UserWithCredentials {
+            id: user_handle(&user.id)?,
+            profile: UserProfile::from_stored(&user),
+            username: user.username,
+            credentials,
         })
     }
 
@@ -271,82 +565,130 @@ impl App {
             return Err(AppError::UnknownError);
         };
 
-        let n_added = self
-            .db
-            .call(|conn| {
-                Ok(conn.execute(
-                    r#"insert into credentials (name, user, value)
-                       values (?1, (select id from users where username = ?2), json(?3))"#,
-                    (credential_name, username, cred_val),
-                ))
+        let Some(user) = self.storage.find_user(&username).await? else {
+            return Err(AppError::UserNotFound);
+        };
+
+        self.storage
+            .insert_credential(&StoredCredential {
+                cred_id: encode_cred_id(credential.cred_id()),
+                user_id: user.id,
+                relying_party: self.relying_party.clone(),
+                name: credential_name,
+                value: cred_val,
+                disabled: false,
+                backup_eligible: Credential::from(credential.clone()).backup_eligible,
             })
-            .await??;
+            .await?;
 
-        if n_added != 1 {
-            Err(AppError::UserNotFound)
-        } else {
-            Ok::<_, AppError>(())
-        }
+        Ok(())
     }
 
     pub async fn update_credential(
         &self,
         auth_result: AuthenticationResult,
     ) -> Result<(), AppError> {
-        let cred_id = serde_json::to_string(auth_result.cred_id())?;
-
-        let cred_json = self
-            .db
-            .call(move |conn| {
-                Ok(conn.query_row(
-                    r#"select value from credentials
-                       where value->'$.cred.cred_id' = ?1"#,
-                    (cred_id,),
-                    |row| row.get::<_, String>(0),
-                ))
-            })
-            .await??;
+        let cred_id = encode_cred_id(auth_result.cred_id());
+
+        let Some(stored) = self
+            .storage
+            .get_credential(&cred_id)
+            .await?
+            .filter(|c| c.relying_party == self.relying_party)
+        else {
+            return Err(AppError::EntityNotFound);
+        };
 
-        let mut passkey = serde_json::from_str::<Passkey>(&cred_json)?;
+        let mut passkey = serde_json::from_str::<Passkey>(&stored.value)?;
         if passkey.update_credential(&auth_result).is_none() {
             return Err(AppError::MismatchingCredential);
         }
 
-        let cred_id = serde_json::to_string(passkey.cred_id())?;
-
         let cred_json = serde_json::to_string(&passkey)?;
 
         _ = self
-            .db
-            .call(move |conn| {
-                Ok(conn.execute(
-                    r#"update credentials set value = ?1
-                       where value->'$.cred.cred_id' = ?2"#,
-                    (cred_json, cred_id),
-                ))
-            })
-            .await??;
+            .storage
+            .update_credential_value(&cred_id, &cred_json)
+            .await?;
 
         Ok(())
     }
 
-    pub async fn delete_credential(&self, cred_id: CredentialID) -> Result<(), AppError> {
-        let cred_id = serde_json::to_string(&cred_id)?;
-
-        let n_deleted = self
-            .db
-            .call(move |conn| {
-                Ok(conn.execute(
-                    r#"delete from credentials where value->'$.cred.cred_id' = ?1"#,
-                    (&cred_id,),
-                ))
-            })
-            .await??;
+    /// Fails with `CredentialNotFound` if the credential was registered with another relying
+    /// party, whose credentials cannot be changed through this one.
+    async fn check_relying_party(&self, cred_id: &str) -> Result<(), AppError> {
+        match self.storage.get_credential(cred_id).await? {
+            Some(credential) if credential.relying_party == self.relying_party => Ok(()),
+            _ => Err(AppError::CredentialNotFound),
+        }
+    }
+
+    pub async fn set_credential_disabled(
+        &self,
+        cred_id: CredentialID,
+        disabled: bool,
+    ) -> Result<(), AppError> {
+        self.check_relying_party(&encode_cred_id(&cred_id)).await?;
+        if self
+            .storage
+            .set_credential_disabled(&encode_cred_id(&cred_id), disabled)
+            .await?
+        {
+            Ok(())
+        } else {
+            Err(AppError::CredentialNotFound)
+        }
+    }
+
+    pub async fn rename_credential(
+        &self,
+        cred_id: CredentialID,
+        name: &str,
+    ) -> Result<(), AppError> {
+        if name.is_empty() {
+            return Err(AppError::BadInput);
+        }
 
-        if n_deleted != 1 {
+        self.check_relying_party(&encode_cred_id(&cred_id)).await?;
+        if self
+            .storage
+            .rename_credential(&encode_cred_id(&cred_id), name)
+            .await?
+        {
+            Ok(())
+        } else {
             Err(AppError::CredentialNotFound)
+        }
+    }
+
+    /// Enables all of the user's disabled credentials, such as those disabled after a signature
+    /// counter regression. Returns the number of enabled credentials.
+    pub async fn unlock_user(&self, username: &str) -> Result<usize, AppError> {
+        let user = self
+            .find_user(username)
+            .await?
+            .ok_or(AppError::UserNotFound)?;
+
+        let mut n_enabled = 0;
+        for credential in user.credentials.iter().filter(|c| c.disabled) {
+            self.set_credential_disabled(credential.credential.cred_id().clone(), false)
+                .await?;
+            n_enabled += 1;
+        }
+
+        Ok(n_enabled)
+    }
+
+    pub async fn delete_credential(&self, cred_id: CredentialID) -> Result<(), AppError> {
+        self.check_relying_party(&encode_cred_id(&cred_id)).await?;
+        if self
+            .storage
+            .delete_credential(&encode_cred_id(&cred_id))
+            .await?
+        {
+            Ok(())
         } else {
-            Ok::<_, AppError>(())
+            Err(AppError::CredentialNotFound)
         }
     }
 }
@@ -356,58 +698,260 @@ mod tests {
     use std::time::Duration;
 
     use super::*;
-    use tokio_rusqlite::Connection;
+    use crate::storage::test_backends;
     use webauthn_authenticator_rs::{prelude::Url, softtoken::SoftToken, WebauthnAuthenticator};
     use webauthn_rs_core::WebauthnCore;
 
-    async fn get_app_with_db() -> App {
-        let db = Connection::open(":memory:").await.unwrap();
-        let app = App::new(db);
-        app.init().await.unwrap();
-        app
+    /// An app for each storage backend, so that the flows run against SQLite as well.
+    async fn get_apps_with_storage() -> Vec<App> {
+        test_backends().await.into_iter().map(App::new).collect()
     }
 
+    #[cfg(feature = "sqlite")]
     #[tokio::test]
     async fn test_init_is_idempotent() {
-        let app = get_app_with_db().await;
-        app.init().await.unwrap();
+        let storage = crate::storage::sqlite::SqliteStorage::open(":memory:")
+            .await
+            .unwrap();
+        storage.migrate().await.unwrap();
+        let app = App::new(Arc::new(storage.clone()));
+        app.create_user("foo_user").await.unwrap();
+
+        storage.migrate().await.unwrap();
+        assert!(app.find_user("foo_user").await.unwrap().is_some());
     }
 
     #[tokio::test]
-    async fn test_get_user_with_credentials() {
-        let app = get_app_with_db().await;
+    async fn test_find_and_create_user() {
+        for app in get_apps_with_storage().await {
+            assert!(app.find_user("foo_user").await.unwrap().is_none());
+
+            let user = app.create_user("foo_user").await.unwrap();
+
+            // the user handle is stable
+            assert_eq!(
+                app.find_user("foo_user").await.unwrap().unwrap().id,
+                user.id
+            );
+            assert_eq!(app.create_user("foo_user").await.unwrap().id, user.id);
+        }
+    }
 
-        assert_eq!(
-            app.db
-                .call(|conn| {
-                    let exists: usize = conn
-                        .query_row("select exists(select id from users)", [], |row| row.get(0))
-                        .unwrap();
+    #[tokio::test]
+    async fn test_update_profile() {
+        for app in get_apps_with_storage().await {
+            let user = app.create_user("foo_user").await.unwrap();
+            assert_eq!(user.display_name(), "foo_user");
+
+            let profile = UserProfile {
+                display_name: Some("Foo User".to_string()),
+                email: Some("foo@example.com".to_string()),
+                groups: vec!["admins".to_string()],
+            };
+            app.update_profile("foo_user", profile.clone())
+                .await
+                .unwrap();
+
+            let user = app.find_user("foo_user").await.unwrap().unwrap();
+            assert_eq!(user.profile, profile);
+            assert_eq!(user.display_name(), "Foo User");
+
+            for invalid in [
+                UserProfile {
+                    email: Some("foo".to_string()),
+                    ..Default::default()
+                },
+                UserProfile {
+                    display_name: Some("Foo\r\nX-Injected: 1".to_string()),
+                    ..Default::default()
+                },
+                UserProfile {
+                    groups: vec!["admins,users".to_string()],
+                    ..Default::default()
+                },
+            ] {
+                assert!(matches!(
+                    app.update_profile("foo_user", invalid).await,
+                    Err(AppError::BadInput)
+                ));
+            }
+
+            assert!(matches!(
+                app.update_profile("bar_user", profile).await,
+                Err(AppError::UserNotFound)
+            ));
+        }
+    }
 
-                    Ok(exists)
-                })
+    #[tokio::test]
+    async fn test_audit_log_checkpoints() {
+        for app in get_apps_with_storage().await {
+            let path = std::env::temp_dir().join(format!("webauthn-tiny-{}.pem", Uuid::new_v4()));
+            let key = AuditSigningKey::load_or_generate(&path).unwrap();
+            _ = std::fs::remove_file(&path);
+
+            assert_eq!(app.checkpoint_audit_log(&key).await.unwrap(), None);
+
+            for _ in 0..3 {
+                app.record(AuditEvent::new(
+                    crate::audit::AuditEventKind::PasswordCheck,
+                    &Default::default(),
+                ))
+                .await;
+            }
+
+            assert_eq!(app.checkpoint_audit_log(&key).await.unwrap(), Some(3));
+            assert_eq!(app.checkpoint_audit_log(&key).await.unwrap(), None);
+
+            let report = app.verify_audit_log(&key).await.unwrap();
+            assert_eq!(report.first_break, None);
+            assert_eq!(report.chained_events, 3);
+            assert_eq!(report.last_checkpointed_event, Some(3));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sync_users() {
+        for app in get_apps_with_storage().await {
+            app.create_user("removed_user").await.unwrap();
+
+            app.sync_users(&["foo_user", "bar_user"], false)
                 .await
-                .unwrap(),
-            0
-        );
+                .unwrap();
+            assert!(app.find_user("foo_user").await.unwrap().is_some());
+            assert!(app.find_user("bar_user").await.unwrap().is_some());
+            assert!(app.find_user("removed_user").await.unwrap().is_some());
+
+            app.sync_users(&["foo_user"], true).await.unwrap();
+            assert!(app.find_user("foo_user").await.unwrap().is_some());
+            assert!(app.find_user("bar_user").await.unwrap().is_none());
+            assert!(app.find_user("removed_user").await.unwrap().is_none());
+        }
+    }
 
-        app.get_user_with_credentials("foo_user".to_string())
-            .await
-            .unwrap(); // user is created if they do not exist
+    #[tokio::test]
+    async fn test_delete_user_and_revoke_sessions() {
+        use tower_sessions::{
+            cookie::time::OffsetDateTime,
+            session::{Id, Record},
+            SessionStore,
+        };
+
+        for app in get_apps_with_storage().await {
+            app.create_user("foo_user").await.unwrap();
+            app.create_user("bar_user").await.unwrap();
+
+            let store = app.session_store();
+            let mut sessions = Vec::new();
+            for username in ["foo_user", "foo_user", "bar_user"] {
+                let mut record = Record {
+                    id: Id::default(),
+                    data: [(SESSIONKEY_USERNAME.to_string(), username.into())].into(),
+                    expiry_date: OffsetDateTime::now_utc(),
+                };
+                store.create(&mut record).await.unwrap();
+                sessions.push(record.id);
+            }
+
+            assert_eq!(app.user_sessions("foo_user").await.unwrap().len(), 2);
+            assert_eq!(app.stats().await.unwrap().expired_sessions, 3);
+            assert_eq!(app.revoke_user_sessions("foo_user").await.unwrap(), 2);
+            assert!(app.user_sessions("foo_user").await.unwrap().is_empty());
+            assert!(store.load(&sessions[0]).await.unwrap().is_none());
+            assert!(store.load(&sessions[2]).await.unwrap().is_some());
+
+            app.delete_user("bar_user").await.unwrap();
+            assert!(store.load(&sessions[2]).await.unwrap().is_none());
+            assert!(matches!(
+                app.delete_user("bar_user").await,
+                Err(AppError::UserNotFound)
+            ));
+            assert_eq!(
+                app.list_users()
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|user| user.username)
+                    .collect::<Vec<_>>(),
+                vec!["foo_user"]
+            );
+
+            app.create_user("other_user").await.unwrap();
+            assert_eq!(
+                app.list_users_in(&["foo_user"])
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|user| user.username)
+                    .collect::<Vec<_>>(),
+                vec!["foo_user"]
+            );
+        }
+    }
 
-        assert_eq!(
-            app.db
-                .call(|conn| {
-                    let exists: usize = conn
-                        .query_row("select exists(select id from users)", [], |row| row.get(0))
-                        .unwrap();
+    #[tokio::test]
+    async fn test_sessions_per_relying_party() {
+        use tower_sessions::{
+            cookie::time::OffsetDateTime,
+            session::{Id, Record},
+            SessionStore,
+        };
 
-                    Ok(exists)
+        for app in get_apps_with_storage().await {
+            let other_app = app.for_relying_party("other");
+            app.create_user("foo_user").await.unwrap();
+
+            let mut sessions = Vec::new();
+            for store in [app.session_store(), other_app.session_store()] {
+                let mut record = Record {
+                    id: Id::default(),
+                    data: [(SESSIONKEY_USERNAME.to_string(), "foo_user".into())].into(),
+                    expiry_date: OffsetDateTime::now_utc(),
+                };
+                store.create(&mut record).await.unwrap();
+                sessions.push(record.id);
+            }
+
+            assert_eq!(app.user_sessions("foo_user").await.unwrap().len(), 1);
+            assert_eq!(other_app.user_sessions("foo_user").await.unwrap().len(), 1);
+            assert_eq!(app.revoke_user_sessions("foo_user").await.unwrap(), 1);
+            assert_eq!(other_app.user_sessions("foo_user").await.unwrap().len(), 1);
+
+            other_app.revoke_sessions().await.unwrap();
+            assert!(other_app
+                .user_sessions("foo_user")
+                .await
+                .unwrap()
+                .is_empty());
+            app.session_store()
+                .create(&mut Record {
+                    id: sessions[0],
+                    data: [(SESSIONKEY_USERNAME.to_string(), "foo_user".into())].into(),
+                    expiry_date: OffsetDateTime::now_utc(),
                 })
                 .await
-                .unwrap(),
-            1
-        );
+                .unwrap();
+            other_app.revoke_sessions().await.unwrap();
+            assert_eq!(app.user_sessions("foo_user").await.unwrap().len(), 1);
+
+            // Deleting the user logs them out of every relying party.
+            other_app
+                .session_store()
+                .create(&mut Record {
+                    id: sessions[1],
+                    data: [(SESSIONKEY_USERNAME.to_string(), "foo_user".into())].into(),
+                    expiry_date: OffsetDateTime::now_utc(),
+                })
+                .await
+                .unwrap();
+            other_app.delete_user("foo_user").await.unwrap();
+            assert!(app.user_sessions("foo_user").await.unwrap().is_empty());
+            assert!(other_app
+                .user_sessions("foo_user")
+                .await
+                .unwrap()
+                .is_empty());
+        }
     }
 
     #[tokio::test]
@@ -424,54 +968,115 @@ mod tests {
         );
         let mut wa = WebauthnAuthenticator::new(soft_token);
 
-        let app = get_app_with_db().await;
-        let user = app
-            .get_user_with_credentials("bar_user".to_string())
-            .await
-            .unwrap();
+        for app in get_apps_with_storage().await {
+            let user = app.create_user("bar_user").await.unwrap();
 
-        assert!(user.credentials.is_empty());
+            assert!(user.credentials.is_empty());
 
-        let (chal, reg_state) = wan
-            .generate_challenge_register(
-                wan.new_challenge_register_builder(
-                    &user.id.into_bytes(),
-                    &user.username,
-                    &user.username,
+            let (chal, reg_state) = wan
+                .generate_challenge_register(
+                    wan.new_challenge_register_builder(
+                        &user.id.into_bytes(),
+                        &user.username,
+                        &user.username,
+                    )
+                    .unwrap(),
                 )
-                .unwrap(),
-            )
-            .unwrap();
-
-        let r = wa
-            .do_registration(Url::parse("https://localhost:8080").unwrap(), chal)
-            .unwrap();
+                .unwrap();
 
-        let cred = wan.register_credential(&r, &reg_state, None).unwrap();
+            let r = wa
+                .do_registration(Url::parse("https://localhost:8080").unwrap(), chal)
+                .unwrap();
 
-        app.add_credential(
-            user.username,
-            "bar_credential".to_string(),
-            &Passkey::from(cred.clone()),
-        )
-        .await
-        .unwrap();
+            let cred = wan.register_credential(&r, &reg_state, None).unwrap();
 
-        let user = app
-            .get_user_with_credentials("bar_user".to_string())
+            app.add_credential(
+                user.username,
+                "bar_credential".to_string(),
+                &Passkey::from(cred.clone()),
+            )
             .await
             .unwrap();
-        assert!(user.credentials.len() == 1);
 
-        // TODO(jared): test this
-        // app.update_credential();
+            let user = app.find_user("bar_user").await.unwrap().unwrap();
+            assert!(user.credentials.len() == 1);
+            assert!(!user.credentials[0].backup_eligible()); // soft tokens are device-bound
+
+            // the stored credential ID must match the one serialized by webauthn-rs
+            assert_eq!(
+                serde_json::to_value(&cred.cred_id).unwrap(),
+                encode_cred_id(&cred.cred_id)
+            );
+
+            // credential IDs are unique across users
+            app.create_user("baz_user").await.unwrap();
+            assert_eq!(
+                app.stats().await.unwrap(),
+                StoredCounts {
+                    users: 2,
+                    users_without_credentials: 1,
+                    credentials: [((DEFAULT_RELYING_PARTY.to_string(), false), 1)].into(),
+                    ..Default::default()
+                }
+            );
+            assert!(matches!(
+                app.add_credential(
+                    "baz_user".to_string(),
+                    "baz_credential".to_string(),
+                    &Passkey::from(cred.clone()),
+                )
+                .await,
+                Err(AppError::DuplicateCredential)
+            ));
+            assert!(!user.credentials[0].disabled);
 
-        app.delete_credential(cred.cred_id).await.unwrap();
+            app.set_credential_disabled(cred.cred_id.clone(), true)
+                .await
+                .unwrap();
 
-        let user = app
-            .get_user_with_credentials("bar_user".to_string())
-            .await
-            .unwrap();
-        assert!(user.credentials.is_empty());
+            let user = app.find_user("bar_user").await.unwrap().unwrap();
+            assert!(user.credentials[0].disabled);
+
+            assert_eq!(app.unlock_user("bar_user").await.unwrap(), 1);
+            assert_eq!(app.unlock_user("bar_user").await.unwrap(), 0);
+            let user = app.find_user("bar_user").await.unwrap().unwrap();
+            assert!(!user.credentials[0].disabled);
+            assert!(matches!(
+                app.unlock_user("qux_user").await,
+                Err(AppError::UserNotFound)
+            ));
+
+            app.rename_credential(cred.cred_id.clone(), "renamed_credential")
+                .await
+                .unwrap();
+            assert!(matches!(
+                app.rename_credential(cred.cred_id.clone(), "").await,
+                Err(AppError::BadInput)
+            ));
+            let user = app.find_user("bar_user").await.unwrap().unwrap();
+            assert_eq!(user.credentials[0].name, "renamed_credential");
+
+            // TODO(jared): test this
+            // app.update_credential();
+
+            // credentials are only visible to the relying party they were registered with
+            let other_app = app.for_relying_party("other");
+            assert!(other_app
+                .find_user("bar_user")
+                .await
+                .unwrap()
+                .unwrap()
+                .credentials
+                .is_empty());
+            assert!(matches!(
+                other_app.delete_credential(cred.cred_id.clone()).await,
+                Err(AppError::CredentialNotFound)
+            ));
+
+            app.delete_credential(cred.cred_id).await.unwrap();
+
+            let user = app.find_user("bar_user").await.unwrap().unwrap();
+            assert!(user.credentials.is_empty());
+        }
     }
 }
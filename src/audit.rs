@@ -0,0 +1,521 @@
+use crate::{
+    app::AppError,
+    storage::{AuditEventQuery, StoredAuditCheckpoint, StoredAuditEvent},
+};
+use base64::{engine::general_purpose, Engine as _};
+use openssl::{
+    pkey::{PKey, Private},
+    sign::{Signer, Verifier},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap, fmt::Display, io::Write, net::IpAddr, os::unix::fs::OpenOptionsExt,
+    path::Path, str::FromStr,
+};
+use time::OffsetDateTime;
+
+/// Maximum number of audit events returned by a single query.
+pub const MAX_PAGE_SIZE: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEventKind {
+    PasswordCheck,
+    Registration,
+    Authentication,
+    CredentialEnabled,
+    CredentialDisabled,
+    CredentialDeleted,
+    CredentialRenamed,
+    UserUpdated,
+    UserDeleted,
+    SessionsRevoked,
+    UserUnlocked,
+    AdminAssertion,
+}
+
+impl AuditEventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditEventKind::PasswordCheck => "password_check",
+            AuditEventKind::Registration => "registration",
+            AuditEventKind::Authentication => "authentication",
+            AuditEventKind::CredentialEnabled => "credential_enabled",
+            AuditEventKind::CredentialDisabled => "credential_disabled",
+            AuditEventKind::CredentialDeleted => "credential_deleted",
+            AuditEventKind::CredentialRenamed => "credential_renamed",
+            AuditEventKind::UserUpdated => "user_updated",
+            AuditEventKind::UserDeleted => "user_deleted",
+            AuditEventKind::SessionsRevoked => "sessions_revoked",
+            AuditEventKind::UserUnlocked => "user_unlocked",
+            AuditEventKind::AdminAssertion => "admin_assertion",
+        }
+    }
+}
+
+impl Display for AuditEventKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for AuditEventKind {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(serde_json::Value::String(s.to_string()))
+            .map_err(|_| AppError::BadInput)
+    }
+}
+
+/// The client a request came from, as far as it can be told.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+}
+
+/// A single entry of the audit log. The reason of a failed event is the name of the `AppError`
+/// that caused it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEvent {
+    pub id: i64,
+    #[serde(with = "time::serde::rfc3339")]
+    pub timestamp: OffsetDateTime,
+    pub kind: AuditEventKind,
+    pub success: bool,
+    pub reason: Option<String>,
+    pub username: Option<String>,
+    pub credential: Option<String>,
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+    /// The event's link in the hash chain, if it was recorded after the log was chained.
+    pub hash: Option<String>,
+}
+
+impl AuditEvent {
+    /// A new event for the current time. The ID is assigned by the storage backend.
+    pub fn new(kind: AuditEventKind, client: &ClientInfo) -> Self {
+        Self {
+            id: 0,
+            timestamp: OffsetDateTime::now_utc(),
+            kind,
+            success: true,
+            reason: None,
+            username: None,
+            credential: None,
+            ip: client.ip,
+            user_agent: client.user_agent.clone(),
+            hash: None,
+        }
+    }
+
+    pub fn outcome<T>(mut self, result: &Result<T, AppError>) -> Self {
+        if let Err(err) = result {
+            self.success = false;
+            self.reason = Some(format!("{err:?}"));
+        }
+        self
+    }
+
+    pub fn failed(mut self) -> Self {
+        self.success = false;
+        self
+    }
+
+    pub fn reason(mut self, reason: AppError) -> Self {
+        self.reason = Some(format!("{reason:?}"));
+        self
+    }
+
+    pub fn username(mut self, username: impl Into<Option<String>>) -> Self {
+        self.username = username.into();
+        self
+    }
+
+    pub fn credential(mut self, credential: impl Into<Option<String>>) -> Self {
+        self.credential = credential.into();
+        self
+    }
+
+    pub fn to_stored(&self) -> StoredAuditEvent {
+        StoredAuditEvent {
+            id: self.id,
+            timestamp: (self.timestamp.unix_timestamp_nanos() / 1_000_000) as i64,
+            kind: self.kind.to_string(),
+            success: self.success,
+            reason: self.reason.clone(),
+            username: self.username.clone(),
+            credential: self.credential.clone(),
+            ip: self.ip.map(|ip| ip.to_string()),
+            user_agent: self.user_agent.clone(),
+            prev_hash: None,
+            hash: self.hash.clone(),
+        }
+    }
+
+    pub fn from_stored(event: StoredAuditEvent) -> Result<Self, AppError> {
+        Ok(Self {
+            id: event.id,
+            timestamp: OffsetDateTime::from_unix_timestamp_nanos(
+                i128::from(event.timestamp) * 1_000_000,
+            )
+            .map_err(|_| AppError::BadInput)?,
+            kind: event.kind.parse()?,
+            success: event.success,
+            reason: event.reason,
+            username: event.username,
+            credential: event.credential,
+            ip: event.ip.and_then(|ip| ip.parse().ok()),
+            user_agent: event.user_agent,
+            hash: event.hash,
+        })
+    }
+}
+
+/// The SHA-256 over an event's contents and the hash of the event before it, hex encoded. The
+/// fields are hashed as a JSON array so that their boundaries are unambiguous.
+pub fn chain_hash(event: &StoredAuditEvent) -> String {
+    let contents = serde_json::json!([
+        event.prev_hash,
+        event.timestamp,
+        event.kind,
+        event.success,
+        event.reason,
+        event.username,
+        event.credential,
+        event.ip,
+        event.user_agent,
+    ]);
+
+    hex::encode(openssl::sha::sha256(contents.to_string().as_bytes()))
+}
+
+/// The Ed25519 key that signs audit log checkpoints. It is kept in the state directory; instances
+/// sharing a database must share the key for their checkpoints to verify.
+pub struct AuditSigningKey {
+    key: PKey<Private>,
+}
+
+impl AuditSigningKey {
+    pub const FILE_NAME: &str = "audit-signing-key.pem";
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let pem = std::fs::read(path)
+            .map_err(|err| anyhow::anyhow!("reading {}: {err}", path.display()))?;
+
+        Ok(Self {
+            key: PKey::private_key_from_pem(&pem)?,
+        })
+    }
+
+    /// Loads the key, generating it first if it does not exist yet.
+    pub fn load_or_generate(path: &Path) -> anyhow::Result<Self> {
+        if path.exists() {
+            return Self::load(path);
+        }
+
+        let key = PKey::generate_ed25519()?;
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)?
+            .write_all(&key.private_key_to_pem_pkcs8()?)?;
+
+        Ok(Self { key })
+    }
+
+    fn message(event_id: i64, hash: &str, timestamp: i64) -> String {
+        format!("webauthn-tiny audit checkpoint\n{event_id}\n{hash}\n{timestamp}")
+    }
+
+    pub fn sign(&self, event_id: i64, hash: &str) -> anyhow::Result<StoredAuditCheckpoint> {
+        let timestamp = (OffsetDateTime::now_utc().unix_timestamp_nanos() / 1_000_000) as i64;
+        let signature = Signer::new_without_digest(&self.key)?
+            .sign_oneshot_to_vec(Self::message(event_id, hash, timestamp).as_bytes())?;
+
+        Ok(StoredAuditCheckpoint {
+            event_id,
+            hash: hash.to_string(),
+            timestamp,
+            signature: general_purpose::STANDARD.encode(signature),
+        })
+    }
+
+    pub fn verify(&self, checkpoint: &StoredAuditCheckpoint) -> bool {
+        let Ok(signature) = general_purpose::STANDARD.decode(&checkpoint.signature) else {
+            return false;
+        };
+
+        Verifier::new_without_digest(&self.key)
+            .and_then(|mut verifier| {
+                verifier.verify_oneshot(
+                    &signature,
+                    Self::message(checkpoint.event_id, &checkpoint.hash, checkpoint.timestamp)
+                        .as_bytes(),
+                )
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// The first problem found while walking the audit chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChainBreak {
+    HashMismatch(i64),
+    BrokenLink(i64),
+    MissingHash(i64),
+    InvalidCheckpointSignature(i64),
+    CheckpointMismatch(i64),
+}
+
+impl Display for ChainBreak {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChainBreak::HashMismatch(id) => {
+                write!(f, "event {id} does not match its hash, it was modified")
+            }
+            ChainBreak::BrokenLink(id) => write!(
+                f,
+                "event {id} does not link to the event before it, events were removed or reordered"
+            ),
+            ChainBreak::MissingHash(id) => write!(f, "event {id} is missing from the chain"),
+            ChainBreak::InvalidCheckpointSignature(id) => {
+                write!(f, "checkpoint at event {id} has an invalid signature")
+            }
+            ChainBreak::CheckpointMismatch(id) => write!(
+                f,
+                "event {id} no longer matches its signed checkpoint, the log was rewritten or truncated"
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ChainReport {
+    /// Events recorded before the audit log was chained.
+    pub unchained_events: usize,
+    pub chained_events: usize,
+    pub checkpoints: usize,
+    pub last_event: Option<i64>,
+    pub last_checkpointed_event: Option<i64>,
+    pub first_break: Option<ChainBreak>,
+}
+
+/// Walks the audit chain event by event, oldest first, and stops at the first broken link.
+pub struct ChainVerifier {
+    /// The hash of each checkpointed event and whether the checkpoint's signature is valid.
+    checkpoints: BTreeMap<i64, (String, bool)>,
+    prev_hash: Option<String>,
+    report: ChainReport,
+}
+
+impl ChainVerifier {
+    pub fn new(key: &AuditSigningKey, checkpoints: &[StoredAuditCheckpoint]) -> Self {
+        Self {
+            checkpoints: checkpoints
+                .iter()
+                .map(|c| (c.event_id, (c.hash.clone(), key.verify(c))))
+                .collect(),
+            prev_hash: None,
+            report: ChainReport {
+                checkpoints: checkpoints.len(),
+                last_checkpointed_event: checkpoints.iter().map(|c| c.event_id).max(),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Checks the next event. Returns false once the chain is broken.
+    pub fn push(&mut self, event: &StoredAuditEvent) -> bool {
+        self.report.last_event = Some(event.id);
+        self.report.first_break = self.check(event);
+        self.report.first_break.is_none()
+    }
+
+    fn check(&mut self, event: &StoredAuditEvent) -> Option<ChainBreak> {
+        let Some(hash) = &event.hash else {
+            if self.report.chained_events > 0 {
+                return Some(ChainBreak::MissingHash(event.id));
+            }
+            self.report.unchained_events += 1;
+            return None;
+        };
+
+        if event.prev_hash != self.prev_hash {
+            return Some(ChainBreak::BrokenLink(event.id));
+        }
+        if chain_hash(event) != *hash {
+            return Some(ChainBreak::HashMismatch(event.id));
+        }
+
+        if let Some((checkpoint_hash, valid)) = self.checkpoints.remove(&event.id) {
+            if !valid {
+                return Some(ChainBreak::InvalidCheckpointSignature(event.id));
+            }
+            if checkpoint_hash != *hash {
+                return Some(ChainBreak::CheckpointMismatch(event.id));
+            }
+        }
+
+        self.report.chained_events += 1;
+        self.prev_hash = Some(hash.clone());
+        None
+    }
+
+    /// Checkpoints for events that were never seen mean those events were removed.
+    pub fn finish(mut self) -> ChainReport {
+        if self.report.first_break.is_none() {
+            self.report.first_break = self
+                .checkpoints
+                .into_keys()
+                .next()
+                .map(ChainBreak::CheckpointMismatch);
+        }
+
+        self.report
+    }
+}
+
+/// Query parameters of the audit log API. Events are returned newest first; the next page starts
+/// before the ID of the last event of the previous page.
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditLogParams {
+    pub before: Option<i64>,
+    pub limit: Option<usize>,
+    pub username: Option<String>,
+}
+
+impl From<AuditLogParams> for AuditEventQuery {
+    fn from(params: AuditLogParams) -> Self {
+        Self {
+            before: params.before,
+            limit: params.limit.unwrap_or(50).clamp(1, MAX_PAGE_SIZE),
+            username: params.username,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chain(n: usize) -> Vec<StoredAuditEvent> {
+        let mut events: Vec<StoredAuditEvent> = Vec::new();
+        for id in 1..=n as i64 {
+            let mut event = AuditEvent::new(AuditEventKind::PasswordCheck, &ClientInfo::default())
+                .username(format!("user{id}"))
+                .to_stored();
+            event.id = id;
+            event.prev_hash = events.last().and_then(|e| e.hash.clone());
+            event.hash = Some(chain_hash(&event));
+            events.push(event);
+        }
+        events
+    }
+
+    fn verify(
+        key: &AuditSigningKey,
+        events: &[StoredAuditEvent],
+        checkpoints: &[StoredAuditCheckpoint],
+    ) -> ChainReport {
+        let mut verifier = ChainVerifier::new(key, checkpoints);
+        for event in events {
+            if !verifier.push(event) {
+                break;
+            }
+        }
+        verifier.finish()
+    }
+
+    #[test]
+    fn test_chain_verification() {
+        let path = std::env::temp_dir().join(format!("webauthn-tiny-{}.pem", uuid::Uuid::new_v4()));
+        let key = AuditSigningKey::load_or_generate(&path).unwrap();
+        assert!(AuditSigningKey::load_or_generate(&path)
+            .unwrap()
+            .verify(&key.sign(1, "hash").unwrap()));
+        _ = std::fs::remove_file(&path);
+
+        let events = chain(5);
+        let checkpoint = key.sign(4, events[3].hash.as_ref().unwrap()).unwrap();
+
+        let report = verify(&key, &events, std::slice::from_ref(&checkpoint));
+        assert_eq!(report.first_break, None);
+        assert_eq!(report.chained_events, 5);
+        assert_eq!(report.last_checkpointed_event, Some(4));
+
+        let mut modified = events.clone();
+        modified[1].username = Some("mallory".to_string());
+        assert_eq!(
+            verify(&key, &modified, &[]).first_break,
+            Some(ChainBreak::HashMismatch(2))
+        );
+
+        let mut removed = events.clone();
+        removed.remove(2);
+        assert_eq!(
+            verify(&key, &removed, &[]).first_break,
+            Some(ChainBreak::BrokenLink(4))
+        );
+
+        // rewriting the chain after a modification is caught by the checkpoint
+        let mut rewritten = modified.clone();
+        for i in 1..rewritten.len() {
+            rewritten[i].prev_hash = rewritten[i - 1].hash.clone();
+            rewritten[i].hash = Some(chain_hash(&rewritten[i]));
+        }
+        assert_eq!(
+            verify(&key, &rewritten, std::slice::from_ref(&checkpoint)).first_break,
+            Some(ChainBreak::CheckpointMismatch(4))
+        );
+
+        assert_eq!(
+            verify(&key, &events[..3], std::slice::from_ref(&checkpoint)).first_break,
+            Some(ChainBreak::CheckpointMismatch(4))
+        );
+
+        let forged = StoredAuditCheckpoint {
+            timestamp: checkpoint.timestamp + 1,
+            ..checkpoint
+        };
+        assert_eq!(
+            verify(&key, &events, &[forged]).first_break,
+            Some(ChainBreak::InvalidCheckpointSignature(4))
+        );
+    }
+
+    #[test]
+    fn test_stored_roundtrip() {
+        let event = AuditEvent::new(
+            AuditEventKind::Authentication,
+            &ClientInfo {
+                ip: Some("::1".parse().unwrap()),
+                user_agent: Some("curl/8.0".to_string()),
+            },
+        )
+        .outcome::<()>(&Err(AppError::CounterRegression))
+        .username("foo_user".to_string())
+        .credential("Zm9v".to_string());
+
+        assert_eq!(event.reason.as_deref(), Some("CounterRegression"));
+        assert!(!event.success);
+
+        let stored = event.to_stored();
+        assert_eq!(stored.kind, "authentication");
+
+        let roundtripped = AuditEvent::from_stored(stored).unwrap();
+        assert_eq!(
+            roundtripped.timestamp.unix_timestamp_nanos() / 1_000_000,
+            event.timestamp.unix_timestamp_nanos() / 1_000_000
+        );
+        assert_eq!(
+            roundtripped,
+            AuditEvent {
+                timestamp: roundtripped.timestamp,
+                ..event
+            }
+        );
+    }
+}
@@ -0,0 +1,649 @@
+//! The `--config` file, and validation of the options for serving, run by the `check-config`
+//! command and on startup so that every problem is reported at once instead of failing on the
+//! first.
+
+use crate::{
+    listen, password,
+    policy::{CounterRegressionAction, HostPolicy},
+    relying_party::{self, RelyingPartyConfig},
+    storage::{encrypted::StorageKey, DEFAULT_RELYING_PARTY},
+    tls, ServeArgs, StorageArgs,
+};
+use clap::{parser::ValueSource, ArgMatches};
+use serde::{Deserialize, Deserializer};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::OpenOptions,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+use tower_sessions::cookie::Key;
+use webauthn_rs::prelude::Url;
+
+/// The TOML file passed with `--config`. Keys are named like the flags, with repeatable flags
+/// taking an array, and per-host policies and additional relying parties are only available here.
+/// Values given as flags or environment variables take precedence over the file, which takes
+/// precedence over the defaults.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ConfigFile {
+    address: Option<SocketAddr>,
+    unix_socket: Option<PathBuf>,
+    #[serde(default, deserialize_with = "deserialize_mode")]
+    unix_socket_mode: Option<u32>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    tls_redirect_address: Option<SocketAddr>,
+    rp_id: Option<String>,
+    rp_origin: Option<String>,
+    extra_allowed_origin: Option<Vec<String>>,
+    cookie_domain: Option<String>,
+    session_secret_file: Option<PathBuf>,
+    password_file: Option<PathBuf>,
+    state_directory: Option<PathBuf>,
+    database_url: Option<String>,
+    storage_key_file: Option<PathBuf>,
+    previous_storage_key_file: Option<Vec<PathBuf>>,
+    reject_backup_eligible: Option<bool>,
+    device_bound_host: Option<Vec<String>>,
+    counter_regression_action: Option<CounterRegressionAction>,
+    admin_user: Option<Vec<String>>,
+    admin_group: Option<Vec<String>>,
+    purge_removed_users: Option<bool>,
+    audit_checkpoint_interval: Option<u64>,
+    metrics_refresh_interval: Option<u64>,
+    session_cache_ttl: Option<u64>,
+    session_cache_capacity: Option<usize>,
+    shutdown_timeout: Option<u64>,
+    #[cfg(feature = "sqlite")]
+    backup_directory: Option<PathBuf>,
+    #[cfg(feature = "sqlite")]
+    backup_interval: Option<u64>,
+    #[cfg(feature = "sqlite")]
+    backup_retention: Option<usize>,
+    #[serde(default)]
+    hosts: HashMap<String, HostPolicy>,
+    #[serde(default)]
+    relying_parties: BTreeMap<String, RelyingPartyConfig>,
+}
+
+/// Parses a file mode given in octal as a string, like the flag.
+fn deserialize_mode<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
+    listen::parse_mode(&String::deserialize(deserializer)?)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+/// Whether the option was neither passed as a flag nor as an environment variable, so that a
+/// value from the config file applies.
+fn unset(matches: &ArgMatches, id: &str) -> bool {
+    !matches!(
+        matches.value_source(id),
+        Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+    )
+}
+
+/// Overrides each field of `$args` with the file's value, unless it was set otherwise.
+macro_rules! apply {
+    ($file:expr, $args:expr, $matches:expr, $($field:ident),+ $(,)?) => {
+        $(
+            if let Some(value) = &$file.$field {
+                if unset($matches, stringify!($field)) {
+                    $args.$field = value.clone().into();
+                }
+            }
+        )+
+    };
+}
+
+impl ConfigFile {
+    /// Reads the file if one is given, otherwise returns an empty configuration.
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| anyhow::anyhow!("reading {}: {err}", path.display()))?;
+        toml::from_str(&contents)
+            .map_err(|err| anyhow::anyhow!("parsing {}: {err}", path.display()))
+    }
+
+    pub fn apply_storage(&self, args: &mut StorageArgs, matches: &ArgMatches) {
+        apply!(
+            self,
+            args,
+            matches,
+            state_directory,
+            database_url,
+            storage_key_file,
+            previous_storage_key_file,
+        );
+    }
+
+    pub fn apply_serve(&self, args: &mut ServeArgs, matches: &ArgMatches) {
+        apply!(
+            self,
+            args,
+            matches,
+            address,
+            unix_socket,
+            unix_socket_mode,
+            tls_cert,
+            tls_key,
+            tls_redirect_address,
+            rp_id,
+            rp_origin,
+            extra_allowed_origin,
+            cookie_domain,
+            session_secret_file,
+            password_file,
+            reject_backup_eligible,
+            device_bound_host,
+            counter_regression_action,
+            admin_user,
+            admin_group,
+            purge_removed_users,
+            audit_checkpoint_interval,
+            metrics_refresh_interval,
+            session_cache_ttl,
+            session_cache_capacity,
+            shutdown_timeout,
+        );
+        #[cfg(feature = "sqlite")]
+        apply!(
+            self,
+            args,
+            matches,
+            backup_directory,
+            backup_interval,
+            backup_retention,
+        );
+        args.hosts = self.hosts.clone();
+        args.relying_parties = self.relying_parties.clone();
+        self.apply_storage(&mut args.storage, matches);
+    }
+}
+
+/// A shared value that is replaced when the configuration is reloaded.
+pub struct Reloadable<T>(Arc<RwLock<Arc<T>>>);
+
+impl<T> Clone for Reloadable<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T> Reloadable<T> {
+    pub fn new(value: T) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(value))))
+    }
+
+    pub fn get(&self) -> Arc<T> {
+        self.0.read().expect("lock poisoned").clone()
+    }
+
+    pub fn set(&self, value: T) {
+        *self.0.write().expect("lock poisoned") = Arc::new(value);
+    }
+}
+
+/// Checks that an origin is a secure context the RP ID is a registrable domain suffix of, as
+/// browsers require.
+fn check_web_origin(rp_id: &str, origin: &Url, option: &str) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    let Some(domain) = origin.domain() else {
+        return vec![format!("{option} {origin} must have a domain name")];
+    };
+    if domain != rp_id && !domain.ends_with(&format!(".{rp_id}")) {
+        problems.push(format!(
+            "{option} {origin} is not on {rp_id} or one of its subdomains, as the RP ID requires"
+        ));
+    }
+    if origin.scheme() != "https" && domain != "localhost" {
+        problems.push(format!(
+            "{option} {origin} must use https, browsers only allow WebAuthn on secure origins"
+        ));
+    }
+    if origin.path() != "/" || origin.query().is_some() || origin.fragment().is_some() {
+        problems.push(format!(
+            "{option} {origin} must be an origin without path, query or fragment"
+        ));
+    }
+
+    problems
+}
+
+pub fn check_origins(
+    rp_id: &str,
+    rp_origin: &str,
+    extra_allowed_origins: &[String],
+) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    if rp_id.is_empty()
+        || rp_id
+            .chars()
+            .any(|c| !(c.is_ascii_alphanumeric() || c == '-' || c == '.'))
+    {
+        problems.push(format!(
+            "RP ID {rp_id:?} must be a domain name, without scheme, port or path"
+        ));
+    } else if rp_id != rp_id.to_ascii_lowercase() {
+        problems.push(format!("RP ID {rp_id} must be lowercase"));
+    }
+
+    match Url::parse(rp_origin) {
+        Ok(origin) => problems.extend(check_web_origin(rp_id, &origin, "RP origin")),
+        Err(err) => problems.push(format!("RP origin {rp_origin:?} is not a URL: {err}")),
+    }
+
+    for origin in extra_allowed_origins {
+        match Url::parse(origin) {
+            // Other schemes are used by native apps, e.g. `android:apk-key-hash:...`.
+            Ok(url) if ["http", "https"].contains(&url.scheme()) => {
+                problems.extend(check_web_origin(rp_id, &url, "extra allowed origin"))
+            }
+            Ok(_) => {}
+            Err(err) => problems.push(format!(
+                "extra allowed origin {origin:?} is not a URL: {err}"
+            )),
+        }
+    }
+
+    problems
+}
+
+/// Checks that the session cookie set on the origin is sent back to it, and thus also to the
+/// protected hosts under the cookie domain.
+fn check_cookie_domain(cookie_domain: &str, rp_origin: &str) -> Option<String> {
+    let host = Url::parse(rp_origin).ok()?.host_str()?.to_string();
+    if relying_party::domain_matches(&host, cookie_domain) {
+        return None;
+    }
+
+    Some(format!(
+        "cookie domain {cookie_domain} does not cover the host of RP origin {rp_origin}"
+    ))
+}
+
+/// Checks the relying parties from the config file and that no two relying parties share a cookie
+/// domain, since requests are routed by it.
+fn check_relying_parties(args: &ServeArgs) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    for (name, rp) in &args.relying_parties {
+        if name == DEFAULT_RELYING_PARTY {
+            problems.push(format!(
+                "relying party name {name} is reserved for the one configured with --rp-id"
+            ));
+        }
+        problems.extend(relying_party::check_name(name));
+        problems.extend(
+            check_origins(&rp.rp_id, &rp.rp_origin, &rp.extra_allowed_origin)
+                .into_iter()
+                .chain(check_cookie_domain(rp.cookie_domain(), &rp.rp_origin))
+                .chain(
+                    rp.password_file
+                        .as_deref()
+                        .map(password::check_password_file)
+                        .unwrap_or_default(),
+                )
+                .map(|problem| format!("relying party {name}: {problem}")),
+        );
+    }
+
+    let mut cookie_domains = HashMap::new();
+    for (name, rp) in args.relying_parties() {
+        if let Some(other) = cookie_domains.insert(rp.cookie_domain().to_string(), name.clone()) {
+            problems.push(format!(
+                "relying parties {other} and {name} have the same cookie domain {}",
+                rp.cookie_domain()
+            ));
+        }
+    }
+
+    problems
+}
+
+pub fn check_session_secret(path: &Path) -> Option<String> {
+    match std::fs::read_to_string(path) {
+        Ok(secret) => Key::try_from(secret.as_bytes()).err().map(|_| {
+            format!(
+                "session secret file {} must contain at least 64 bytes, it contains {}",
+                path.display(),
+                secret.len()
+            )
+        }),
+        Err(err) => Some(format!(
+            "reading session secret file {}: {err}",
+            path.display()
+        )),
+    }
+}
+
+/// Checks that a file can be created in the directory, which also catches read-only mounts and
+/// sandboxing that permission bits do not show.
+pub fn check_writable_directory(path: &Path, description: &str) -> Option<String> {
+    if !path.is_dir() {
+        return Some(format!(
+            "{description} {} does not exist or is not a directory",
+            path.display()
+        ));
+    }
+
+    let probe = path.join(format!(".webauthn-tiny-check-{}", std::process::id()));
+    match OpenOptions::new().write(true).create_new(true).open(&probe) {
+        Ok(_) => {
+            _ = std::fs::remove_file(&probe);
+            None
+        }
+        Err(err) => Some(format!(
+            "{description} {} is not writable: {err}",
+            path.display()
+        )),
+    }
+}
+
+/// Returns every problem found with the options.
+pub fn check(args: &ServeArgs) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    match (&args.rp_id, &args.rp_origin) {
+        (Some(rp_id), Some(rp_origin)) => {
+            problems.extend(check_origins(rp_id, rp_origin, &args.extra_allowed_origin));
+            problems.extend(check_cookie_domain(
+                args.cookie_domain.as_deref().unwrap_or(rp_id),
+                rp_origin,
+            ));
+        }
+        _ => problems.push("--rp-id and --rp-origin are required".to_string()),
+    }
+
+    match &args.session_secret_file {
+        Some(path) => problems.extend(check_session_secret(path)),
+        None => problems.push("--session-secret-file is required".to_string()),
+    }
+
+    match &args.password_file {
+        Some(path) => problems.extend(password::check_password_file(path)),
+        None => problems.push("--password-file is required".to_string()),
+    }
+
+    problems.extend(check_relying_parties(args));
+    problems.extend(check_writable_directory(
+        &args.storage.state_directory,
+        "state directory",
+    ));
+    if let Some(parent) = args.unix_socket.as_deref().and_then(Path::parent) {
+        problems.extend(check_writable_directory(parent, "Unix socket directory"));
+    }
+    match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => {
+            if let Err(err) = tls::load_config(cert, key) {
+                problems.push(err.to_string());
+            }
+            if args.unix_socket.is_some() {
+                problems.push("--tls-cert cannot be used with --unix-socket".to_string());
+            }
+        }
+        (None, None) => {
+            if args.tls_redirect_address.is_some() {
+                problems.push("--tls-redirect-address requires --tls-cert".to_string());
+            }
+        }
+        _ => problems.push("--tls-cert and --tls-key must be given together".to_string()),
+    }
+    for host in args.hosts.keys() {
+        if host != &host.to_ascii_lowercase() {
+            problems.push(format!("host {host} in the config file must be lowercase"));
+        }
+    }
+    for path in args
+        .storage
+        .storage_key_file
+        .iter()
+        .chain(&args.storage.previous_storage_key_file)
+    {
+        if let Err(err) = StorageKey::load(path) {
+            problems.push(err.to_string());
+        }
+    }
+
+    // A zero period would make the interval timer of a background task panic.
+    if args.audit_checkpoint_interval == 0 {
+        problems.push("--audit-checkpoint-interval must be at least 1".to_string());
+    }
+    if args.metrics_refresh_interval == 0 {
+        problems.push("--metrics-refresh-interval must be at least 1".to_string());
+    }
+    #[cfg(feature = "sqlite")]
+    if args.backup_interval == 0 {
+        problems.push("--backup-interval must be at least 1".to_string());
+    }
+    // Keeping no backups would delete the one that was just written.
+    #[cfg(feature = "sqlite")]
+    if args.backup_retention == 0 {
+        problems.push("--backup-retention must be at least 1".to_string());
+    }
+
+    #[cfg(feature = "sqlite")]
+    if let Some(directory) = &args.backup_directory {
+        if let Err(err) = args.storage.sqlite_path() {
+            problems.push(err.to_string());
+        }
+        // The backup directory is created on startup if it does not exist.
+        if directory.exists() {
+            problems.extend(check_writable_directory(directory, "backup directory"));
+        }
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Cli;
+    use clap::CommandFactory;
+
+    fn serve_args(file: &str, args: &[&str]) -> ServeArgs {
+        let matches = Cli::command()
+            .try_get_matches_from([&["webauthn-tiny", "serve"], args].concat())
+            .unwrap();
+        let (_, matches) = matches.subcommand().unwrap();
+        let mut args = <ServeArgs as clap::FromArgMatches>::from_arg_matches(matches).unwrap();
+        toml::from_str::<ConfigFile>(file)
+            .unwrap()
+            .apply_serve(&mut args, matches);
+        args
+    }
+
+    #[test]
+    fn test_config_file_precedence() {
+        let file = r#"
+            rp-id = "file.example.com"
+            extra-allowed-origin = ["https://a.file.example.com"]
+            state-directory = "/var/lib/file"
+            session-cache-ttl = 10
+            counter-regression-action = "disable"
+            reject-backup-eligible = true
+            unix-socket-mode = "600"
+
+            [hosts."git.file.example.com"]
+            device-bound = true
+            allowed-groups = ["developers"]
+        "#;
+
+        let args = serve_args(file, &[]);
+        assert_eq!(args.rp_id.as_deref(), Some("file.example.com"));
+        assert_eq!(args.extra_allowed_origin, ["https://a.file.example.com"]);
+        assert_eq!(args.storage.state_directory, Path::new("/var/lib/file"));
+        assert_eq!(args.session_cache_ttl, 10);
+        assert_eq!(
+            args.counter_regression_action,
+            CounterRegressionAction::Disable
+        );
+        assert!(args.reject_backup_eligible);
+        assert_eq!(args.unix_socket_mode, 0o600);
+        assert_eq!(
+            args.hosts["git.file.example.com"].allowed_groups,
+            ["developers"]
+        );
+        // Defaults apply to options missing from the file.
+        assert_eq!(args.session_cache_capacity, 10000);
+
+        let args = serve_args(
+            file,
+            &[
+                "--rp-id=flag.example.com",
+                "--extra-allowed-origin=https://b.flag.example.com",
+                "--state-directory=/var/lib/flag",
+                "--session-cache-ttl=20",
+            ],
+        );
+        assert_eq!(args.rp_id.as_deref(), Some("flag.example.com"));
+        assert_eq!(args.extra_allowed_origin, ["https://b.flag.example.com"]);
+        assert_eq!(args.storage.state_directory, Path::new("/var/lib/flag"));
+        assert_eq!(args.session_cache_ttl, 20);
+
+        assert!(toml::from_str::<ConfigFile>("rp_id = \"foo\"").is_err());
+        assert!(toml::from_str::<ConfigFile>("[hosts.foo]\nfoo = true").is_err());
+        assert!(toml::from_str::<ConfigFile>("unix-socket-mode = \"999\"").is_err());
+    }
+
+    #[test]
+    fn test_check_origins() {
+        assert!(check_origins("example.com", "https://example.com", &[]).is_empty());
+        assert!(check_origins(
+            "example.com",
+            "https://auth.example.com:8443",
+            &[
+                "https://other.example.com".to_string(),
+                "android:apk-key-hash:foo".to_string()
+            ]
+        )
+        .is_empty());
+        assert!(check_origins("localhost", "http://localhost:8080", &[]).is_empty());
+
+        assert_eq!(
+            check_origins("https://example.com", "https://example.com", &[]).len(),
+            2
+        );
+        assert_eq!(
+            check_origins("example.com", "https://myexample.com", &[]).len(),
+            1
+        );
+        assert_eq!(
+            check_origins("example.com", "http://example.com/login", &[]).len(),
+            2
+        );
+        assert_eq!(check_origins("example.com", "example.com", &[]).len(), 1);
+
+        // Every problem is reported, including those of each extra origin.
+        assert_eq!(
+            check_origins(
+                "Example.com",
+                "https://127.0.0.1",
+                &["http://other.com".to_string(), "::".to_string()]
+            )
+            .len(),
+            5
+        );
+    }
+
+    #[test]
+    fn test_check_intervals() {
+        let problems = check(&serve_args(
+            "",
+            &[
+                "--audit-checkpoint-interval=0",
+                "--metrics-refresh-interval=0",
+            ],
+        ));
+        assert!(problems.contains(&"--audit-checkpoint-interval must be at least 1".to_string()));
+        assert!(problems.contains(&"--metrics-refresh-interval must be at least 1".to_string()));
+
+        #[cfg(feature = "sqlite")]
+        {
+            let problems = check(&serve_args(
+                "",
+                &["--backup-interval=0", "--backup-retention=0"],
+            ));
+            assert!(problems.contains(&"--backup-interval must be at least 1".to_string()));
+            assert!(problems.contains(&"--backup-retention must be at least 1".to_string()));
+        }
+
+        let problems = check(&serve_args("", &[]));
+        assert!(!problems.iter().any(|problem| problem.contains("at least")));
+    }
+
+    #[test]
+    fn test_check_relying_parties() {
+        let file = r#"
+            rp-id = "example.com"
+            rp-origin = "https://auth.example.com"
+
+            [relying-parties.other]
+            rp-id = "other.org"
+            rp-origin = "https://auth.other.org"
+
+            [relying-parties.corp]
+            rp-id = "corp.example.com"
+            rp-origin = "https://auth.corp.example.com"
+            cookie-domain = "auth.corp.example.com"
+        "#;
+        let args = serve_args(file, &[]);
+        assert!(check_relying_parties(&args).is_empty());
+        assert_eq!(
+            args.relying_parties()
+                .iter()
+                .map(|(name, rp)| (name.as_str(), rp.cookie_domain()))
+                .collect::<Vec<_>>(),
+            [
+                ("default", "example.com"),
+                ("corp", "auth.corp.example.com"),
+                ("other", "other.org")
+            ]
+        );
+
+        let file = r#"
+            rp-id = "example.com"
+            rp-origin = "https://auth.example.com"
+
+            [relying-parties.default]
+            rp-id = "other.org"
+            rp-origin = "https://auth.other.org"
+            cookie-domain = "auth.example.org"
+
+            [relying-parties.Corp]
+            rp-id = "example.com"
+            rp-origin = "https://example.com"
+        "#;
+        // The reserved name, the invalid name, the cookie domain not covering the origin and the
+        // duplicate cookie domain.
+        assert_eq!(check_relying_parties(&serve_args(file, &[])).len(), 4);
+
+        assert_eq!(
+            check_cookie_domain("example.com", "https://auth.example.com"),
+            None
+        );
+        assert!(check_cookie_domain("auth.example.com", "https://example.com").is_some());
+    }
+
+    #[test]
+    fn test_check_session_secret() {
+        let path = std::env::temp_dir().join(format!("webauthn-tiny-{}", uuid::Uuid::new_v4()));
+        assert!(check_session_secret(&path).is_some());
+
+        std::fs::write(&path, "a".repeat(63)).unwrap();
+        assert!(check_session_secret(&path).is_some());
+
+        std::fs::write(&path, "a".repeat(64)).unwrap();
+        assert!(check_session_secret(&path).is_none());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,460 @@
+//! The `/admin` console, where admins manage the users of a relying party. Admins are named with
+//! `--admin-user` or `--admin-group`, and every action they take must be confirmed with a fresh
+//! assertion of one of their passkeys, so that a hijacked session alone cannot be used to change
+//! other users.
+
+use crate::{
+    app::{encode_cred_id, App, AppError, SharedAppState},
+    audit::{AuditEvent, AuditEventKind, AuditLogParams, ClientInfo},
+    config::Reloadable,
+    handlers::{finish_html, CredentialIDWithName, LoggedIn, Templates},
+    policy::CredentialPolicy,
+    session::SESSIONKEY_USERNAME,
+};
+use axum::{
+    body::Body,
+    extract::{self, Path, Query},
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::{Html, IntoResponse, Redirect, Response},
+    Extension, Json,
+};
+use axum_macros::debug_handler;
+use liquid::Template;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tower_sessions::Session;
+use tracing::{error, info, trace};
+use webauthn_rs::{prelude::*, Webauthn};
+use webauthn_rs_proto::{PublicKeyCredential, RequestChallengeResponse};
+
+const SESSIONKEY_ADMINASSERTION: &str = "admin_assertion";
+
+/// Seconds an admin has to complete a passkey assertion after it was started.
+const ASSERTION_LIFETIME: i64 = 300;
+
+/// The name of the admin making the request, added by `require_admin`.
+#[derive(Clone)]
+pub struct Admin(String);
+
+/// Middleware that only allows admins. Users who are not logged in are sent to log in first when
+/// requesting a page, and refused when calling the API.
+pub async fn require_admin(
+    LoggedIn(logged_in): LoggedIn,
+    session: Session,
+    policy: Extension<Reloadable<CredentialPolicy>>,
+    Extension(app): Extension<SharedAppState>,
+    mut req: Request<Body>,
+    next: Next,
+) -> Response {
+    if !logged_in {
+        return if req.uri().path().starts_with("/api/") {
+            StatusCode::UNAUTHORIZED.into_response()
+        } else {
+            Redirect::temporary("/authenticate?redirect_url=/admin").into_response()
+        };
+    }
+
+    let username = match session.get::<String>(SESSIONKEY_USERNAME).await {
+        Ok(Some(username)) => username,
+        Ok(None) => return AppError::BadSession.into_response(),
+        Err(err) => return AppError::from(err).into_response(),
+    };
+    let groups = match app.find_profile(&username).await {
+        Ok(profile) => profile.unwrap_or_default().groups,
+        Err(err) => return err.into_response(),
+    };
+    if !policy.get().admins.includes(&username, &groups) {
+        info!("user {username} is not an admin");
+        return AppError::NotAdmin.into_response();
+    }
+
+    req.extensions_mut().insert(Admin(username));
+    next.run(req).await
+}
+
+fn render(template: &Template, data: &liquid::Object) -> Result<Response, AppError> {
+    match template.render(data) {
+        Ok(html) => Ok(Html(finish_html(html)).into_response()),
+        Err(e) => {
+            error!("template.render: {e}");
+            Err(AppError::UnknownError)
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct UserRow {
+    username: String,
+    display_name: Option<String>,
+    groups: Vec<String>,
+    credentials: usize,
+    /// Whether the user has credentials but cannot log in since all of them are disabled.
+    locked: bool,
+}
+
+#[debug_handler]
+pub async fn get_users_console_handler(
+    Extension(Admin(admin)): Extension<Admin>,
+    templates: Extension<Arc<Templates>>,
+    Extension(app): Extension<SharedAppState>,
+    Extension(passwords): Extension<Reloadable<HashMap<String, String>>>,
+) -> Result<Response, AppError> {
+    trace!("get_users_console_handler");
+
+    let passwords = passwords.get();
+    let usernames: Vec<&str> = passwords.keys().map(String::as_str).collect();
+    let users: Vec<UserRow> = app
+        .list_users_in(&usernames)
+        .await?
+        .into_iter()
+        .map(|user| UserRow {
+            locked: !user.credentials.is_empty() && user.credentials.iter().all(|c| c.disabled),
+            credentials: user.credentials.len(),
+            username: user.username,
+            display_name: user.profile.display_name,
+            groups: user.profile.groups,
+        })
+        .collect();
+
+    render(
+        &templates.admin_users_template,
+        &liquid::object!({
+            "admin": admin,
+            "users": users,
+        }),
+    )
+}
+
+/// Checks that the user is in the password file of the relying party the console is served for,
+/// like the users listed on `/admin`, since the users of all relying parties share the storage.
+fn check_listed(
+    passwords: &Reloadable<HashMap<String, String>>,
+    username: &str,
+) -> Result<(), AppError> {
+    if !passwords.get().contains_key(username) {
+        return Err(AppError::UserNotFound);
+    }
+
+    Ok(())
+}
+
+#[debug_handler]
+pub async fn get_user_console_handler(
+    Path(username): Path<String>,
+    Extension(Admin(admin)): Extension<Admin>,
+    templates: Extension<Arc<Templates>>,
+    Extension(app): Extension<SharedAppState>,
+    Extension(passwords): Extension<Reloadable<HashMap<String, String>>>,
+) -> Result<Response, AppError> {
+    trace!("get_user_console_handler");
+
+    check_listed(&passwords, &username)?;
+    let user = app
+        .find_user(&username)
+        .await?
+        .ok_or(AppError::UserNotFound)?;
+    let credentials: Vec<CredentialIDWithName> = user
+        .credentials
+        .iter()
+        .map(CredentialIDWithName::from)
+        .collect();
+    let sessions: Vec<String> = app
+        .user_sessions(&username)
+        .await?
+        .into_iter()
+        .map(|expiry_date| expiry_date.format(&Rfc3339).unwrap_or_default())
+        .collect();
+
+    render(
+        &templates.admin_user_template,
+        &liquid::object!({
+            "admin": admin,
+            "username": user.username,
+            "display_name": user.display_name(),
+            "email": user.profile.email,
+            "groups": user.profile.groups,
+            "locked": !credentials.is_empty() && user.credentials.iter().all(|c| c.disabled),
+            "credentials": credentials,
+            "sessions": sessions,
+        }),
+    )
+}
+
+#[debug_handler]
+pub async fn get_events_console_handler(
+    params: Query<AuditLogParams>,
+    Extension(Admin(admin)): Extension<Admin>,
+    templates: Extension<Arc<Templates>>,
+    Extension(app): Extension<SharedAppState>,
+) -> Result<Response, AppError> {
+    trace!("get_events_console_handler");
+
+    let username = params.username.clone();
+    let query = params.0.into();
+    let events = app.audit_events(&query).await?;
+    let next = events
+        .last()
+        .filter(|_| events.len() == query.limit)
+        .map(|event| event.id);
+
+    render(
+        &templates.admin_events_template,
+        &liquid::object!({
+            "admin": admin,
+            "username": username,
+            "events": events,
+            "next": next,
+        }),
+    )
+}
+
+/// A passkey authentication started for an admin action, with the time it was started at.
+#[derive(Serialize, Deserialize)]
+struct PendingAssertion {
+    state: PasskeyAuthentication,
+    started: i64,
+}
+
+#[debug_handler]
+pub async fn assertion_start_console_handler(
+    Extension(Admin(admin)): Extension<Admin>,
+    session: Session,
+    Extension(app): Extension<SharedAppState>,
+    webauthn: Extension<Arc<Webauthn>>,
+) -> Result<Json<RequestChallengeResponse>, AppError> {
+    trace!("assertion_start_console_handler");
+
+    let user = app.find_user(&admin).await?.ok_or(AppError::UserNotFound)?;
+    let passkeys: Vec<_> = user
+        .credentials
+        .iter()
+        .filter(|c| !c.disabled)
+        .map(|c| c.credential.to_owned())
+        .collect();
+    if passkeys.is_empty() {
+        info!("admin {admin} has no enabled passkey to confirm actions with");
+        return Err(AppError::NoEnabledCredentials);
+    }
+
+    let Ok((req_chal, state)) = webauthn.start_passkey_authentication(&passkeys) else {
+        return Err(AppError::WebauthnFailed);
+    };
+
+    session
+        .insert(
+            SESSIONKEY_ADMINASSERTION,
+            PendingAssertion {
+                state,
+                started: OffsetDateTime::now_utc().unix_timestamp(),
+            },
+        )
+        .await?;
+
+    Ok(Json(req_chal))
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum AdminAction {
+    DeleteCredential {
+        username: String,
+        credential: CredentialID,
+    },
+    RevokeSessions {
+        username: String,
+    },
+    Unlock {
+        username: String,
+    },
+}
+
+impl AdminAction {
+    /// The user the action is performed on.
+    fn username(&self) -> &str {
+        match self {
+            AdminAction::DeleteCredential { username, .. }
+            | AdminAction::RevokeSessions { username }
+            | AdminAction::Unlock { username } => username,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AdminActionRequestPayload {
+    #[serde(flatten)]
+    action: AdminAction,
+    assertion: PublicKeyCredential,
+}
+
+/// Checks the assertion against the authentication started by `assertion_start_console_handler`,
+/// which can only be used once.
+async fn verify_assertion(
+    session: &Session,
+    app: &App,
+    webauthn: &Webauthn,
+    assertion: &PublicKeyCredential,
+) -> Result<(), AppError> {
+    let Some(pending) = session
+        .remove::<PendingAssertion>(SESSIONKEY_ADMINASSERTION)
+        .await?
+    else {
+        return Err(AppError::BadSession);
+    };
+    if OffsetDateTime::now_utc().unix_timestamp() - pending.started > ASSERTION_LIFETIME {
+        return Err(AppError::AssertionExpired);
+    }
+
+    let Ok(auth_result) = webauthn.finish_passkey_authentication(assertion, &pending.state) else {
+        return Err(AppError::WebauthnFailed);
+    };
+    if auth_result.needs_update() {
+        app.update_credential(auth_result).await?;
+    }
+
+    Ok(())
+}
+
+async fn delete_user_credential(
+    app: &App,
+    username: &str,
+    cred_id: &CredentialID,
+) -> Result<(), AppError> {
+    let user = app
+        .find_user(username)
+        .await?
+        .ok_or(AppError::UserNotFound)?;
+    if !user
+        .credentials
+        .iter()
+        .any(|c| c.credential.cred_id() == cred_id)
+    {
+        return Err(AppError::CredentialNotFound);
+    }
+
+    app.delete_credential(cred_id.clone()).await
+}
+
+/// Performs an admin action after verifying the admin's assertion. The assertion is recorded in
+/// the audit log under the admin's name, followed by the action under the affected user's name.
+#[debug_handler]
+pub async fn action_console_handler(
+    Extension(Admin(admin)): Extension<Admin>,
+    session: Session,
+    client: ClientInfo,
+    Extension(app): Extension<SharedAppState>,
+    webauthn: Extension<Arc<Webauthn>>,
+    Extension(passwords): Extension<Reloadable<HashMap<String, String>>>,
+    payload: extract::Json<AdminActionRequestPayload>,
+) -> Result<StatusCode, AppError> {
+    trace!("action_console_handler");
+
+    check_listed(&passwords, payload.action.username())?;
+
+    let result = verify_assertion(&session, &app, &webauthn, &payload.assertion).await;
+    app.record(
+        AuditEvent::new(AuditEventKind::AdminAssertion, &client)
+            .outcome(&result)
+            .username(admin.clone())
+            .credential(encode_cred_id(&CredentialID::from(
+                payload.assertion.raw_id.clone(),
+            ))),
+    )
+    .await;
+    result?;
+
+    let (event, result) = match &payload.action {
+        AdminAction::DeleteCredential {
+            username,
+            credential,
+        } => (
+            AuditEvent::new(AuditEventKind::CredentialDeleted, &client)
+                .username(username.clone())
+                .credential(encode_cred_id(credential)),
+            delete_user_credential(&app, username, credential).await,
+        ),
+        AdminAction::RevokeSessions { username } => (
+            AuditEvent::new(AuditEventKind::SessionsRevoked, &client).username(username.clone()),
+            app.revoke_user_sessions(username).await.map(|_| ()),
+        ),
+        AdminAction::Unlock { username } => (
+            AuditEvent::new(AuditEventKind::UserUnlocked, &client).username(username.clone()),
+            app.unlock_user(username).await.map(|_| ()),
+        ),
+    };
+    let kind = event.kind;
+    app.record(event.outcome(&result)).await;
+    result?;
+
+    info!("admin {admin} performed {kind}");
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_listed() {
+        let passwords = Reloadable::new(HashMap::from([(
+            "foo_user".to_string(),
+            "hash".to_string(),
+        )]));
+
+        assert!(check_listed(&passwords, "foo_user").is_ok());
+        assert!(matches!(
+            check_listed(&passwords, "other_user"),
+            Err(AppError::UserNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_admin_action_payload() {
+        let assertion = serde_json::json!({
+            "id": "AAEC",
+            "rawId": "AAEC",
+            "response": {
+                "authenticatorData": "AAEC",
+                "clientDataJSON": "AAEC",
+                "signature": "AAEC",
+                "userHandle": null,
+            },
+            "extensions": {},
+            "type": "public-key",
+        });
+
+        let payload: AdminActionRequestPayload = serde_json::from_value(serde_json::json!({
+            "action": "delete_credential",
+            "username": "foo_user",
+            "credential": "AAEC",
+            "assertion": assertion,
+        }))
+        .unwrap();
+        assert!(matches!(
+            payload.action,
+            AdminAction::DeleteCredential { username, credential }
+                if username == "foo_user" && credential.as_slice() == [0, 1, 2]
+        ));
+
+        let payload: AdminActionRequestPayload = serde_json::from_value(serde_json::json!({
+            "action": "unlock",
+            "username": "foo_user",
+            "assertion": assertion,
+        }))
+        .unwrap();
+        assert!(
+            matches!(payload.action, AdminAction::Unlock { username } if username == "foo_user")
+        );
+
+        assert!(
+            serde_json::from_value::<AdminActionRequestPayload>(serde_json::json!({
+                "action": "delete_user",
+                "username": "foo_user",
+                "assertion": assertion,
+            }))
+            .is_err()
+        );
+    }
+}
@@ -1,9 +1,17 @@
-use crate::app::{AppError, SharedAppState};
+use crate::{
+    app::{encode_cred_id, App, AppError, CredentialWithName, SharedAppState, UserProfile},
+    audit::{AuditEvent, AuditEventKind, AuditLogParams, ClientInfo},
+    config::Reloadable,
+    listen::PeerAddr,
+    policy::{CounterRegressionAction, CredentialPolicy},
+    relying_party::{domain_matches, CookieDomain},
+    session::SESSIONKEY_USERNAME,
+};
 use argon2::{password_hash::PasswordHash, Argon2, PasswordVerifier};
 use axum::{
     body::Body,
-    extract::{self, ConnectInfo, FromRequestParts, Path, Query},
-    http::{header, request::Parts, HeaderMap, Request, StatusCode, Uri},
+    extract::{self, ConnectInfo, FromRequestParts, MatchedPath, Path, Query},
+    http::{header, request::Parts, HeaderMap, HeaderValue, Request, StatusCode, Uri},
     middleware::Next,
     response::{Html, IntoResponse, Redirect, Response},
     Extension, Json,
@@ -11,15 +19,11 @@ use axum::{
 use axum_macros::debug_handler;
 use base64::{engine::general_purpose, Engine as _};
 use liquid::Template;
-use metrics::counter;
+use metrics::{counter, histogram};
 use serde::{Deserialize, Serialize};
-use std::{
-    collections::HashMap,
-    net::{IpAddr, SocketAddr},
-    sync::Arc,
-};
+use std::{collections::HashMap, net::IpAddr, sync::Arc, time::Instant};
 use tower_sessions::Session;
-use tracing::{error, info, trace};
+use tracing::{error, info, trace, warn};
 use webauthn_rs::{prelude::*, Webauthn};
 use webauthn_rs_proto::{
     CreationChallengeResponse, PublicKeyCredential, RegisterPublicKeyCredential,
@@ -30,9 +34,9 @@ const SESSIONKEY_LOGGEDIN: &str = "logged_in";
 const SESSIONKEY_PASSKEYREGISTRATION: &str = "passkey_registration";
 const SESSIONKEY_PASSKEYAUTHENTICATION: &str = "passkey_authentication";
 const SESSIONKEY_REDIRECTURL: &str = "redirect_url";
-const SESSIONKEY_USERNAME: &str = "username";
+const SESSIONKEY_DEVICEBOUND: &str = "device_bound";
 
-pub struct LoggedIn(bool);
+pub struct LoggedIn(pub bool);
 
 impl<S> FromRequestParts<S> for LoggedIn
 where
@@ -53,43 +57,198 @@ where
     }
 }
 
+impl<S> FromRequestParts<S> for ClientInfo
+where
+    S: Send + Sync,
+{
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<PeerAddr>>()
+            .map(|ConnectInfo(peer)| *peer);
+        Ok(ClientInfo {
+            ip: peer
+                .filter(PeerAddr::is_trusted_proxy)
+                .and_then(|_| forwarded_client_ip(&parts.headers))
+                .or_else(|| peer.and_then(|peer| peer.ip())),
+            user_agent: parts
+                .headers
+                .get(header::USER_AGENT)
+                .and_then(|user_agent| user_agent.to_str().ok())
+                .map(String::from),
+        })
+    }
+}
+
+/// Returns the client address added to X-Forwarded-For by the reverse proxy. This is the last
+/// entry, since any entries before it were sent by the client and cannot be trusted. Only used
+/// for requests from a trusted proxy.
+fn forwarded_client_ip(headers: &HeaderMap) -> Option<IpAddr> {
+    headers
+        .get("x-forwarded-for")
+        .and_then(|x_forwarded_for| x_forwarded_for.to_str().ok())
+        .and_then(|s| s.rsplit(',').next())
+        .and_then(|s| s.trim().parse::<IpAddr>().ok())
+        .map(|ip| ip.to_canonical())
+}
+
+/// Counts a failed registration or authentication, labeled with the name of the error like the
+/// reason of an audit event.
+fn count_failure(metric: &'static str, err: AppError) -> AppError {
+    counter!(metric, "reason" => format!("{err:?}")).increment(1);
+    err
+}
+
+/// Middleware that requires a logged in session. Requests are counted by the protected host from
+/// the X-Forwarded-Host header, see [`host_label`].
 pub async fn require_logged_in(
     LoggedIn(logged_in): LoggedIn,
+    policy: Extension<Reloadable<CredentialPolicy>>,
+    Extension(CookieDomain(cookie_domain)): Extension<CookieDomain>,
     req: Request<Body>,
     next: Next,
 ) -> Response {
+    let host = req
+        .headers()
+        .get("x-forwarded-host")
+        .and_then(|host| host.to_str().ok());
+    let host = host_label(host, &policy.get(), &cookie_domain);
+
     if logged_in {
-        counter!("authorized_requests").increment(1);
+        counter!("authorized_requests", "host" => host).increment(1);
         next.run(req).await
     } else {
-        counter!("unauthorized_requests").increment(1);
+        counter!("unauthorized_requests", "host" => host).increment(1);
         StatusCode::UNAUTHORIZED.into_response()
     }
 }
 
-/// Middleware that only allows connections from a loopback address. This first checks the client
-/// address from the X-Forwarded-For header to determine if the request is coming from a local
-/// client. If X-Forwarded-For is not present (i.e. the request is not coming from a proxy), then
-/// the direct connection info is used.
+/// Returns the label of a protected host, which is sent by the client and must not create a new
+/// time series for every value. Hosts with a policy keep their name, other hosts under the cookie
+/// domain are labeled with the cookie domain and the rest with `other`. Requests that did not come
+/// through `/api/validate` have no host and an empty label.
+fn host_label(host: Option<&str>, policy: &CredentialPolicy, cookie_domain: &str) -> String {
+    let Some(host) = host.map(str::to_ascii_lowercase) else {
+        return String::new();
+    };
+
+    if policy.hosts.contains_key(&host) {
+        host
+    } else if domain_matches(&host, cookie_domain) {
+        cookie_domain.to_string()
+    } else {
+        "other".to_string()
+    }
+}
+
+/// Middleware that records the latency of each request by method, route and status. It is added
+/// as a route layer, so that the route is the matched path and unknown paths are not recorded.
+pub async fn record_request_duration(req: Request<Body>, next: Next) -> Response {
+    let method = req.method().to_string();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_default();
+
+    let start = Instant::now();
+    let response = next.run(req).await;
+    histogram!(
+        "http_request_duration_seconds",
+        "method" => method,
+        "route" => route,
+        "status" => response.status().as_u16().to_string(),
+    )
+    .record(start.elapsed());
+
+    response
+}
+
+/// Handler for nginx's auth_request subrequests. The protected host is taken from the
+/// X-Forwarded-Host header and is checked against the credential policy, so that hosts requiring
+/// a device-bound credential reject sessions that were authenticated with a synced passkey, and
+/// hosts restricted to some groups reject users in none of them. The response carries the user's
+/// identity in Remote-User, Remote-Name, Remote-Email and Remote-Groups headers, which the proxy
+/// can pass on to the protected service.
+#[debug_handler]
+pub async fn validate_handler(
+    session: Session,
+    headers: HeaderMap,
+    policy: Extension<Reloadable<CredentialPolicy>>,
+    Extension(app): Extension<SharedAppState>,
+) -> Result<Response, AppError> {
+    trace!("validate_handler");
+
+    let policy = policy.get();
+    let host = headers
+        .get("x-forwarded-host")
+        .and_then(|host| host.to_str().ok());
+    if let Some(host) = host {
+        if policy.requires_device_bound(host)
+            && !session
+                .get::<bool>(SESSIONKEY_DEVICEBOUND)
+                .await?
+                .unwrap_or_default()
+        {
+            info!("host {host} requires a device-bound credential");
+            return Err(AppError::DeviceBoundCredentialRequired);
+        }
+    }
+
+    let mut response = StatusCode::OK.into_response();
+
+    let Some(username) = session.get::<String>(SESSIONKEY_USERNAME).await? else {
+        return Ok(response);
+    };
+    let profile = app.find_profile(&username).await?.unwrap_or_default();
+
+    if let Some(host) = host {
+        if !policy.allows_groups(host, &profile.groups) {
+            info!("user {username} is not in a group allowed on host {host}");
+            return Err(AppError::GroupNotAllowed);
+        }
+    }
+
+    let identity_headers = [
+        ("remote-user", Some(username.clone())),
+        (
+            "remote-name",
+            Some(profile.display_name.unwrap_or(username)),
+        ),
+        ("remote-email", profile.email),
+        (
+            "remote-groups",
+            Some(profile.groups.join(",")).filter(|groups| !groups.is_empty()),
+        ),
+    ];
+    for (name, value) in identity_headers {
+        if let Some(value) = value.and_then(|value| HeaderValue::from_str(&value).ok()) {
+            response.headers_mut().insert(name, value);
+        }
+    }
+
+    Ok(response)
+}
+
+/// Middleware that only allows connections from a loopback address. If the request comes through
+/// a trusted reverse proxy (see [`PeerAddr::is_trusted_proxy`]) and has an X-Forwarded-For header,
+/// the client address from that header is checked. Otherwise the header is ignored, since the
+/// client could have set it, and the direct connection info is used, where connections over a
+/// Unix socket are local.
 pub async fn allow_only_localhost(
-    connect_info: ConnectInfo<SocketAddr>,
+    ConnectInfo(peer): ConnectInfo<PeerAddr>,
     req: Request<Body>,
     next: Next,
 ) -> Response {
-    if req
-        .headers()
-        .get("x-forwarded-for")
-        .map(|x_forwarded_for| {
-            x_forwarded_for
-                .to_str()
-                .ok()
-                .and_then(|s| s.split(',').next())
-                .and_then(|s| s.trim().parse::<IpAddr>().ok())
-                .filter(|ip| ip.to_canonical().is_loopback())
-                .is_some()
-        })
-        .unwrap_or_else(|| connect_info.ip().to_canonical().is_loopback())
-    {
+    let is_local = if peer.is_trusted_proxy() && req.headers().contains_key("x-forwarded-for") {
+        forwarded_client_ip(req.headers()).is_some_and(|ip| ip.is_loopback())
+    } else {
+        peer.is_local()
+    };
+
+    if is_local {
         next.run(req).await
     } else {
         StatusCode::UNAUTHORIZED.into_response()
@@ -99,7 +258,7 @@ pub async fn allow_only_localhost(
 #[debug_handler]
 pub async fn register_start_handler(
     session: Session,
-    shared_state: Extension<SharedAppState>,
+    Extension(app): Extension<SharedAppState>,
     webauthn: Extension<Arc<Webauthn>>,
 ) -> Result<Json<CreationChallengeResponse>, AppError> {
     trace!("register_start_handler");
@@ -108,8 +267,10 @@ pub async fn register_start_handler(
         return Err(AppError::BadSession);
     };
 
-    let app = shared_state.read().await;
-    let user = app.get_user_with_credentials(username).await?;
+    let user = app
+        .find_user(&username)
+        .await?
+        .ok_or(AppError::UserNotFound)?;
 
     let existing_credentials: Vec<CredentialID> = user
         .credentials
@@ -120,7 +281,7 @@ pub async fn register_start_handler(
     let Ok((req_chal, passkey_reg)) = webauthn.start_passkey_registration(
         user.id,
         &user.username,
-        &user.username, // use username as display name
+        user.display_name(),
         if existing_credentials.is_empty() {
             None
         } else {
@@ -150,18 +311,40 @@ pub struct RegisterEndRequestPayload {
 #[debug_handler]
 pub async fn register_end_handler(
     session: Session,
-    shared_state: Extension<SharedAppState>,
+    client: ClientInfo,
+    Extension(app): Extension<SharedAppState>,
     webauthn: Extension<Arc<Webauthn>>,
+    policy: Extension<Reloadable<CredentialPolicy>>,
     payload: extract::Json<RegisterEndRequestPayload>,
 ) -> Result<(), AppError> {
     trace!("register_end_handler");
 
+    let result = register_end(&session, &app, &webauthn, &policy.get(), &payload).await;
+
+    app.record(
+        AuditEvent::new(AuditEventKind::Registration, &client)
+            .outcome(&result)
+            .username(session.get::<String>(SESSIONKEY_USERNAME).await?)
+            .credential(encode_cred_id(&CredentialID::from(
+                payload.credential.raw_id.clone(),
+            ))),
+    )
+    .await;
+
+    result
+}
+
+async fn register_end(
+    session: &Session,
+    app: &App,
+    webauthn: &Webauthn,
+    policy: &CredentialPolicy,
+    payload: &RegisterEndRequestPayload,
+) -> Result<(), AppError> {
     let Some(username) = session.get::<String>(SESSIONKEY_USERNAME).await? else {
         return Err(AppError::BadSession);
     };
 
-    let app = shared_state.read().await;
-
     let Some(passkey_reg) = session
         .get::<PasskeyRegistration>(SESSIONKEY_PASSKEYREGISTRATION)
         .await?
@@ -171,15 +354,32 @@ pub async fn register_end_handler(
 
     let Ok(passkey) = webauthn.finish_passkey_registration(&payload.credential, &passkey_reg)
     else {
-        counter!("failed_registrations").increment(1);
         _ = session
             .remove::<PasskeyRegistration>(SESSIONKEY_PASSKEYREGISTRATION)
             .await?;
 
-        return Err(AppError::WebauthnFailed);
+        return Err(count_failure(
+            "failed_registrations",
+            AppError::WebauthnFailed,
+        ));
     };
 
-    let user = app.get_user_with_credentials(username.clone()).await?;
+    if !policy.allows_registration(&passkey) {
+        info!("refusing to register backup-eligible credential");
+        _ = session
+            .remove::<PasskeyRegistration>(SESSIONKEY_PASSKEYREGISTRATION)
+            .await?;
+
+        return Err(count_failure(
+            "failed_registrations",
+            AppError::BackupEligibleCredential,
+        ));
+    }
+
+    let user = app
+        .find_user(&username)
+        .await?
+        .ok_or(AppError::UserNotFound)?;
 
     if user
         .credentials
@@ -190,14 +390,14 @@ pub async fn register_end_handler(
         return Err(AppError::DuplicateCredential);
     }
 
-    app.add_credential(username, payload.name.clone(), &passkey)
+    app.add_credential(username.clone(), payload.name.clone(), &passkey)
         .await?;
 
     _ = session
         .remove::<PasskeyRegistration>(SESSIONKEY_PASSKEYREGISTRATION)
         .await?;
 
-    counter!("successful_registrations").increment(1);
+    counter!("successful_registrations", "user" => username).increment(1);
 
     Ok(())
 }
@@ -205,20 +405,44 @@ pub async fn register_end_handler(
 #[debug_handler]
 pub async fn authenticate_start_handler(
     session: Session,
-    shared_state: Extension<SharedAppState>,
+    client: ClientInfo,
+    Extension(app): Extension<SharedAppState>,
     webauthn: Extension<Arc<Webauthn>>,
 ) -> Result<Json<RequestChallengeResponse>, AppError> {
     trace!("authenticate_start_handler");
 
+    let result = authenticate_start(&session, &app, &webauthn).await;
+
+    // A successful start is recorded once the ceremony finishes, except for users without
+    // credentials, who are logged in by their password alone.
+    if let Err(err) = &result {
+        let event = AuditEvent::new(AuditEventKind::Authentication, &client)
+            .reason(*err)
+            .username(session.get::<String>(SESSIONKEY_USERNAME).await?);
+        app.record(if matches!(err, AppError::NoUserCredentials) {
+            event
+        } else {
+            event.failed()
+        })
+        .await;
+    }
+
+    result
+}
+
+async fn authenticate_start(
+    session: &Session,
+    app: &App,
+    webauthn: &Webauthn,
+) -> Result<Json<RequestChallengeResponse>, AppError> {
     let Some(username) = session.get::<String>(SESSIONKEY_USERNAME).await? else {
         return Err(AppError::BadSession);
     };
 
-    let user = shared_state
-        .read()
-        .await
-        .get_user_with_credentials(username.clone())
-        .await?;
+    let user = app
+        .find_user(&username)
+        .await?
+        .ok_or(AppError::UserNotFound)?;
 
     if user.credentials.is_empty() {
         info!("user does not have any credentials");
@@ -233,12 +457,23 @@ pub async fn authenticate_start_handler(
     let passkeys: Vec<_> = user
         .credentials
         .iter()
+        .filter(|c| !c.disabled)
         .map(|c| c.credential.to_owned())
         .collect();
 
+    if passkeys.is_empty() {
+        info!("all of the user's credentials are disabled");
+        return Err(count_failure(
+            "failed_authentications",
+            AppError::NoEnabledCredentials,
+        ));
+    }
+
     let Ok((req_chal, passkey_auth)) = webauthn.start_passkey_authentication(&passkeys) else {
-        counter!("failed_authentications").increment(1);
-        return Err(AppError::WebauthnFailed);
+        return Err(count_failure(
+            "failed_authentications",
+            AppError::WebauthnFailed,
+        ));
     };
 
     if let Err(e) = session
@@ -255,12 +490,36 @@ pub async fn authenticate_start_handler(
 #[debug_handler]
 pub async fn authenticate_end_handler(
     session: Session,
-    shared_state: Extension<SharedAppState>,
+    client: ClientInfo,
+    Extension(app): Extension<SharedAppState>,
     webauthn: Extension<Arc<Webauthn>>,
+    policy: Extension<Reloadable<CredentialPolicy>>,
     payload: extract::Json<PublicKeyCredential>,
 ) -> Result<(), AppError> {
     trace!("authenticate_end_handler");
 
+    let result =
+        authenticate_end(&session, &client, &app, &webauthn, &policy.get(), &payload).await;
+
+    app.record(
+        AuditEvent::new(AuditEventKind::Authentication, &client)
+            .outcome(&result)
+            .username(session.get::<String>(SESSIONKEY_USERNAME).await?)
+            .credential(encode_cred_id(&CredentialID::from(payload.raw_id.clone()))),
+    )
+    .await;
+
+    result
+}
+
+async fn authenticate_end(
+    session: &Session,
+    client: &ClientInfo,
+    app: &App,
+    webauthn: &Webauthn,
+    policy: &CredentialPolicy,
+    payload: &PublicKeyCredential,
+) -> Result<(), AppError> {
     let Some(passkey_authentication) = session
         .get::<PasskeyAuthentication>(SESSIONKEY_PASSKEYAUTHENTICATION)
         .await?
@@ -268,16 +527,57 @@ pub async fn authenticate_end_handler(
         return Err(AppError::BadSession);
     };
 
-    let Ok(auth_result) =
-        webauthn.finish_passkey_authentication(&payload.0, &passkey_authentication)
-    else {
-        counter!("failed_authentications").increment(1);
-        return Err(AppError::WebauthnFailed);
+    let auth_result = match webauthn.finish_passkey_authentication(payload, &passkey_authentication)
+    {
+        Ok(auth_result) => auth_result,
+        Err(WebauthnError::CredentialPossibleCompromise) => {
+            let err = count_failure("failed_authentications", AppError::CounterRegression);
+            _ = session
+                .remove::<PasskeyAuthentication>(SESSIONKEY_PASSKEYAUTHENTICATION)
+                .await?;
+
+            let username = session
+                .get::<String>(SESSIONKEY_USERNAME)
+                .await?
+                .unwrap_or_default();
+            let cred_id = CredentialID::from(payload.raw_id.clone());
+            warn!(
+                "signature counter regressed for credential {} of user {username}, the authenticator may have been cloned",
+                encode_cred_id(&cred_id)
+            );
+
+            if policy.counter_regression_action >= CounterRegressionAction::Metric {
+                counter!("counter_regressions").increment(1);
+            }
+
+            if policy.counter_regression_action >= CounterRegressionAction::Disable {
+                info!("disabling credential after signature counter regression");
+                let result = app.set_credential_disabled(cred_id.clone(), true).await;
+                app.record(
+                    AuditEvent::new(AuditEventKind::CredentialDisabled, client)
+                        .reason(AppError::CounterRegression)
+                        .outcome(&result)
+                        .username(username)
+                        .credential(encode_cred_id(&cred_id)),
+                )
+                .await;
+                result?;
+            }
+
+            return Err(err);
+        }
+        Err(_) => {
+            return Err(count_failure(
+                "failed_authentications",
+                AppError::WebauthnFailed,
+            ));
+        }
     };
 
-    let state = shared_state.read().await;
+    let device_bound = !auth_result.backup_eligible();
+
     if auth_result.needs_update() {
-        state.update_credential(auth_result).await?;
+        app.update_credential(auth_result).await?;
     }
 
     _ = session
@@ -289,7 +589,16 @@ pub async fn authenticate_end_handler(
         return Err(AppError::BadSession);
     }
 
-    counter!("successful_authentications").increment(1);
+    if let Err(e) = session.insert(SESSIONKEY_DEVICEBOUND, device_bound).await {
+        error!("session.insert: {e}");
+        return Err(AppError::BadSession);
+    }
+
+    let username = session
+        .get::<String>(SESSIONKEY_USERNAME)
+        .await?
+        .unwrap_or_default();
+    counter!("successful_authentications", "user" => username).increment(1);
 
     Ok(())
 }
@@ -299,18 +608,221 @@ pub struct GetCredentialsResponsePayload {
     pub data: Vec<CredentialIDWithName>,
 }
 
+#[debug_handler]
+pub async fn get_credentials_api_handler(
+    session: Session,
+    Extension(app): Extension<SharedAppState>,
+) -> Result<Json<GetCredentialsResponsePayload>, AppError> {
+    trace!("get_credentials_api_handler");
+
+    let Some(username) = session.get::<String>(SESSIONKEY_USERNAME).await? else {
+        return Err(AppError::BadSession);
+    };
+
+    let user = app
+        .find_user(&username)
+        .await?
+        .ok_or(AppError::UserNotFound)?;
+
+    Ok(Json(GetCredentialsResponsePayload {
+        data: user
+            .credentials
+            .iter()
+            .map(CredentialIDWithName::from)
+            .collect(),
+    }))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UpdateCredentialRequestPayload {
+    disabled: bool,
+}
+
+/// Fails with `CredentialNotFound` unless the credential belongs to the user, so that users cannot
+/// change each other's credentials by ID.
+async fn check_owns_credential(
+    app: &App,
+    username: &str,
+    cred_id: &CredentialID,
+) -> Result<(), AppError> {
+    let user = app
+        .find_user(username)
+        .await?
+        .ok_or(AppError::UserNotFound)?;
+    if !user
+        .credentials
+        .iter()
+        .any(|c| c.credential.cred_id() == cred_id)
+    {
+        return Err(AppError::CredentialNotFound);
+    }
+
+    Ok(())
+}
+
+#[debug_handler]
+pub async fn update_credentials_api_handler(
+    Path(cred_id): Path<CredentialID>,
+    session: Session,
+    client: ClientInfo,
+    Extension(app): Extension<SharedAppState>,
+    payload: extract::Json<UpdateCredentialRequestPayload>,
+) -> Result<StatusCode, AppError> {
+    trace!("update_credentials_api_handler");
+
+    let Some(username) = session.get::<String>(SESSIONKEY_USERNAME).await? else {
+        return Err(AppError::BadSession);
+    };
+
+    let result = async {
+        check_owns_credential(&app, &username, &cred_id).await?;
+        app.set_credential_disabled(cred_id.clone(), payload.disabled)
+            .await
+    }
+    .await;
+
+    app.record(
+        AuditEvent::new(
+            if payload.disabled {
+                AuditEventKind::CredentialDisabled
+            } else {
+                AuditEventKind::CredentialEnabled
+            },
+            &client,
+        )
+        .outcome(&result)
+        .username(username)
+        .credential(encode_cred_id(&cred_id)),
+    )
+    .await;
+
+    result?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
 #[debug_handler]
 pub async fn delete_credentials_api_handler(
     Path(cred_id): Path<CredentialID>,
-    shared_state: Extension<SharedAppState>,
+    session: Session,
+    client: ClientInfo,
+    Extension(app): Extension<SharedAppState>,
 ) -> Result<StatusCode, AppError> {
     trace!("delete_credentials_handler");
 
-    let app = shared_state.read().await;
-    app.delete_credential(cred_id).await?;
+    let Some(username) = session.get::<String>(SESSIONKEY_USERNAME).await? else {
+        return Err(AppError::BadSession);
+    };
+
+    let result = async {
+        check_owns_credential(&app, &username, &cred_id).await?;
+        app.delete_credential(cred_id.clone()).await
+    }
+    .await;
+
+    app.record(
+        AuditEvent::new(AuditEventKind::CredentialDeleted, &client)
+            .outcome(&result)
+            .username(username)
+            .credential(encode_cred_id(&cred_id)),
+    )
+    .await;
+
+    result?;
     Ok(StatusCode::NO_CONTENT)
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct UserProfileWithName {
+    username: String,
+    #[serde(flatten)]
+    profile: UserProfile,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GetUsersResponsePayload {
+    data: Vec<UserProfileWithName>,
+}
+
+#[debug_handler]
+pub async fn get_users_admin_api_handler(
+    Extension(app): Extension<SharedAppState>,
+) -> Result<Json<GetUsersResponsePayload>, AppError> {
+    trace!("get_users_admin_api_handler");
+
+    Ok(Json(GetUsersResponsePayload {
+        data: app
+            .list_profiles()
+            .await?
+            .into_iter()
+            .map(|(username, profile)| UserProfileWithName { username, profile })
+            .collect(),
+    }))
+}
+
+#[debug_handler]
+pub async fn update_user_admin_api_handler(
+    Path(username): Path<String>,
+    client: ClientInfo,
+    Extension(app): Extension<SharedAppState>,
+    payload: extract::Json<UserProfile>,
+) -> Result<StatusCode, AppError> {
+    trace!("update_user_admin_api_handler");
+
+    let result = app.update_profile(&username, payload.0).await;
+
+    app.record(
+        AuditEvent::new(AuditEventKind::UserUpdated, &client)
+            .outcome(&result)
+            .username(username),
+    )
+    .await;
+
+    result?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[debug_handler]
+pub async fn revoke_sessions_admin_api_handler(
+    client: ClientInfo,
+    Extension(app): Extension<SharedAppState>,
+) -> Result<StatusCode, AppError> {
+    trace!("revoke_sessions_admin_api_handler");
+
+    let result = app.revoke_sessions().await;
+
+    app.record(AuditEvent::new(AuditEventKind::SessionsRevoked, &client).outcome(&result))
+        .await;
+
+    result?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GetAuditEventsResponsePayload {
+    data: Vec<AuditEvent>,
+    /// The `before` parameter for the next page, if there may be one.
+    next: Option<i64>,
+}
+
+#[debug_handler]
+pub async fn get_audit_events_admin_api_handler(
+    params: Query<AuditLogParams>,
+    Extension(app): Extension<SharedAppState>,
+) -> Result<Json<GetAuditEventsResponsePayload>, AppError> {
+    trace!("get_audit_events_admin_api_handler");
+
+    let query = params.0.into();
+    let events = app.audit_events(&query).await?;
+
+    Ok(Json(GetAuditEventsResponsePayload {
+        next: events
+            .last()
+            .filter(|_| events.len() == query.limit)
+            .map(|event| event.id),
+        data: events,
+    }))
+}
+
 pub async fn root_handler(uri: Uri) -> Response {
     match uri.path() {
         "/" => Redirect::permanent("/credentials").into_response(),
@@ -332,12 +844,30 @@ pub async fn root_handler(uri: Uri) -> Response {
 pub struct Templates {
     pub credentials_template: Template,
     pub authenticate_template: Template,
+    pub admin_users_template: Template,
+    pub admin_user_template: Template,
+    pub admin_events_template: Template,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct CredentialIDWithName {
     id: CredentialID,
     name: String,
+    backup_eligible: bool,
+    backup_state: bool,
+    disabled: bool,
+}
+
+impl From<&CredentialWithName> for CredentialIDWithName {
+    fn from(c: &CredentialWithName) -> Self {
+        Self {
+            id: c.credential.cred_id().to_owned(),
+            name: c.name.clone(),
+            backup_eligible: c.backup_eligible(),
+            backup_state: c.backup_state(),
+            disabled: c.disabled,
+        }
+    }
 }
 
 #[debug_handler]
@@ -345,12 +875,10 @@ pub async fn get_credentials_template_handler(
     LoggedIn(logged_in): LoggedIn,
     session: Session,
     templates: Extension<Arc<Templates>>,
-    shared_state: Extension<SharedAppState>,
+    Extension(app): Extension<SharedAppState>,
 ) -> Result<Response, AppError> {
     trace!("get_credentials_template_handler");
 
-    let app = shared_state.read().await;
-
     if !logged_in {
         return Ok(Redirect::temporary("/authenticate?redirect_url=/credentials").into_response());
     }
@@ -359,20 +887,23 @@ pub async fn get_credentials_template_handler(
         return Err(AppError::BadSession);
     };
 
-    let user = app.get_user_with_credentials(username).await?;
+    let user = app
+        .find_user(&username)
+        .await?
+        .ok_or(AppError::UserNotFound)?;
     let credentials: Vec<CredentialIDWithName> = user
         .credentials
         .iter()
-        .map(|c| {
-            let c = c.clone();
-            CredentialIDWithName {
-                id: c.credential.cred_id().to_owned(),
-                name: c.name,
-            }
-        })
+        .map(CredentialIDWithName::from)
         .collect();
 
-    let tmpl_data = liquid::object!({ "credentials": credentials });
+    let tmpl_data = liquid::object!({
+        "credentials": credentials,
+        "username": user.username,
+        "display_name": user.display_name(),
+        "email": user.profile.email,
+        "groups": user.profile.groups,
+    });
 
     match templates.credentials_template.render(&tmpl_data) {
         Ok(html) => Ok(Html(finish_html(html)).into_response()),
@@ -389,6 +920,7 @@ pub struct GetAuthenticateQueryParams {
 }
 
 #[debug_handler]
+#[allow(clippy::too_many_arguments)]
 pub async fn get_authenticate_template_handler(
     LoggedIn(logged_in): LoggedIn,
     params: Query<GetAuthenticateQueryParams>,
@@ -396,7 +928,9 @@ pub async fn get_authenticate_template_handler(
     session: Session,
     templates: Extension<Arc<Templates>>,
     webauthn: Extension<Arc<Webauthn>>,
-    passwords: Extension<HashMap<String, String>>,
+    passwords: Extension<Reloadable<HashMap<String, String>>>,
+    client: ClientInfo,
+    Extension(app): Extension<SharedAppState>,
 ) -> Result<Response, AppError> {
     trace!("get_authenticate_template_handler");
 
@@ -436,18 +970,28 @@ pub async fn get_authenticate_template_handler(
     };
 
     if passwords
+        .get()
         .get(&username)
         .and_then(|hashed_password| {
             PasswordHash::new(hashed_password)
                 .ok()
                 .and_then(|parsed_hash| {
-                    Argon2::default()
-                        .verify_password(password.as_bytes(), &parsed_hash)
-                        .ok()
+                    let start = Instant::now();
+                    let result =
+                        Argon2::default().verify_password(password.as_bytes(), &parsed_hash);
+                    histogram!("argon2_verification_seconds").record(start.elapsed());
+                    result.ok()
                 })
         })
         .is_none()
     {
+        app.record(
+            AuditEvent::new(AuditEventKind::PasswordCheck, &client)
+                .failed()
+                .username(username),
+        )
+        .await;
+
         return Ok((
             StatusCode::UNAUTHORIZED,
             Html(finish_html(String::from(
@@ -457,6 +1001,15 @@ pub async fn get_authenticate_template_handler(
             .into_response());
     }
 
+    app.record(AuditEvent::new(AuditEventKind::PasswordCheck, &client).username(username.clone()))
+        .await;
+
+    // Users in the password file are created at startup and on reload.
+    let user = app
+        .find_user(&username)
+        .await?
+        .ok_or(AppError::UserNotFound)?;
+
     session
         .insert(SESSIONKEY_USERNAME, username.clone())
         .await?;
@@ -473,7 +1026,11 @@ pub async fn get_authenticate_template_handler(
         }
     }
 
-    let tmpl_data = liquid::object!({ "username": username, "logged_in": logged_in });
+    let tmpl_data = liquid::object!({
+        "username": username,
+        "display_name": user.display_name(),
+        "logged_in": logged_in,
+    });
     match templates.authenticate_template.render(&tmpl_data) {
         Ok(html) => Ok(Html(finish_html(html)).into_response()),
         Err(e) => {
@@ -499,7 +1056,7 @@ const BOTTOM_HTML: &str = r#"
 </html>
 "#;
 
-fn finish_html(page_html: String) -> String {
+pub fn finish_html(page_html: String) -> String {
     format!("{}{}{}", TOP_HTML, page_html, BOTTOM_HTML)
 }
 
@@ -561,4 +1118,27 @@ mod tests {
                 );
             });
     }
+
+    #[test]
+    fn test_host_label() {
+        let policy = CredentialPolicy {
+            hosts: HashMap::from([("secure.foo.com".to_string(), Default::default())]),
+            ..Default::default()
+        };
+
+        assert_eq!(host_label(None, &policy, "foo.com"), "");
+        assert_eq!(
+            host_label(Some("Secure.foo.com"), &policy, "foo.com"),
+            "secure.foo.com"
+        );
+        assert_eq!(
+            host_label(Some("app.foo.com"), &policy, "foo.com"),
+            "foo.com"
+        );
+        assert_eq!(host_label(Some("foo.com"), &policy, "foo.com"), "foo.com");
+        assert_eq!(
+            host_label(Some("evil.example"), &policy, "foo.com"),
+            "other"
+        );
+    }
 }
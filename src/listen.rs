@@ -0,0 +1,258 @@
+//! The sockets the server listens on: a TCP address, a Unix socket path, or a socket passed by
+//! systemd socket activation, optionally with TLS on top.
+
+use crate::{config::Reloadable, tls::TlsListener};
+use axum::{extract::connect_info::Connected, serve::IncomingStream, Router};
+use rustls::ServerConfig;
+use std::{
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    os::{
+        fd::{FromRawFd, RawFd},
+        unix::fs::{FileTypeExt, PermissionsExt},
+    },
+    path::Path,
+};
+use tokio::net::{TcpListener, UnixListener};
+
+/// The first file descriptor passed by systemd, see sd_listen_fds(3).
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// The peer of a connection. Peers connected through a Unix socket are on the same host.
+#[derive(Clone, Copy, Debug)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    Tls(SocketAddr),
+    Unix,
+}
+
+impl PeerAddr {
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            PeerAddr::Tcp(addr) | PeerAddr::Tls(addr) => Some(addr.ip().to_canonical()),
+            PeerAddr::Unix => None,
+        }
+    }
+
+    pub fn is_local(&self) -> bool {
+        match self {
+            PeerAddr::Tcp(addr) | PeerAddr::Tls(addr) => addr.ip().to_canonical().is_loopback(),
+            PeerAddr::Unix => true,
+        }
+    }
+
+    /// Whether the peer is a reverse proxy whose X-Forwarded-For header can be trusted, i.e. one
+    /// on the same host. Connections over TLS never come from the proxy, which terminates TLS
+    /// itself, so they are taken to be from clients.
+    pub fn is_trusted_proxy(&self) -> bool {
+        match self {
+            PeerAddr::Tls(_) => false,
+            peer => peer.is_local(),
+        }
+    }
+}
+
+impl Connected<IncomingStream<'_, TcpListener>> for PeerAddr {
+    fn connect_info(stream: IncomingStream<'_, TcpListener>) -> Self {
+        PeerAddr::Tcp(*stream.remote_addr())
+    }
+}
+
+impl Connected<IncomingStream<'_, TlsListener>> for PeerAddr {
+    fn connect_info(stream: IncomingStream<'_, TlsListener>) -> Self {
+        PeerAddr::Tls(*stream.remote_addr())
+    }
+}
+
+impl Connected<IncomingStream<'_, UnixListener>> for PeerAddr {
+    fn connect_info(_stream: IncomingStream<'_, UnixListener>) -> Self {
+        PeerAddr::Unix
+    }
+}
+
+/// Reads and removes the socket activation variables, returning the number of sockets systemd
+/// passed to this process. The variables are not meant for child processes. Since changing the
+/// environment is not thread-safe, this must be called before any threads are started.
+pub fn take_systemd_fds() -> Option<String> {
+    let for_this_process = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        == Some(std::process::id());
+    let n_fds = std::env::var("LISTEN_FDS").ok();
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    n_fds.filter(|_| for_this_process)
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Tls(TlsListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    pub async fn bind_tcp(address: SocketAddr) -> anyhow::Result<Self> {
+        Ok(Listener::Tcp(TcpListener::bind(address).await?))
+    }
+
+    /// Binds a Unix socket at the path with the given permissions, replacing a socket left behind
+    /// by a previous run.
+    pub fn bind_unix(path: &Path, mode: u32) -> anyhow::Result<Self> {
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+            Ok(_) => anyhow::bail!("{} exists and is not a socket", path.display()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => anyhow::bail!("{}: {err}", path.display()),
+        }
+
+        let listener = UnixListener::bind(path)
+            .map_err(|err| anyhow::anyhow!("binding {}: {err}", path.display()))?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+
+        Ok(Listener::Unix(listener))
+    }
+
+    /// Takes the socket passed by systemd socket activation, given the number of sockets read by
+    /// [`take_systemd_fds`].
+    pub fn from_systemd(n_fds: Option<&str>) -> anyhow::Result<Option<Self>> {
+        match n_fds {
+            None | Some("0") => return Ok(None),
+            Some("1") => {}
+            Some(n_fds) => anyhow::bail!("expected a single socket from systemd, got {n_fds}"),
+        }
+
+        let fd = SD_LISTEN_FDS_START;
+        // SAFETY: sockaddr_storage is a plain C struct that getsockname fills in.
+        let mut addr = unsafe { std::mem::zeroed::<libc::sockaddr_storage>() };
+        let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        if unsafe { libc::getsockname(fd, std::ptr::addr_of_mut!(addr).cast(), &mut len) } != 0 {
+            anyhow::bail!(
+                "socket passed by systemd: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+
+        // SAFETY: systemd passes ownership of the descriptor, which is only taken once since the
+        // variables were removed by take_systemd_fds.
+        let listener = match addr.ss_family as libc::c_int {
+            libc::AF_UNIX => {
+                let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+                listener.set_nonblocking(true)?;
+                Listener::Unix(UnixListener::from_std(listener)?)
+            }
+            libc::AF_INET | libc::AF_INET6 => {
+                let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+                listener.set_nonblocking(true)?;
+                Listener::Tcp(TcpListener::from_std(listener)?)
+            }
+            family => anyhow::bail!("socket passed by systemd has unsupported family {family}"),
+        };
+
+        Ok(Some(listener))
+    }
+
+    /// Serves HTTPS with the configuration instead of plain HTTP.
+    pub fn with_tls(self, config: Reloadable<ServerConfig>) -> anyhow::Result<Self> {
+        match self {
+            Listener::Tcp(listener) => Ok(Listener::Tls(TlsListener::new(listener, config)?)),
+            Listener::Tls(_) => Ok(self),
+            Listener::Unix(_) => anyhow::bail!("TLS is not supported on Unix sockets"),
+        }
+    }
+
+    /// The port of a TCP socket.
+    pub fn port(&self) -> Option<u16> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().ok().map(|addr| addr.port()),
+            Listener::Tls(listener) => axum::serve::Listener::local_addr(listener)
+                .ok()
+                .map(|addr| addr.port()),
+            Listener::Unix(_) => None,
+        }
+    }
+
+    /// Serves the router until the listener fails, or until `shutdown` completes and the open
+    /// connections are closed.
+    pub async fn serve(
+        self,
+        router: Router,
+        shutdown: impl Future<Output = ()> + Send + 'static,
+    ) -> std::io::Result<()> {
+        let make_service = router.into_make_service_with_connect_info::<PeerAddr>();
+        match self {
+            Listener::Tcp(listener) => {
+                axum::serve(listener, make_service)
+                    .with_graceful_shutdown(shutdown)
+                    .await
+            }
+            Listener::Tls(listener) => {
+                axum::serve(listener, make_service)
+                    .with_graceful_shutdown(shutdown)
+                    .await
+            }
+            Listener::Unix(listener) => {
+                axum::serve(listener, make_service)
+                    .with_graceful_shutdown(shutdown)
+                    .await
+            }
+        }
+    }
+
+    pub fn local_addr(&self) -> String {
+        match self {
+            Listener::Tcp(listener) => listener
+                .local_addr()
+                .map(|addr| addr.to_string())
+                .unwrap_or_default(),
+            Listener::Tls(listener) => axum::serve::Listener::local_addr(listener)
+                .map(|addr| format!("{addr} (TLS)"))
+                .unwrap_or_default(),
+            Listener::Unix(listener) => listener
+                .local_addr()
+                .ok()
+                .and_then(|addr| addr.as_pathname().map(|path| path.display().to_string()))
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Parses a file mode given in octal, like `660`.
+pub fn parse_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or_else(|| format!("{mode} is not an octal file mode"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(parse_mode("660"), Ok(0o660));
+        assert_eq!(parse_mode("0600"), Ok(0o600));
+        assert!(parse_mode("1777").is_err());
+        assert!(parse_mode("680").is_err());
+        assert!(parse_mode("").is_err());
+    }
+
+    #[test]
+    fn test_peer_addr_is_local() {
+        assert!(PeerAddr::Unix.is_local());
+        assert!(PeerAddr::Tcp("[::1]:1234".parse().unwrap()).is_local());
+        assert!(PeerAddr::Tcp("[::ffff:127.0.0.1]:1234".parse().unwrap()).is_local());
+        assert!(!PeerAddr::Tcp("192.0.2.1:1234".parse().unwrap()).is_local());
+    }
+
+    #[test]
+    fn test_peer_addr_is_trusted_proxy() {
+        assert!(PeerAddr::Unix.is_trusted_proxy());
+        assert!(PeerAddr::Tcp("127.0.0.1:1234".parse().unwrap()).is_trusted_proxy());
+        assert!(!PeerAddr::Tcp("192.0.2.1:1234".parse().unwrap()).is_trusted_proxy());
+        assert!(!PeerAddr::Tls("127.0.0.1:1234".parse().unwrap()).is_trusted_proxy());
+        assert!(PeerAddr::Tls("127.0.0.1:1234".parse().unwrap()).is_local());
+    }
+}
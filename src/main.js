@@ -17,6 +17,46 @@ document.addEventListener("DOMContentLoaded", () => {
       }
     });
   }
+  for (const button of document.getElementsByClassName("toggle-credential")) {
+    button.addEventListener("click", async function (_) {
+      const cred_id = button.getAttribute("value");
+      if (!cred_id) return;
+      const disabled = button.getAttribute("data-disabled") !== "true";
+      const response = await fetch(`/api/credentials/${cred_id}`, {
+        method: "PATCH",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify({ disabled }),
+      });
+      if (!response.ok) return window.alert("Failed to update credential");
+      else if (response.status === 204) return location.reload();
+    });
+  }
+  for (const button of document.getElementsByClassName("admin-action")) {
+    button.addEventListener("click", async function (_) {
+      if (!window.confirm(button.getAttribute("data-confirm"))) return;
+      // Every admin action is confirmed with a fresh passkey assertion.
+      const startResponse = await fetch("/api/console/assertion", {
+        method: "GET",
+      });
+      if (!startResponse.ok) {
+        return window.alert("Failed to start passkey confirmation");
+      }
+      const response = await fetch("/api/console/actions", {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify({
+          action: button.getAttribute("data-action"),
+          username: button.getAttribute("data-username"),
+          credential: button.getAttribute("data-credential") ?? undefined,
+          assertion: await get(
+            parseRequestOptionsFromJSON(await startResponse.json()),
+          ),
+        }),
+      });
+      if (!response.ok) return window.alert("Admin action failed");
+      else if (response.status === 204) return location.reload();
+    });
+  }
   const addButton = document.getElementById("add-credential");
   if (addButton != null) {
     addButton.addEventListener("click", async function (_) {
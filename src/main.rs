@@ -1,34 +1,348 @@
+mod admin;
 mod app;
+mod audit;
+mod config;
+mod console;
 mod handlers;
+mod listen;
+mod password;
+mod policy;
+mod relying_party;
 mod session;
+mod storage;
+mod systemd;
+mod tls;
 
+use admin::OutputFormat;
 use app::App;
+use audit::AuditSigningKey;
 use axum::{
     middleware,
-    routing::{delete, get},
+    routing::{delete, get, patch, post, put},
     Extension, Router,
 };
-use clap::Parser;
+use clap::{ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand};
+use config::{ConfigFile, Reloadable};
+use console::{
+    action_console_handler, assertion_start_console_handler, get_events_console_handler,
+    get_user_console_handler, get_users_console_handler, require_admin,
+};
 use handlers::{
     allow_only_localhost, authenticate_end_handler, authenticate_start_handler,
-    delete_credentials_api_handler, get_authenticate_template_handler,
-    get_credentials_template_handler, register_end_handler, register_start_handler,
-    require_logged_in, root_handler, Templates,
+    delete_credentials_api_handler, get_audit_events_admin_api_handler,
+    get_authenticate_template_handler, get_credentials_api_handler,
+    get_credentials_template_handler, get_users_admin_api_handler, record_request_duration,
+    register_end_handler, register_start_handler, require_logged_in,
+    revoke_sessions_admin_api_handler, root_handler, update_credentials_api_handler,
+    update_user_admin_api_handler, validate_handler, Templates,
+};
+use listen::Listener;
+use metrics::{counter, describe_counter, describe_gauge, describe_histogram, gauge, Unit};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use policy::{AdminRole, CounterRegressionAction, CredentialPolicy, HostPolicy};
+use relying_party::{CookieDomain, RelyingPartyConfig};
+use session::SessionCache;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    env,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+#[cfg(feature = "sqlite")]
+use storage::sqlite::backup;
+use storage::{
+    encrypted::{self, EncryptedStorage, Keyring, StorageKey, ValueStats},
+    DEFAULT_RELYING_PARTY,
 };
-use metrics::counter;
-use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
-use std::{collections::HashMap, env, net::SocketAddr, path::PathBuf, sync::Arc};
-use tokio::sync::RwLock;
-use tokio_rusqlite::Connection;
+use tokio::signal::unix::{signal, SignalKind};
 use tower_http::trace::TraceLayer;
 use tower_sessions::{cookie::Key, SessionManagerLayer};
-use tracing::debug;
+use tracing::{debug, error, info, warn};
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
-use webauthn_rs::{prelude::Url, WebauthnBuilder};
+
+#[derive(Args)]
+struct StorageArgs {
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Directory to store program state",
+        default_value = "/var/lib/webauthn-tiny"
+    )]
+    state_directory: PathBuf,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Database URL (sqlite:<path>, postgres://..., memory:), defaults to a SQLite database in the state directory"
+    )]
+    database_url: Option<String>,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "File with a 256-bit key (64 hex digits) to encrypt stored credentials and sessions with"
+    )]
+    storage_key_file: Option<PathBuf>,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        requires = "storage_key_file",
+        help = "Previous storage key file, values encrypted with it are re-encrypted with the current key"
+    )]
+    previous_storage_key_file: Vec<PathBuf>,
+}
+
+impl StorageArgs {
+    fn keyring(&self) -> anyhow::Result<Option<Keyring>> {
+        let Some(path) = &self.storage_key_file else {
+            return Ok(None);
+        };
+
+        Ok(Some(Keyring::new(
+            StorageKey::load(path)?,
+            self.previous_storage_key_file
+                .iter()
+                .map(|path| StorageKey::load(path))
+                .collect::<anyhow::Result<_>>()?,
+        )))
+    }
+
+    /// Connects to the storage and checks that all stored values can be decrypted. Returns the
+    /// unencrypted storage, the keyring if encryption is enabled and how the values are stored.
+    async fn connect_unencrypted(
+        &self,
+    ) -> anyhow::Result<(Arc<dyn storage::Storage>, Option<Keyring>, ValueStats)> {
+        let storage = storage::connect(self.database_url.as_deref(), &self.state_directory).await?;
+        let keyring = self.keyring()?;
+        let stats = encrypted::check_keys(storage.as_ref(), keyring.as_ref()).await?;
+
+        Ok((storage, keyring, stats))
+    }
+
+    async fn connect(&self) -> anyhow::Result<Arc<dyn storage::Storage>> {
+        Ok(match self.connect_unencrypted().await? {
+            (storage, Some(keyring), _) => Arc::new(EncryptedStorage::new(storage, keyring).await?),
+            (storage, None, _) => storage,
+        })
+    }
+
+    #[cfg(feature = "sqlite")]
+    fn sqlite_path(&self) -> anyhow::Result<PathBuf> {
+        storage::sqlite_path(self.database_url.as_deref(), &self.state_directory).ok_or_else(|| {
+            anyhow::anyhow!("backups are only supported for SQLite databases, use the database's own tools instead")
+        })
+    }
+
+    fn audit_signing_key_path(&self) -> PathBuf {
+        self.state_directory.join(AuditSigningKey::FILE_NAME)
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the server, the default without a command
+    Serve(ServeArgs),
+    /// Check the options for serving and report every problem found, without starting the server
+    CheckConfig(ServeArgs),
+    /// Check the audit log's hash chain and signed checkpoints, reporting the first broken link
+    VerifyAuditLog {
+        #[clap(flatten)]
+        storage: StorageArgs,
+    },
+    /// Write a consistent copy of the SQLite database to a file, safe to run while serving
+    #[cfg(feature = "sqlite")]
+    Backup {
+        #[clap(flatten)]
+        storage: StorageArgs,
+        #[clap(value_parser, help = "File to write the backup to")]
+        target: PathBuf,
+    },
+    /// Replace the SQLite database with a backup after checking its integrity and schema version;
+    /// the service must be stopped
+    #[cfg(feature = "sqlite")]
+    Restore {
+        #[clap(flatten)]
+        storage: StorageArgs,
+        #[clap(value_parser, help = "Backup file to restore")]
+        backup: PathBuf,
+    },
+    /// Manage users
+    #[clap(subcommand)]
+    Users(UsersCommand),
+    /// Manage credentials
+    #[clap(subcommand)]
+    Credentials(CredentialsCommand),
+    /// Manage sessions
+    #[clap(subcommand)]
+    Sessions(SessionsCommand),
+    /// Hash a password with Argon2id and print a line for the password file
+    ///
+    /// The password is prompted for without echo, or read from the first line of stdin when it is
+    /// not a terminal.
+    HashPassword {
+        #[clap(value_parser, help = "Username")]
+        username: String,
+        #[clap(
+            long,
+            value_parser,
+            help = "Add the line to this password file, replacing the user's existing line, instead of printing it"
+        )]
+        password_file: Option<PathBuf>,
+        #[clap(
+            long,
+            value_parser,
+            help = "Argon2 memory cost in KiB",
+            default_value_t = argon2::Params::DEFAULT_M_COST
+        )]
+        memory_cost: u32,
+        #[clap(
+            long,
+            value_parser,
+            help = "Argon2 time cost (number of iterations)",
+            default_value_t = argon2::Params::DEFAULT_T_COST
+        )]
+        time_cost: u32,
+        #[clap(
+            long,
+            value_parser,
+            help = "Argon2 degree of parallelism",
+            default_value_t = argon2::Params::DEFAULT_P_COST
+        )]
+        parallelism: u32,
+    },
+}
+
+impl Command {
+    fn storage_mut(&mut self) -> Option<&mut StorageArgs> {
+        match self {
+            Command::Serve(args) | Command::CheckConfig(args) => Some(&mut args.storage),
+            Command::VerifyAuditLog { storage } => Some(storage),
+            #[cfg(feature = "sqlite")]
+            Command::Backup { storage, .. } | Command::Restore { storage, .. } => Some(storage),
+            Command::Users(UsersCommand::List { storage, .. })
+            | Command::Users(UsersCommand::Delete { storage, .. })
+            | Command::Credentials(CredentialsCommand::List { storage, .. })
+            | Command::Credentials(CredentialsCommand::Delete { storage, .. })
+            | Command::Credentials(CredentialsCommand::Rename { storage, .. })
+            | Command::Sessions(SessionsCommand::Revoke { storage, .. }) => Some(storage),
+            Command::HashPassword { .. } => None,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum UsersCommand {
+    /// List users with their profiles and number of credentials
+    List {
+        #[clap(flatten)]
+        storage: StorageArgs,
+        #[clap(long, value_enum, help = "Output format", default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+    /// Delete a user together with their credentials and sessions
+    Delete {
+        #[clap(flatten)]
+        storage: StorageArgs,
+        #[clap(value_parser, help = "Username")]
+        username: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum CredentialsCommand {
+    /// List the credentials of a user
+    List {
+        #[clap(flatten)]
+        storage: StorageArgs,
+        #[clap(long, value_parser, help = "Username")]
+        user: String,
+        #[clap(
+            long,
+            value_parser,
+            help = "Relying party whose credentials to list",
+            default_value = DEFAULT_RELYING_PARTY
+        )]
+        relying_party: String,
+        #[clap(long, value_enum, help = "Output format", default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+    /// Delete a credential
+    Delete {
+        #[clap(flatten)]
+        storage: StorageArgs,
+        #[clap(value_parser, help = "Credential ID")]
+        cred_id: String,
+        #[clap(
+            long,
+            value_parser,
+            help = "Relying party the credential was registered with",
+            default_value = DEFAULT_RELYING_PARTY
+        )]
+        relying_party: String,
+    },
+    /// Rename a credential
+    Rename {
+        #[clap(flatten)]
+        storage: StorageArgs,
+        #[clap(value_parser, help = "Credential ID")]
+        cred_id: String,
+        #[clap(value_parser, help = "New name")]
+        name: String,
+        #[clap(
+            long,
+            value_parser,
+            help = "Relying party the credential was registered with",
+            default_value = DEFAULT_RELYING_PARTY
+        )]
+        relying_party: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum SessionsCommand {
+    /// Log users out by deleting their sessions
+    Revoke {
+        #[clap(flatten)]
+        storage: StorageArgs,
+        #[clap(
+            long,
+            value_parser,
+            help = "Only revoke the sessions of this user instead of all sessions"
+        )]
+        user: Option<String>,
+        #[clap(
+            long,
+            value_parser,
+            help = "Relying party whose sessions to revoke",
+            default_value = DEFAULT_RELYING_PARTY
+        )]
+        relying_party: String,
+    },
+}
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)] // Read from `Cargo.toml`
 struct Cli {
+    #[clap(
+        env,
+        long,
+        global = true,
+        value_parser,
+        help = "TOML configuration file, flags and environment variables take precedence over it"
+    )]
+    config: Option<PathBuf>,
+    #[clap(subcommand)]
+    command: Option<Command>,
+    #[clap(flatten)]
+    serve: ServeArgs,
+}
+
+#[derive(Args)]
+struct ServeArgs {
     #[clap(
         env,
         long,
@@ -37,77 +351,628 @@ struct Cli {
         default_value = "[::]:8080"
     )]
     address: SocketAddr,
-    #[clap(env, long, value_parser, help = "Relying Party ID")]
-    rp_id: String,
-    #[clap(env, long, value_parser, help = "Relying Party origin")]
-    rp_origin: String,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Unix socket to listen on instead of the address"
+    )]
+    unix_socket: Option<PathBuf>,
+    #[clap(
+        env,
+        long,
+        value_parser = listen::parse_mode,
+        help = "Permissions of the Unix socket, in octal",
+        default_value = "660"
+    )]
+    unix_socket_mode: u32,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "TLS certificate chain file (PEM) to serve HTTPS with, reloaded when it changes"
+    )]
+    tls_cert: Option<PathBuf>,
+    #[clap(env, long, value_parser, help = "TLS private key file (PEM)")]
+    tls_key: Option<PathBuf>,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Address to redirect plain HTTP requests to HTTPS on"
+    )]
+    tls_redirect_address: Option<SocketAddr>,
+    // Options required for serving are optional types since they may be given in the config file,
+    // `config::check` reports them if they are missing.
+    #[clap(env, long, value_parser, help = "Relying Party ID (required)")]
+    rp_id: Option<String>,
+    #[clap(env, long, value_parser, help = "Relying Party origin (required)")]
+    rp_origin: Option<String>,
     #[clap(env, long, value_parser, help = "Extra allowed origin")]
     extra_allowed_origin: Vec<String>,
-    #[clap(env, long, value_parser, help = "Session secret file")]
-    session_secret_file: PathBuf,
-    #[clap(env, long, value_parser, help = "Password file")]
-    password_file: PathBuf,
     #[clap(
         env,
         long,
         value_parser,
-        help = "Directory to store program state",
-        default_value = "/var/lib/webauthn-tiny"
+        help = "Domain of the session cookie, defaults to the Relying Party ID"
     )]
-    state_directory: PathBuf,
+    cookie_domain: Option<String>,
+    #[clap(env, long, value_parser, help = "Session secret file (required)")]
+    session_secret_file: Option<PathBuf>,
+    #[clap(env, long, value_parser, help = "Password file (required)")]
+    password_file: Option<PathBuf>,
+    #[clap(flatten)]
+    storage: StorageArgs,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Reject registration of backup-eligible (synced) credentials"
+    )]
+    reject_backup_eligible: bool,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Protected host that requires a device-bound credential"
+    )]
+    device_bound_host: Vec<String>,
+    #[clap(
+        env,
+        long,
+        value_enum,
+        help = "Action taken when a credential's signature counter regresses (possible cloned authenticator)",
+        default_value_t = CounterRegressionAction::Log
+    )]
+    counter_regression_action: CounterRegressionAction,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "User allowed to use the admin console"
+    )]
+    admin_user: Vec<String>,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Group whose users are allowed to use the admin console"
+    )]
+    admin_group: Vec<String>,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Delete users, and their credentials, that are no longer in the password file"
+    )]
+    purge_removed_users: bool,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Seconds between signed checkpoints of the audit log",
+        default_value_t = 3600
+    )]
+    audit_checkpoint_interval: u64,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Seconds between refreshes of the session, user and credential gauges from the database",
+        default_value_t = 60
+    )]
+    metrics_refresh_interval: u64,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Seconds sessions are cached in memory, bounding how long a session deleted by another instance stays valid (0 disables the cache)",
+        default_value_t = 60
+    )]
+    session_cache_ttl: u64,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Maximum number of sessions cached in memory",
+        default_value_t = 10000
+    )]
+    session_cache_capacity: usize,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Seconds to wait for open connections to finish on SIGTERM or SIGINT before exiting",
+        default_value_t = 30
+    )]
+    shutdown_timeout: u64,
+    #[cfg(feature = "sqlite")]
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Directory to periodically back the SQLite database up to"
+    )]
+    backup_directory: Option<PathBuf>,
+    #[cfg(feature = "sqlite")]
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Seconds between scheduled backups",
+        default_value_t = 86400
+    )]
+    backup_interval: u64,
+    #[cfg(feature = "sqlite")]
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Number of scheduled backups to keep",
+        default_value_t = 7
+    )]
+    backup_retention: usize,
+    /// Per-host policies, only configurable in the config file.
+    #[clap(skip)]
+    hosts: HashMap<String, HostPolicy>,
+    /// Relying parties in addition to the default one, only configurable in the config file.
+    #[clap(skip)]
+    relying_parties: BTreeMap<String, RelyingPartyConfig>,
 }
 
-fn read_password_file(filepath: PathBuf) -> anyhow::Result<HashMap<String, String>> {
-    Ok(std::fs::read_to_string(filepath)?
-        .lines()
-        .fold(HashMap::new(), |mut acc, cur| {
-            if let Some((username, hash)) = cur.split_once(':') {
-                acc.insert(String::from(username), String::from(hash));
-            }
-            acc
-        }))
+impl ServeArgs {
+    /// Parses the options from the command line and the environment, filling in the rest from
+    /// the config file.
+    fn parse(matches: &ArgMatches, config: Option<&Path>) -> anyhow::Result<Self> {
+        let mut args = Self::from_arg_matches(matches)?;
+        ConfigFile::load(config)?.apply_serve(&mut args, matches);
+
+        Ok(args)
+    }
+
+    /// All relying parties, starting with the default one configured by the flags. Must only be
+    /// called once `config::check` found no problems.
+    fn relying_parties(&self) -> Vec<(String, RelyingPartyConfig)> {
+        let default = RelyingPartyConfig {
+            rp_id: self.rp_id.clone().unwrap_or_default(),
+            rp_origin: self.rp_origin.clone().unwrap_or_default(),
+            extra_allowed_origin: self.extra_allowed_origin.clone(),
+            cookie_domain: self.cookie_domain.clone(),
+            password_file: self.password_file.clone(),
+        };
+
+        std::iter::once((DEFAULT_RELYING_PARTY.to_string(), default))
+            .chain(self.relying_parties.iter().map(|(name, rp)| {
+                let mut rp = rp.clone();
+                rp.password_file = rp.password_file.or_else(|| self.password_file.clone());
+                (name.clone(), rp)
+            }))
+            .collect()
+    }
+
+    fn credential_policy(&self) -> CredentialPolicy {
+        let mut hosts = self.hosts.clone();
+        for host in &self.device_bound_host {
+            hosts
+                .entry(host.to_ascii_lowercase())
+                .or_default()
+                .device_bound = true;
+        }
+
+        CredentialPolicy {
+            reject_backup_eligible: self.reject_backup_eligible,
+            hosts,
+            counter_regression_action: self.counter_regression_action,
+            admins: AdminRole {
+                users: self.admin_user.clone(),
+                groups: self.admin_group.clone(),
+            },
+        }
+    }
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
+fn hash_password(
+    username: &str,
+    password_file: Option<&std::path::Path>,
+    params: argon2::Params,
+) -> anyhow::Result<()> {
+    if username.is_empty() || username.contains([':', '\n', '\r']) {
+        anyhow::bail!("username must not be empty or contain colons or line breaks");
+    }
+
+    let hash = password::hash_password(&password::read_password()?, params)?;
+
+    match password_file {
+        None => println!("{username}:{hash}"),
+        Some(path) => {
+            let action = if password::update_password_file(path, username, &hash)? {
+                "replaced"
+            } else {
+                "added"
+            };
+            eprintln!(
+                "{action} {username} in {}, reload or restart the service to apply",
+                path.display()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+async fn verify_audit_log(storage_args: StorageArgs) -> anyhow::Result<()> {
+    let key = AuditSigningKey::load(&storage_args.audit_signing_key_path())?;
+    let app = App::new(storage_args.connect().await?);
+
+    let report = app.verify_audit_log(&key).await?;
+
+    if report.unchained_events > 0 {
+        println!(
+            "{} events were recorded before the audit log was chained and cannot be verified",
+            report.unchained_events
+        );
+    }
+    println!(
+        "checked {} chained events against {} checkpoints",
+        report.chained_events, report.checkpoints
+    );
+    if let (Some(last_event), Some(last_checkpointed_event)) =
+        (report.last_event, report.last_checkpointed_event)
+    {
+        if last_event > last_checkpointed_event {
+            println!(
+                "events after {last_checkpointed_event} are not covered by a checkpoint yet, their removal would not be detected"
+            );
+        }
+    }
+
+    match report.first_break {
+        Some(chain_break) => anyhow::bail!("audit log verification failed: {chain_break}"),
+        None => Ok(()),
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    // Read before the runtime starts its worker threads, see take_systemd_fds.
+    let systemd_fds = listen::take_systemd_fds();
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(systemd_fds))
+}
+
+async fn run(systemd_fds: Option<String>) -> anyhow::Result<()> {
     tracing_subscriber::registry()
         .with(fmt::layer())
         .with(EnvFilter::from_env("WEBAUTHN_TINY_LOG"))
         .init();
 
-    let prometheus_handle = PrometheusBuilder::new().install_recorder()?;
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    // The options of the innermost subcommand, which are overridden by the config file.
+    let mut command_matches = &matches;
+    while let Some((_, matches)) = command_matches.subcommand() {
+        command_matches = matches;
+    }
+
+    let mut command = cli.command.unwrap_or(Command::Serve(cli.serve));
+    match &mut command {
+        Command::Serve(args) | Command::CheckConfig(args) => {
+            *args = ServeArgs::parse(command_matches, cli.config.as_deref())?;
+        }
+        command => {
+            if let Some(storage) = command.storage_mut() {
+                ConfigFile::load(cli.config.as_deref())?.apply_storage(storage, command_matches);
+            }
+        }
+    }
+
+    match command {
+        Command::Serve(args) => serve(args, command_matches.clone(), cli.config, systemd_fds).await,
+        Command::CheckConfig(args) => {
+            let problems = config::check(&args);
+            if !problems.is_empty() {
+                for problem in &problems {
+                    eprintln!("error: {problem}");
+                }
+                anyhow::bail!("the configuration is invalid");
+            }
+            println!("configuration is valid");
+            Ok(())
+        }
+        Command::VerifyAuditLog { storage } => verify_audit_log(storage).await,
+        #[cfg(feature = "sqlite")]
+        Command::Backup { storage, target } => {
+            backup::backup(&storage.sqlite_path()?, &target).await
+        }
+        #[cfg(feature = "sqlite")]
+        Command::Restore { storage, backup } => {
+            let database = storage.sqlite_path()?;
+            let version = backup::restore(&backup, &database).await?;
+            println!(
+                "restored {} (schema version {version}), the replaced database was kept at {}",
+                backup.display(),
+                backup::replaced_path(&database).display()
+            );
+            Ok(())
+        }
+        Command::Users(UsersCommand::List { storage, format }) => {
+            admin::list_users(&App::new(storage.connect().await?), format).await
+        }
+        Command::Users(UsersCommand::Delete { storage, username }) => {
+            admin::delete_user(&App::new(storage.connect().await?), &username).await
+        }
+        Command::Credentials(CredentialsCommand::List {
+            storage,
+            user,
+            relying_party,
+            format,
+        }) => {
+            let app = App::new(storage.connect().await?).for_relying_party(&relying_party);
+            admin::list_credentials(&app, &user, format).await
+        }
+        Command::Credentials(CredentialsCommand::Delete {
+            storage,
+            cred_id,
+            relying_party,
+        }) => {
+            let app = App::new(storage.connect().await?).for_relying_party(&relying_party);
+            admin::delete_credential(&app, &cred_id).await
+        }
+        Command::Credentials(CredentialsCommand::Rename {
+            storage,
+            cred_id,
+            name,
+            relying_party,
+        }) => {
+            let app = App::new(storage.connect().await?).for_relying_party(&relying_party);
+            admin::rename_credential(&app, &cred_id, &name).await
+        }
+        Command::Sessions(SessionsCommand::Revoke {
+            storage,
+            user,
+            relying_party,
+        }) => {
+            let app = App::new(storage.connect().await?).for_relying_party(&relying_party);
+            admin::revoke_sessions(&app, user.as_deref()).await
+        }
+        Command::HashPassword {
+            username,
+            password_file,
+            memory_cost,
+            time_cost,
+            parallelism,
+        } => hash_password(
+            &username,
+            password_file.as_deref(),
+            argon2::Params::new(memory_cost, time_cost, parallelism, None)
+                .map_err(|err| anyhow::anyhow!("invalid Argon2 parameters: {err}"))?,
+        ),
+    }
+}
+
+/// Reads the password file of every relying party, in the same order.
+fn read_password_files(
+    relying_parties: &[(String, RelyingPartyConfig)],
+) -> anyhow::Result<Vec<HashMap<String, String>>> {
+    relying_parties
+        .iter()
+        .map(|(_, rp)| match &rp.password_file {
+            Some(path) => password::read_password_file(path),
+            None => unreachable!("checked by config::check"),
+        })
+        .collect()
+}
 
-    counter!("successful_registrations").absolute(0);
-    counter!("failed_registrations").absolute(0);
-    counter!("successful_authentications").absolute(0);
-    counter!("failed_authentications").absolute(0);
-    counter!("authorized_requests").absolute(0);
-    counter!("unauthorized_requests").absolute(0);
+/// The users of all relying parties. Users with the same name in several password files are the
+/// same user, sharing their profile.
+fn all_usernames(passwords: &[HashMap<String, String>]) -> Vec<&str> {
+    passwords
+        .iter()
+        .flat_map(HashMap::keys)
+        .map(String::as_str)
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
 
-    let cli = Cli::parse();
-    let origin_url = Url::parse(&cli.rp_origin)?;
-    let mut builder = WebauthnBuilder::new(&cli.rp_id, &origin_url)?.allow_subdomains(true);
-    for url in cli.extra_allowed_origin {
-        builder = builder.append_allowed_origin(&Url::parse(&url)?);
+/// Reloads the password files and the credential and host policies, the other options only take
+/// effect on restart. The configuration is checked first and kept unchanged if it has problems.
+async fn reload(
+    app: &App,
+    matches: &ArgMatches,
+    config: Option<&Path>,
+    passwords: &[(String, Reloadable<HashMap<String, String>>)],
+    policy: &Reloadable<CredentialPolicy>,
+    tls_config: Option<&Reloadable<rustls::ServerConfig>>,
+) -> anyhow::Result<()> {
+    let args = ServeArgs::parse(matches, config)?;
+    let problems = config::check(&args);
+    if !problems.is_empty() {
+        anyhow::bail!("{}", problems.join("; "));
     }
-    let webauthn = builder.build()?;
 
-    let mut db_path = cli.state_directory;
-    db_path.push("webauthn-tiny.db");
-    let db = Connection::open(db_path).await?;
+    let relying_parties = args.relying_parties();
+    if relying_parties
+        .iter()
+        .map(|(name, _)| name)
+        .ne(passwords.iter().map(|(name, _)| name))
+    {
+        warn!("adding or removing relying parties only takes effect on restart");
+    }
 
-    let store = session::SqliteSessionStore::new(db.clone());
-    store.init().await?;
+    let new_passwords = read_password_files(&relying_parties)?;
+    app.sync_users(&all_usernames(&new_passwords), args.purge_removed_users)
+        .await?;
+    for (name, passwords) in passwords {
+        if let Some(index) = relying_parties.iter().position(|(n, _)| n == name) {
+            passwords.set(new_passwords[index].clone());
+        }
+    }
+    policy.set(args.credential_policy());
+    if let (Some(tls_config), Some(cert), Some(key)) = (tls_config, &args.tls_cert, &args.tls_key) {
+        tls_config.set(tls::load_config(cert, key)?);
+    }
 
-    let session_layer = SessionManagerLayer::new(store)
-        .with_private(Key::try_from(
-            std::fs::read_to_string(cli.session_secret_file)?.as_bytes(),
-        )?)
-        .with_always_save(false)
-        .with_domain(cli.rp_id);
+    Ok(())
+}
 
-    let app = App::new(db);
-    app.init().await?;
+async fn serve(
+    args: ServeArgs,
+    matches: ArgMatches,
+    config: Option<PathBuf>,
+    systemd_fds: Option<String>,
+) -> anyhow::Result<()> {
+    let problems = config::check(&args);
+    if !problems.is_empty() {
+        anyhow::bail!("invalid configuration:\n{}", problems.join("\n"));
+    }
+
+    let Some(session_secret_file) = args.session_secret_file.clone() else {
+        unreachable!("checked by config::check");
+    };
+    let relying_parties = args.relying_parties();
+    let policy = Reloadable::new(args.credential_policy());
+
+    let prometheus_handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Suffix("_seconds".to_string()), LATENCY_BUCKETS)?
+        .install_recorder()?;
+    describe_metrics();
+
+    let (storage, keyring, stats) = args.storage.connect_unencrypted().await?;
+    let storage: Arc<dyn storage::Storage> = match keyring {
+        Some(keyring) => {
+            let key_id = keyring.current().id().to_string();
+            let storage = Arc::new(EncryptedStorage::new(storage, keyring).await?);
+            if stats.needs_reencryption() {
+                info!(
+                    "re-encrypting {} plaintext values and {} values encrypted with a previous storage key",
+                    stats.plaintext, stats.previous
+                );
+                let storage = storage.clone();
+                tokio::spawn(async move {
+                    match storage.reencrypt().await {
+                        Ok(n) => info!("re-encrypted {n} values with storage key {key_id}"),
+                        Err(err) => error!("failed to re-encrypt stored values: {err}"),
+                    }
+                });
+            } else {
+                storage.reject_plaintext().await?;
+            }
+            storage
+        }
+        None => storage,
+    };
+    let audit_signing_key =
+        AuditSigningKey::load_or_generate(&args.storage.audit_signing_key_path())?;
+
+    let passwords = read_password_files(&relying_parties)?;
+
+    let mut app = App::new(storage);
+    if args.session_cache_ttl > 0 {
+        app = app.with_session_cache(SessionCache::new(
+            Duration::from_secs(args.session_cache_ttl),
+            args.session_cache_capacity,
+        ));
+    }
+    let app = Arc::new(app);
+    app.sync_users(&all_usernames(&passwords), args.purge_removed_users)
+        .await?;
+    let passwords: Vec<_> = relying_parties
+        .iter()
+        .map(|(name, _)| name.clone())
+        .zip(passwords.into_iter().map(Reloadable::new))
+        .collect();
+
+    let tls_config = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => {
+            let tls_config = Reloadable::new(tls::load_config(cert, key)?);
+            tls::watch(cert.clone(), key.clone(), tls_config.clone());
+            Some(tls_config)
+        }
+        _ => None,
+    };
+
+    let mut hangups = signal(SignalKind::hangup())?;
+    tokio::spawn({
+        let app = app.clone();
+        let passwords = passwords.clone();
+        let policy = policy.clone();
+        let tls_config = tls_config.clone();
+        async move {
+            while hangups.recv().await.is_some() {
+                match reload(
+                    &app,
+                    &matches,
+                    config.as_deref(),
+                    &passwords,
+                    &policy,
+                    tls_config.as_ref(),
+                )
+                .await
+                {
+                    Ok(()) => info!("reloaded the password files and policies"),
+                    Err(err) => {
+                        error!("failed to reload, keeping the previous configuration: {err}")
+                    }
+                }
+            }
+        }
+    });
+
+    tokio::spawn({
+        let app = app.clone();
+        let mut interval =
+            tokio::time::interval(Duration::from_secs(args.audit_checkpoint_interval));
+        async move {
+            loop {
+                interval.tick().await;
+                match app.checkpoint_audit_log(&audit_signing_key).await {
+                    Ok(Some(event_id)) => debug!("signed audit log checkpoint at event {event_id}"),
+                    Ok(None) => {}
+                    Err(err) => error!("failed to sign audit log checkpoint: {err}"),
+                }
+            }
+        }
+    });
+
+    tokio::spawn({
+        let app = app.clone();
+        let relying_parties: Vec<String> = relying_parties
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect();
+        let mut interval =
+            tokio::time::interval(Duration::from_secs(args.metrics_refresh_interval));
+        async move {
+            loop {
+                interval.tick().await;
+                match app.stats().await {
+                    Ok(stats) => record_stats(&stats, &relying_parties),
+                    Err(err) => error!("failed to refresh the gauges: {err}"),
+                }
+            }
+        }
+    });
+
+    #[cfg(feature = "sqlite")]
+    if let Some(directory) = args.backup_directory {
+        let database = args.storage.sqlite_path()?;
+        std::fs::create_dir_all(&directory)?;
+        let mut interval = tokio::time::interval(Duration::from_secs(args.backup_interval));
+        tokio::spawn(async move {
+            loop {
+                interval.tick().await;
+                match backup::scheduled_backup(&database, &directory, args.backup_retention).await {
+                    Ok(path) => info!("backed the database up to {}", path.display()),
+                    Err(err) => error!("failed to back the database up: {err}"),
+                }
+            }
+        });
+    }
+
+    let session_key = Key::try_from(std::fs::read_to_string(session_secret_file)?.as_bytes())?;
 
     let parser = liquid::ParserBuilder::with_stdlib().build()?;
     let templates = Templates {
@@ -119,9 +984,206 @@ async fn main() -> anyhow::Result<()> {
             env!("CARGO_MANIFEST_DIR"),
             "/templates/authenticate.liquid"
         )))?,
+        admin_users_template: parser.parse(include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/templates/admin_users.liquid"
+        )))?,
+        admin_user_template: parser.parse(include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/templates/admin_user.liquid"
+        )))?,
+        admin_events_template: parser.parse(include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/templates/admin_events.liquid"
+        )))?,
     };
 
-    let router = Router::new()
+    let templates = Arc::new(templates);
+    let prometheus_handle = Arc::new(prometheus_handle);
+    let mut routers = Vec::new();
+    for ((name, rp), (_, passwords)) in relying_parties.iter().zip(&passwords) {
+        let app = Arc::new(app.for_relying_party(name));
+        let session_layer = SessionManagerLayer::new(app.session_store())
+            .with_private(session_key.clone())
+            .with_always_save(false)
+            .with_domain(rp.cookie_domain().to_string())
+            .with_name(relying_party::cookie_name(name));
+
+        let router = routes()
+            .layer(session_layer)
+            .layer(Extension(app))
+            .layer(Extension(Arc::new(rp.webauthn()?)))
+            .layer(Extension(templates.clone()))
+            .layer(Extension(policy.clone()))
+            .layer(Extension(prometheus_handle.clone()))
+            .layer(Extension(passwords.clone()))
+            .layer(Extension(CookieDomain(rp.cookie_domain().to_string())));
+        routers.push((rp.cookie_domain().to_string(), router));
+        debug!("serving relying party {name} ({})", rp.rp_id);
+    }
+    let router = relying_party::dispatch(routers).layer(TraceLayer::new_for_http());
+
+    // A socket passed by systemd takes precedence over the configured one.
+    let listener = match Listener::from_systemd(systemd_fds.as_deref())? {
+        Some(listener) => listener,
+        None => match &args.unix_socket {
+            Some(path) => Listener::bind_unix(path, args.unix_socket_mode)?,
+            None => Listener::bind_tcp(args.address).await?,
+        },
+    };
+    let listener = match tls_config {
+        Some(tls_config) => listener.with_tls(tls_config)?,
+        None => listener,
+    };
+
+    if let Some(address) = args.tls_redirect_address {
+        let redirect_listener = tokio::net::TcpListener::bind(address).await?;
+        let https_port = listener.port().unwrap_or(443);
+        tokio::spawn(async move {
+            if let Err(err) = tls::redirect_to_https(redirect_listener, https_port).await {
+                error!("failed to serve HTTPS redirects: {err}");
+            }
+        });
+        debug!("redirecting to HTTPS on {address}");
+    }
+
+    debug!("listening on {}", listener.local_addr());
+
+    if let Err(err) = systemd::notify("READY=1") {
+        error!("failed to notify systemd: {err}");
+    }
+    if let Some(interval) = systemd::watchdog_interval() {
+        let mut interval = tokio::time::interval(interval);
+        tokio::spawn(async move {
+            loop {
+                interval.tick().await;
+                if let Err(err) = systemd::notify("WATCHDOG=1") {
+                    error!("failed to notify the systemd watchdog: {err}");
+                }
+            }
+        });
+    }
+
+    let (shutting_down, shutdown_started) = tokio::sync::oneshot::channel();
+    let shutdown = async move {
+        shutdown_signal().await;
+        info!(
+            "shutting down, waiting up to {} seconds for open connections",
+            args.shutdown_timeout
+        );
+        if let Err(err) = systemd::notify("STOPPING=1") {
+            error!("failed to notify systemd: {err}");
+        }
+        _ = shutting_down.send(());
+    };
+
+    tokio::select! {
+        result = listener.serve(router, shutdown) => result?,
+        _ = async {
+            if shutdown_started.await.is_ok() {
+                tokio::time::sleep(Duration::from_secs(args.shutdown_timeout)).await;
+            } else {
+                std::future::pending::<()>().await;
+            }
+        } => warn!("connections still open after the shutdown timeout, closing them"),
+    }
+
+    Ok(())
+}
+
+/// Buckets of the latency histograms, which would otherwise be exported as summaries.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Describes the metrics and initializes the unlabeled counters, so that they are exported before
+/// their first increment.
+fn describe_metrics() {
+    describe_counter!(
+        "successful_registrations",
+        "Credentials registered, by user"
+    );
+    describe_counter!(
+        "failed_registrations",
+        "Failed credential registrations, by reason"
+    );
+    describe_counter!(
+        "successful_authentications",
+        "Passkey authentications, by user"
+    );
+    describe_counter!(
+        "failed_authentications",
+        "Failed passkey authentications, by reason"
+    );
+    describe_counter!(
+        "authorized_requests",
+        "Requests with a logged in session, by protected host"
+    );
+    describe_counter!(
+        "unauthorized_requests",
+        "Requests without a logged in session, by protected host"
+    );
+    describe_counter!(
+        "counter_regressions",
+        "Authentications refused because the signature counter regressed"
+    );
+    describe_counter!("session_cache_hits", "Sessions loaded from the cache");
+    describe_counter!("session_cache_misses", "Sessions loaded from storage");
+    describe_histogram!(
+        "http_request_duration_seconds",
+        Unit::Seconds,
+        "Request latency, by method, route and status"
+    );
+    describe_histogram!(
+        "argon2_verification_seconds",
+        Unit::Seconds,
+        "Time spent verifying passwords"
+    );
+
+    describe_gauge!("active_sessions", "Stored sessions that have not expired");
+    describe_gauge!("expired_sessions", "Stored sessions that have expired");
+    describe_gauge!("users", "Registered users");
+    describe_gauge!(
+        "users_without_credentials",
+        "Users without a credential for any relying party"
+    );
+    describe_gauge!(
+        "credentials",
+        "Credentials, by relying party and type (synced or device_bound)"
+    );
+
+    counter!("counter_regressions").absolute(0);
+    counter!("session_cache_hits").absolute(0);
+    counter!("session_cache_misses").absolute(0);
+}
+
+/// Sets the state gauges. Credentials are reported for both types of every configured relying
+/// party, so that a count dropping to zero is exported as such.
+fn record_stats(stats: &storage::StoredCounts, relying_parties: &[String]) {
+    gauge!("active_sessions").set(stats.active_sessions as f64);
+    gauge!("expired_sessions").set(stats.expired_sessions as f64);
+    gauge!("users").set(stats.users as f64);
+    gauge!("users_without_credentials").set(stats.users_without_credentials as f64);
+
+    let mut credentials = stats.credentials.clone();
+    for name in relying_parties {
+        for synced in [false, true] {
+            credentials.entry((name.clone(), synced)).or_default();
+        }
+    }
+    for ((relying_party, synced), count) in credentials {
+        gauge!(
+            "credentials",
+            "relying_party" => relying_party,
+            "type" => if synced { "synced" } else { "device_bound" },
+        )
+        .set(count as f64);
+    }
+}
+
+/// The routes served for each relying party.
+fn routes() -> Router {
+    Router::new()
         .route(
             "/metrics",
             get(
@@ -131,7 +1193,7 @@ async fn main() -> anyhow::Result<()> {
         )
         .route(
             "/api/validate",
-            get(|| async {}).layer(middleware::from_fn(require_logged_in)),
+            get(validate_handler).layer(middleware::from_fn(require_logged_in)),
         )
         .route(
             "/api/register",
@@ -143,27 +1205,72 @@ async fn main() -> anyhow::Result<()> {
             "/api/authenticate",
             get(authenticate_start_handler).post(authenticate_end_handler),
         )
+        .route(
+            "/api/credentials",
+            get(get_credentials_api_handler).layer(middleware::from_fn(require_logged_in)),
+        )
         .route(
             "/api/credentials/{cred_id}",
-            delete(delete_credentials_api_handler).layer(middleware::from_fn(require_logged_in)),
+            patch(update_credentials_api_handler)
+                .delete(delete_credentials_api_handler)
+                .layer(middleware::from_fn(require_logged_in)),
+        )
+        .route(
+            "/api/admin/users",
+            get(get_users_admin_api_handler).layer(middleware::from_fn(allow_only_localhost)),
+        )
+        .route(
+            "/api/admin/users/{username}",
+            put(update_user_admin_api_handler).layer(middleware::from_fn(allow_only_localhost)),
+        )
+        .route(
+            "/api/admin/sessions",
+            delete(revoke_sessions_admin_api_handler)
+                .layer(middleware::from_fn(allow_only_localhost)),
+        )
+        .route(
+            "/api/admin/audit-events",
+            get(get_audit_events_admin_api_handler)
+                .layer(middleware::from_fn(allow_only_localhost)),
+        )
+        .route(
+            "/api/console/assertion",
+            get(assertion_start_console_handler).layer(middleware::from_fn(require_admin)),
+        )
+        .route(
+            "/api/console/actions",
+            post(action_console_handler).layer(middleware::from_fn(require_admin)),
+        )
+        .route(
+            "/admin",
+            get(get_users_console_handler).layer(middleware::from_fn(require_admin)),
+        )
+        .route(
+            "/admin/users/{username}",
+            get(get_user_console_handler).layer(middleware::from_fn(require_admin)),
+        )
+        .route(
+            "/admin/events",
+            get(get_events_console_handler).layer(middleware::from_fn(require_admin)),
         )
         .route("/authenticate", get(get_authenticate_template_handler))
         .route("/credentials", get(get_credentials_template_handler))
+        .route_layer(middleware::from_fn(record_request_duration))
         .fallback(root_handler)
-        .layer(TraceLayer::new_for_http())
-        .layer(session_layer)
-        .layer(Extension(Arc::new(RwLock::new(app))))
-        .layer(Extension(Arc::new(webauthn)))
-        .layer(Extension(Arc::new(templates)))
-        .layer(Extension(Arc::new(prometheus_handle)))
-        .layer(Extension(read_password_file(cli.password_file)?))
-        .into_make_service_with_connect_info::<SocketAddr>();
-
-    debug!("listening on {}", cli.address);
-
-    let listener = tokio::net::TcpListener::bind(&cli.address).await?;
+}
 
-    axum::serve(listener, router).await?;
+/// Completes on SIGTERM or SIGINT.
+async fn shutdown_signal() {
+    let (Ok(mut terminate), Ok(mut interrupt)) = (
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt()),
+    ) else {
+        error!("failed to listen for shutdown signals");
+        return std::future::pending().await;
+    };
 
-    Ok(())
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = interrupt.recv() => {}
+    }
 }
@@ -0,0 +1,302 @@
+//! The password file, with lines of `username:argon2_hash`, and the `hash-password` command that
+//! writes them.
+
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::OpenOptions,
+    io::{BufRead, IsTerminal, Write},
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::Path,
+};
+
+pub fn read_password_file(filepath: &Path) -> anyhow::Result<HashMap<String, String>> {
+    Ok(std::fs::read_to_string(filepath)?
+        .lines()
+        .fold(HashMap::new(), |mut acc, cur| {
+            if let Some((username, hash)) = cur.split_once(':') {
+                acc.insert(String::from(username), String::from(hash));
+            }
+            acc
+        }))
+}
+
+/// Returns a problem for every line of the password file that is malformed or has a hash that
+/// logins cannot be verified against.
+pub fn check_password_file(filepath: &Path) -> Vec<String> {
+    let contents = match std::fs::read_to_string(filepath) {
+        Ok(contents) => contents,
+        Err(err) => {
+            return vec![format!(
+                "reading password file {}: {err}",
+                filepath.display()
+            )]
+        }
+    };
+
+    let mut problems = Vec::new();
+    let mut usernames = HashSet::new();
+    for (number, line) in contents.lines().enumerate() {
+        let location = format!("password file {} line {}", filepath.display(), number + 1);
+        if line.trim().is_empty() {
+            continue;
+        }
+        let Some((username, hash)) = line.split_once(':') else {
+            problems.push(format!("{location} is not of the form username:hash"));
+            continue;
+        };
+        if username.is_empty() {
+            problems.push(format!("{location} has an empty username"));
+        }
+        if !usernames.insert(username) {
+            problems.push(format!(
+                "{location} repeats user {username}, only the last line for a user is used"
+            ));
+        }
+
+        let hash = match PasswordHash::new(hash) {
+            Ok(hash) => hash,
+            Err(err) => {
+                problems.push(format!("{location} has a malformed hash: {err}"));
+                continue;
+            }
+        };
+        if Algorithm::try_from(hash.algorithm).is_err() {
+            problems.push(format!(
+                "{location} has a {} hash instead of an Argon2 hash",
+                hash.algorithm
+            ));
+        } else if let Err(err) = Params::try_from(&hash) {
+            problems.push(format!("{location} has invalid Argon2 parameters: {err}"));
+        } else if hash.salt.is_none() || hash.hash.is_none() {
+            problems.push(format!("{location} has a hash without salt or output"));
+        }
+    }
+
+    if usernames.is_empty() && problems.is_empty() {
+        problems.push(format!(
+            "password file {} contains no users",
+            filepath.display()
+        ));
+    }
+
+    problems
+}
+
+/// Hashes the password with Argon2id and a random salt, in the PHC string format expected in the
+/// password file.
+pub fn hash_password(password: &str, params: Params) -> anyhow::Result<String> {
+    let mut salt = [0; 16];
+    openssl::rand::rand_bytes(&mut salt)?;
+    let salt = SaltString::encode_b64(&salt).map_err(|err| anyhow::anyhow!("{err}"))?;
+
+    Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|err| anyhow::anyhow!("hashing password: {err}"))?
+        .to_string())
+}
+
+/// Disables echoing of the terminal on stdin until dropped.
+struct EchoDisabled(libc::termios);
+
+impl EchoDisabled {
+    fn new() -> std::io::Result<Self> {
+        // SAFETY: termios is a plain C struct that tcgetattr fills in.
+        let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let original = termios;
+        termios.c_lflag &= !libc::ECHO;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(Self(original))
+    }
+}
+
+impl Drop for EchoDisabled {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.0) };
+    }
+}
+
+fn read_line() -> anyhow::Result<String> {
+    let mut line = String::new();
+    std::io::stdin().lock().read_line(&mut line)?;
+
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn prompt(message: &str) -> anyhow::Result<String> {
+    eprint!("{message}");
+    std::io::stderr().flush()?;
+    let line = {
+        let _echo_disabled = EchoDisabled::new()?;
+        read_line()?
+    };
+    eprintln!();
+
+    Ok(line)
+}
+
+/// Prompts for the password twice without echo when stdin is a terminal, otherwise reads it from
+/// the first line of stdin.
+pub fn read_password() -> anyhow::Result<String> {
+    let password = if std::io::stdin().is_terminal() {
+        let password = prompt("Password: ")?;
+        if prompt("Repeat password: ")? != password {
+            anyhow::bail!("passwords do not match");
+        }
+        password
+    } else {
+        read_line()?
+    };
+
+    if password.is_empty() {
+        anyhow::bail!("password is empty");
+    }
+
+    Ok(password)
+}
+
+/// Returns the contents of the password file with the user's line replaced, or appended if the
+/// user has none, and whether a line was replaced.
+fn set_entry(contents: &str, username: &str, hash: &str) -> (String, bool) {
+    let entry = format!("{username}:{hash}");
+    let mut replaced = false;
+    let mut lines = contents
+        .lines()
+        .filter_map(|line| match line.split_once(':') {
+            Some((line_username, _)) if line_username == username => {
+                // Drop duplicate lines, which would otherwise shadow the new one.
+                if replaced {
+                    None
+                } else {
+                    replaced = true;
+                    Some(entry.as_str())
+                }
+            }
+            _ => Some(line),
+        })
+        .collect::<Vec<_>>();
+    if !replaced {
+        lines.push(&entry);
+    }
+
+    (lines.join("\n") + "\n", replaced)
+}
+
+/// Adds or replaces the user's line in the password file, creating it if it does not exist.
+/// Returns whether a line was replaced.
+pub fn update_password_file(path: &Path, username: &str, hash: &str) -> anyhow::Result<bool> {
+    let (contents, mode) = match std::fs::read_to_string(path) {
+        Ok(contents) => (contents, std::fs::metadata(path)?.permissions().mode()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => (String::new(), 0o600),
+        Err(err) => anyhow::bail!("reading {}: {err}", path.display()),
+    };
+    let (contents, replaced) = set_entry(&contents, username, hash);
+
+    // Write the new file next to the old one and rename it over, so that the server never reads a
+    // partially written file.
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("{} is not a file path", path.display()))?;
+    let partial = path.with_file_name(format!(".{}.partial", file_name.to_string_lossy()));
+    _ = std::fs::remove_file(&partial);
+    let result = (|| {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(mode)
+            .open(&partial)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&partial, path)
+    })();
+    if let Err(err) = result {
+        _ = std::fs::remove_file(&partial);
+        anyhow::bail!("writing {}: {err}", path.display());
+    }
+
+    Ok(replaced)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argon2::{password_hash::PasswordHash, PasswordVerifier};
+
+    #[test]
+    fn test_hash_password() {
+        let hash = hash_password("foo_password", Params::new(1024, 1, 1, None).unwrap()).unwrap();
+        assert!(hash.starts_with("$argon2id$v=19$m=1024,t=1,p=1$"));
+
+        let parsed_hash = PasswordHash::new(&hash).unwrap();
+        assert!(Argon2::default()
+            .verify_password(b"foo_password", &parsed_hash)
+            .is_ok());
+        assert!(Argon2::default()
+            .verify_password(b"bar_password", &parsed_hash)
+            .is_err());
+    }
+
+    #[test]
+    fn test_check_password_file() {
+        let path = std::env::temp_dir().join(format!("webauthn-tiny-{}", uuid::Uuid::new_v4()));
+        let hash = hash_password("foo_password", Params::new(1024, 1, 1, None).unwrap()).unwrap();
+
+        std::fs::write(&path, format!("foo_user:{hash}\n\nbar_user:{hash}\n")).unwrap();
+        assert!(check_password_file(&path).is_empty());
+
+        std::fs::write(&path, "").unwrap();
+        assert_eq!(check_password_file(&path).len(), 1);
+
+        std::fs::write(
+            &path,
+            [
+                "foo_user",
+                &format!(":{hash}"),
+                "bar_user:not a hash",
+                &format!("bar_user:{hash}"),
+                "baz_user:$pbkdf2-sha256$i=1000$c2FsdA$aGFzaA",
+                "qux_user:$argon2id$v=19$m=1,t=1,p=1$c2FsdHNhbHQ$aGFzaGhhc2g",
+            ]
+            .join("\n"),
+        )
+        .unwrap();
+        assert_eq!(check_password_file(&path).len(), 6);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_set_entry() {
+        assert_eq!(
+            set_entry("", "foo_user", "$new"),
+            ("foo_user:$new\n".to_string(), false)
+        );
+        assert_eq!(
+            set_entry("bar_user:$bar\n", "foo_user", "$new"),
+            ("bar_user:$bar\nfoo_user:$new\n".to_string(), false)
+        );
+        assert_eq!(
+            set_entry(
+                "foo_user:$old\nbar_user:$bar\nfoo_user:$older",
+                "foo_user",
+                "$new"
+            ),
+            ("foo_user:$new\nbar_user:$bar\n".to_string(), true)
+        );
+        // Usernames that merely share a prefix are left alone.
+        assert_eq!(
+            set_entry("foo_user2:$other\n", "foo_user", "$new"),
+            ("foo_user2:$other\nfoo_user:$new\n".to_string(), false)
+        );
+    }
+}
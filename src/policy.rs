@@ -0,0 +1,147 @@
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::collections::HashMap;
+use webauthn_rs::prelude::{Credential, Passkey};
+
+/// What to do when an authenticator presents a signature counter that did not increase, which
+/// may indicate a cloned authenticator. The WebAuthn library refuses the assertion regardless; the
+/// action determines what happens in addition. Each action includes the ones before it: `Log`
+/// logs a warning, `Metric` also increments the `counter_regressions` metric and `Disable` also
+/// disables the credential so it cannot be used until it is enabled again.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CounterRegressionAction {
+    #[default]
+    Log,
+    Metric,
+    Disable,
+}
+
+/// Policy applied to a protected host, identified by the X-Forwarded-Host header of the
+/// `/api/validate` request.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct HostPolicy {
+    /// Only accept sessions authenticated with a device-bound credential.
+    #[serde(default)]
+    pub device_bound: bool,
+    /// Only allow users in one of these groups, or all users if empty.
+    #[serde(default)]
+    pub allowed_groups: Vec<String>,
+}
+
+/// Users allowed into the `/admin` console, named directly or through one of their groups.
+#[derive(Debug, Default, Clone)]
+pub struct AdminRole {
+    pub users: Vec<String>,
+    pub groups: Vec<String>,
+}
+
+impl AdminRole {
+    pub fn includes(&self, username: &str, groups: &[String]) -> bool {
+        self.users.iter().any(|user| user == username)
+            || self.groups.iter().any(|group| groups.contains(group))
+    }
+}
+
+/// Policy applied to credentials based on whether they are synced passkeys (backup-eligible) or
+/// bound to a single hardware authenticator, to the protected hosts and to the admin console.
+#[derive(Debug, Default, Clone)]
+pub struct CredentialPolicy {
+    /// Refuse to register credentials that are backup-eligible.
+    pub reject_backup_eligible: bool,
+    /// Policies of protected hosts, keyed by lowercase host name.
+    pub hosts: HashMap<String, HostPolicy>,
+    /// Action taken when a credential's signature counter regresses.
+    pub counter_regression_action: CounterRegressionAction,
+    /// Users allowed to administer all users.
+    pub admins: AdminRole,
+}
+
+impl CredentialPolicy {
+    pub fn allows_registration(&self, passkey: &Passkey) -> bool {
+        !(self.reject_backup_eligible && Credential::from(passkey.clone()).backup_eligible)
+    }
+
+    fn host(&self, host: &str) -> Option<&HostPolicy> {
+        self.hosts.get(&host.to_ascii_lowercase())
+    }
+
+    pub fn requires_device_bound(&self, host: &str) -> bool {
+        self.host(host).is_some_and(|policy| policy.device_bound)
+    }
+
+    pub fn allows_groups(&self, host: &str, groups: &[String]) -> bool {
+        self.host(host).is_none_or(|policy| {
+            policy.allowed_groups.is_empty()
+                || policy
+                    .allowed_groups
+                    .iter()
+                    .any(|group| groups.contains(group))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_requires_device_bound() {
+        let policy = CredentialPolicy {
+            reject_backup_eligible: false,
+            hosts: HashMap::from([(
+                "secure.foo.com".to_string(),
+                HostPolicy {
+                    device_bound: true,
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        };
+
+        assert!(policy.requires_device_bound("secure.foo.com"));
+        assert!(policy.requires_device_bound("SECURE.foo.com"));
+        assert!(!policy.requires_device_bound("foo.com"));
+        assert!(!CredentialPolicy::default().requires_device_bound("secure.foo.com"));
+    }
+
+    #[test]
+    fn test_allows_groups() {
+        let policy = CredentialPolicy {
+            hosts: HashMap::from([
+                (
+                    "admin.foo.com".to_string(),
+                    HostPolicy {
+                        allowed_groups: vec!["admins".to_string(), "ops".to_string()],
+                        ..Default::default()
+                    },
+                ),
+                ("open.foo.com".to_string(), HostPolicy::default()),
+            ]),
+            ..Default::default()
+        };
+        let groups = |groups: &[&str]| groups.iter().map(|g| g.to_string()).collect::<Vec<_>>();
+
+        assert!(policy.allows_groups("admin.foo.com", &groups(&["users", "ops"])));
+        assert!(policy.allows_groups("Admin.foo.com", &groups(&["admins"])));
+        assert!(!policy.allows_groups("admin.foo.com", &groups(&["users"])));
+        assert!(!policy.allows_groups("admin.foo.com", &[]));
+        assert!(policy.allows_groups("open.foo.com", &[]));
+        assert!(policy.allows_groups("foo.com", &[]));
+    }
+
+    #[test]
+    fn test_admin_role_includes() {
+        let admins = AdminRole {
+            users: vec!["foo_user".to_string()],
+            groups: vec!["admins".to_string()],
+        };
+
+        assert!(admins.includes("foo_user", &[]));
+        assert!(admins.includes("bar_user", &["users".to_string(), "admins".to_string()]));
+        assert!(!admins.includes("bar_user", &["users".to_string()]));
+        assert!(!admins.includes("foo_user2", &[]));
+        assert!(!AdminRole::default().includes("foo_user", &["admins".to_string()]));
+    }
+}
@@ -0,0 +1,149 @@
+//! Relying parties served in addition to the one configured with `--rp-id`, each with its own
+//! origins, session cookie and optionally its own password file. Requests are routed to the
+//! relying party whose cookie domain covers the request's `Host`, falling back to the default one.
+
+use axum::{
+    extract::Request,
+    http::{header::HOST, uri::Authority},
+    Router,
+};
+use serde::Deserialize;
+use std::{path::PathBuf, sync::Arc};
+use tower::ServiceExt;
+use webauthn_rs::{prelude::Url, Webauthn, WebauthnBuilder};
+
+/// A relying party as configured under `[relying-parties.<name>]` in the config file. Keys are
+/// named like the flags of the default relying party.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RelyingPartyConfig {
+    pub rp_id: String,
+    pub rp_origin: String,
+    #[serde(default)]
+    pub extra_allowed_origin: Vec<String>,
+    /// Defaults to the RP ID.
+    pub cookie_domain: Option<String>,
+    /// Defaults to the password file of the default relying party.
+    pub password_file: Option<PathBuf>,
+}
+
+impl RelyingPartyConfig {
+    pub fn cookie_domain(&self) -> &str {
+        self.cookie_domain.as_deref().unwrap_or(&self.rp_id)
+    }
+
+    pub fn webauthn(&self) -> anyhow::Result<Webauthn> {
+        let origin_url = Url::parse(&self.rp_origin)?;
+        let mut builder = WebauthnBuilder::new(&self.rp_id, &origin_url)?.allow_subdomains(true);
+        for url in &self.extra_allowed_origin {
+            builder = builder.append_allowed_origin(&Url::parse(url)?);
+        }
+
+        Ok(builder.build()?)
+    }
+}
+
+/// The cookie domain of the relying party a request is routed to, added as an extension.
+#[derive(Clone)]
+pub struct CookieDomain(pub String);
+
+/// Names end up in session keys and cookie names, so they are restricted to lowercase letters,
+/// digits and dashes.
+pub fn check_name(name: &str) -> Option<String> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    {
+        return Some(format!(
+            "relying party name {name:?} must only contain lowercase letters, digits and dashes"
+        ));
+    }
+
+    None
+}
+
+/// The name of the session cookie. Relying parties whose cookie domains overlap would otherwise
+/// overwrite each other's cookies.
+pub fn cookie_name(name: &str) -> String {
+    match name {
+        crate::storage::DEFAULT_RELYING_PARTY => "id".to_string(),
+        name => format!("id-{name}"),
+    }
+}
+
+/// Whether a cookie for the domain is sent to the host.
+pub fn domain_matches(host: &str, domain: &str) -> bool {
+    host == domain
+        || host
+            .strip_suffix(domain)
+            .is_some_and(|subdomain| subdomain.ends_with('.'))
+}
+
+/// Returns the index of the cookie domain most specifically covering the host, or 0, the default
+/// relying party, if none does.
+fn select<'a>(host: Option<&str>, cookie_domains: impl Iterator<Item = &'a str>) -> usize {
+    let Some(host) = host.map(str::to_ascii_lowercase) else {
+        return 0;
+    };
+
+    cookie_domains
+        .enumerate()
+        .filter(|(_, domain)| domain_matches(&host, domain))
+        .max_by_key(|(index, domain)| (domain.len(), std::cmp::Reverse(*index)))
+        .map_or(0, |(index, _)| index)
+}
+
+fn request_host(req: &Request) -> Option<String> {
+    req.headers()
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| host.parse::<Authority>().ok())
+        .map(|authority| authority.host().to_string())
+        .or_else(|| req.uri().host().map(str::to_string))
+}
+
+/// Routes each request to the router of its relying party, given with the relying party's cookie
+/// domain. The default relying party comes first.
+pub fn dispatch(relying_parties: Vec<(String, Router)>) -> Router {
+    let relying_parties = Arc::new(relying_parties);
+
+    Router::new().fallback(move |req: Request| {
+        let relying_parties = relying_parties.clone();
+        async move {
+            let index = select(
+                request_host(&req).as_deref(),
+                relying_parties.iter().map(|(domain, _)| domain.as_str()),
+            );
+            relying_parties[index].1.clone().oneshot(req).await
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select() {
+        let domains = ["example.com", "other.org", "corp.example.com"];
+        let select = |host| select(host, domains.into_iter());
+
+        assert_eq!(select(Some("example.com")), 0);
+        assert_eq!(select(Some("auth.example.com")), 0);
+        assert_eq!(select(Some("Auth.Other.org")), 1);
+        assert_eq!(select(Some("git.corp.example.com")), 2);
+        assert_eq!(select(Some("corp.example.com")), 2);
+        assert_eq!(select(Some("notother.org")), 0);
+        assert_eq!(select(Some("[::1]")), 0);
+        assert_eq!(select(None), 0);
+    }
+
+    #[test]
+    fn test_check_name() {
+        assert_eq!(check_name("corp-2"), None);
+        assert!(check_name("").is_some());
+        assert!(check_name("Corp").is_some());
+        assert!(check_name("corp/a").is_some());
+    }
+}
@@ -1,69 +1,263 @@
+use crate::storage::{Storage, StorageResult};
 use async_trait::async_trait;
-use rusqlite::OptionalExtension;
-use tokio_rusqlite::Connection;
+use metrics::counter;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 use tower_sessions::{
     session::{Id, Record},
     session_store::{Error, Result, SessionStore},
 };
 
+/// The session key holding the name of the user a session belongs to.
+pub const SESSIONKEY_USERNAME: &str = "username";
+
+/// A bounded in-memory cache of session records, so that the sessions of frequent requests do not
+/// have to be loaded from the database and deserialized every time. Entries expire after the TTL,
+/// which bounds how long a session deleted by another instance sharing the database stays valid.
+/// Entries are keyed like the stored sessions, so that one cache can be shared by the session
+/// stores of all relying parties.
 #[derive(Clone, Debug)]
-pub struct SqliteSessionStore {
-    db: Connection,
+pub struct SessionCache {
+    ttl: Duration,
+    capacity: usize,
+    entries: Arc<Mutex<HashMap<String, (Record, Instant)>>>,
 }
 
-impl SqliteSessionStore {
-    pub fn new(db: Connection) -> Self {
-        Self { db }
+impl SessionCache {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            ttl,
+            capacity,
+            entries: Default::default(),
+        }
     }
 
-    pub async fn init(&self) -> anyhow::Result<()> {
-        self.db
-            .call(|conn| {
-                Ok(conn.execute(
-                    r#"create table if not exists sessions (
-                         id text primary key not null,
-                         value json not null
-                       )"#,
-                    [],
-                ))
-            })
-            .await??;
+    fn get(&self, key: &str) -> Option<Record> {
+        let mut entries = self.entries.lock().expect("session cache lock poisoned");
+        match entries.get(key) {
+            Some((record, inserted)) if inserted.elapsed() < self.ttl => Some(record.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
 
-        Ok(())
+    fn insert(&self, key: String, record: &Record) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut entries = self.entries.lock().expect("session cache lock poisoned");
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            entries.retain(|_, (_, inserted)| inserted.elapsed() < self.ttl);
+        }
+        // Eviction scans all entries, but only happens when the cache is full of live sessions.
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            if let Some(oldest) = entries
+                .iter()
+                .min_by_key(|(_, (_, inserted))| *inserted)
+                .map(|(key, _)| key.clone())
+            {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(key, (record.clone(), Instant::now()));
+    }
+
+    fn remove(&self, key: &str) {
+        self.entries
+            .lock()
+            .expect("session cache lock poisoned")
+            .remove(key);
+    }
+
+    fn clear(&self) {
+        self.entries
+            .lock()
+            .expect("session cache lock poisoned")
+            .clear();
+    }
+}
+
+/// A session store backed by the storage. Stores with a namespace keep their sessions apart from
+/// those of other namespaces, so that a session of one relying party cannot be loaded, listed or
+/// deleted by another. A store without a namespace owns the sessions stored outside of any.
+#[derive(Clone, Debug)]
+pub struct StorageSessionStore {
+    storage: Arc<dyn Storage>,
+    cache: Option<SessionCache>,
+    namespace: Option<String>,
+    all_namespaces: bool,
+}
+
+impl StorageSessionStore {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        Self {
+            storage,
+            cache: None,
+            namespace: None,
+            all_namespaces: false,
+        }
+    }
+
+    /// Stores sessions under `<namespace>/<id>`. Session IDs are base64url, which never contains
+    /// a slash.
+    pub fn with_namespace(self, namespace: &str) -> Self {
+        Self {
+            namespace: Some(namespace.to_string()),
+            ..self
+        }
+    }
+
+    /// Makes clearing, finding and deleting sessions span every namespace, for when a user is
+    /// deleted from all relying parties.
+    pub fn across_namespaces(self) -> Self {
+        Self {
+            all_namespaces: true,
+            ..self
+        }
+    }
+
+    fn key(&self, id: &Id) -> String {
+        match &self.namespace {
+            Some(namespace) => format!("{namespace}/{id}"),
+            None => id.to_string(),
+        }
+    }
+
+    /// Whether the stored session with the given key belongs to this store's namespace.
+    fn owns(&self, key: &str) -> bool {
+        if self.all_namespaces {
+            return true;
+        }
+
+        match &self.namespace {
+            Some(namespace) => key
+                .strip_prefix(namespace.as_str())
+                .is_some_and(|id| id.starts_with('/')),
+            None => !key.contains('/'),
+        }
+    }
+
+    /// Returns the keys of the stored sessions in this store's namespace.
+    async fn keys(&self) -> StorageResult<Vec<String>> {
+        let mut keys = self.storage.list_session_ids().await?;
+        keys.retain(|key| self.owns(key));
+
+        Ok(keys)
+    }
+
+    /// Serves loads from the cache, writing saves and deletes through to the storage.
+    pub fn with_cache(self, cache: SessionCache) -> Self {
+        Self {
+            cache: Some(cache),
+            ..self
+        }
+    }
+
+    /// Returns the keys and records of the sessions for which `predicate` returns true.
+    async fn matching(
+        &self,
+        predicate: impl Fn(&Record) -> bool,
+    ) -> StorageResult<Vec<(String, Record)>> {
+        let mut records = Vec::new();
+        for key in self.keys().await? {
+            let Some(value) = self.storage.load_session(&key).await? else {
+                continue;
+            };
+            let Ok(record) = serde_json::from_str::<Record>(&value) else {
+                continue;
+            };
+            if predicate(&record) {
+                records.push((key, record));
+            }
+        }
+
+        Ok(records)
+    }
+
+    /// Returns the sessions for which `predicate` returns true, reading them from the storage rather
+    /// than the cache.
+    pub async fn find_matching(
+        &self,
+        predicate: impl Fn(&Record) -> bool,
+    ) -> StorageResult<Vec<Record>> {
+        Ok(self
+            .matching(predicate)
+            .await?
+            .into_iter()
+            .map(|(_, record)| record)
+            .collect())
+    }
+
+    /// Deletes the sessions for which `predicate` returns true. Returns the number of deleted
+    /// sessions.
+    pub async fn delete_matching(
+        &self,
+        predicate: impl Fn(&Record) -> bool,
+    ) -> StorageResult<usize> {
+        let records = self.matching(predicate).await?;
+        for (key, _) in &records {
+            self.storage.delete_session(key).await?;
+            if let Some(cache) = &self.cache {
+                cache.remove(key);
+            }
+        }
+
+        Ok(records.len())
     }
 
-    #[allow(dead_code)]
-    pub async fn clear(&self) -> anyhow::Result<()> {
-        self.db
-            .call(|conn| Ok(conn.execute(r#"delete from sessions"#, [])))
-            .await??;
+    /// Deletes all sessions in this store's namespace.
+    pub async fn clear(&self) -> StorageResult<()> {
+        if self.all_namespaces {
+            self.storage.clear_sessions().await?;
+            if let Some(cache) = &self.cache {
+                cache.clear();
+            }
+            return Ok(());
+        }
+
+        // The sessions are deleted one by one rather than with `delete_matching`, so that sessions
+        // that cannot be read are deleted as well.
+        for key in self.keys().await? {
+            self.storage.delete_session(&key).await?;
+            if let Some(cache) = &self.cache {
+                cache.remove(&key);
+            }
+        }
 
         Ok(())
     }
 }
 
 #[async_trait]
-impl SessionStore for SqliteSessionStore {
+impl SessionStore for StorageSessionStore {
     /// Saves the provided session record to the store.
     ///
     /// This method is intended for updating the state of an existing session.
     async fn save(&self, session_record: &Record) -> Result<()> {
-        let session_id = session_record.id.to_string();
+        let session_id = self.key(&session_record.id);
         let session_value =
             serde_json::to_string(session_record).map_err(|err| Error::Backend(err.to_string()))?;
 
-        _ = self
-            .db
-            .call(|conn| {
-                Ok(conn.execute(
-                    r#"insert or replace into sessions (id, value) values(?1, ?2)"#,
-                    (session_id, session_value),
-                ))
-            })
+        self.storage
+            .save_session(
+                &session_id,
+                &session_value,
+                session_record.expiry_date.unix_timestamp(),
+            )
             .await
-            .map_err(|err| Error::Backend(err.to_string()))?
             .map_err(|err| Error::Backend(err.to_string()))?;
 
+        if let Some(cache) = &self.cache {
+            cache.insert(session_id, session_record);
+        }
+
         Ok(())
     }
 
@@ -73,22 +267,20 @@ impl SessionStore for SqliteSessionStore {
     /// does not exist or has been invalidated (e.g., expired), `None` is
     /// returned.
     async fn load(&self, session_id: &Id) -> Result<Option<Record>> {
-        let session_id = session_id.to_string();
+        let key = self.key(session_id);
+        if let Some(cache) = &self.cache {
+            if let Some(record) = cache.get(&key) {
+                counter!("session_cache_hits").increment(1);
+                return Ok(Some(record));
+            }
+            counter!("session_cache_misses").increment(1);
+        }
 
         let Some(value) = self
-            .db
-            .call(|conn| {
-                Ok(conn
-                    .query_row(
-                        r#"select value from sessions where id = ?1"#,
-                        (session_id,),
-                        |row| row.get::<_, String>(0),
-                    )
-                    .optional())
-            })
+            .storage
+            .load_session(&key)
             .await
             .map_err(|err| Error::Backend(err.to_string()))?
-            .map_err(|err| Error::Backend(err.to_string()))?
         else {
             return Ok(None);
         };
@@ -96,6 +288,10 @@ impl SessionStore for SqliteSessionStore {
         let session: Record =
             serde_json::from_str(&value).map_err(|err| Error::Backend(err.to_string()))?;
 
+        if let Some(cache) = &self.cache {
+            cache.insert(key, &session);
+        }
+
         Ok(Some(session))
     }
 
@@ -103,16 +299,16 @@ impl SessionStore for SqliteSessionStore {
     ///
     /// If the session exists, it is removed from the store.
     async fn delete(&self, session_id: &Id) -> Result<()> {
-        let session_id = session_id.to_string();
-        let _n_deleted = self
-            .db
-            .call(move |conn| {
-                Ok(conn.execute(r#"delete from sessions where id = ?1"#, (session_id,)))
-            })
+        let key = self.key(session_id);
+        self.storage
+            .delete_session(&key)
             .await
-            .map_err(|err| Error::Backend(err.to_string()))?
             .map_err(|err| Error::Backend(err.to_string()))?;
 
+        if let Some(cache) = &self.cache {
+            cache.remove(&key);
+        }
+
         Ok(())
     }
 }
@@ -120,65 +316,140 @@ impl SessionStore for SqliteSessionStore {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::storage::test_backends;
     use std::collections::HashMap;
     use tower_sessions::cookie::time::OffsetDateTime;
 
     #[tokio::test]
     async fn test_session_lifecycle() {
-        let db = Connection::open(":memory:").await.unwrap();
-        let store = SqliteSessionStore::new(db);
-        store.init().await.unwrap();
-
+        for store in test_backends()
+            .await
+            .into_iter()
+            .map(StorageSessionStore::new)
         {
-            let mut session = Record {
-                id: Id::default(),
-                data: HashMap::default(),
-                expiry_date: OffsetDateTime::now_utc(),
-            };
+            {
+                let mut session = Record {
+                    id: Id::default(),
+                    data: HashMap::default(),
+                    expiry_date: OffsetDateTime::now_utc(),
+                };
+
+                store.create(&mut session).await.unwrap();
+                let loaded = store.load(&session.id).await.unwrap().unwrap();
+                assert_eq!(loaded.id, session.id);
+                store.delete(&loaded.id).await.unwrap();
+                assert!(store.load(&session.id).await.unwrap().is_none());
+            }
+
+            {
+                let mut session = Record {
+                    id: Id::default(),
+                    data: HashMap::default(),
+                    expiry_date: OffsetDateTime::now_utc(),
+                };
+                store.create(&mut session).await.unwrap();
+                store.clear().await.unwrap();
+                assert!(store.load(&session.id).await.unwrap().is_none());
+            }
+        }
+    }
+
+    fn record() -> Record {
+        Record {
+            id: Id::default(),
+            data: HashMap::default(),
+            expiry_date: OffsetDateTime::now_utc(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_session_cache() {
+        for storage in test_backends().await {
+            let store = StorageSessionStore::new(storage.clone())
+                .with_cache(SessionCache::new(Duration::from_secs(60), 2));
 
+            let mut session = record();
             store.create(&mut session).await.unwrap();
-            let loaded = store.load(&session.id).await.unwrap().unwrap();
-            store.delete(&loaded.id).await.unwrap();
+            // Served from the cache without touching the storage.
+            storage.clear_sessions().await.unwrap();
             assert_eq!(
-                store
-                    .db
-                    .call(|conn| {
-                        let exists: usize = conn
-                            .query_row("select exists(select id from sessions)", [], |row| {
-                                row.get(0)
-                            })
-                            .unwrap();
-                        Ok(exists)
-                    })
-                    .await
-                    .unwrap(),
-                0
+                store.load(&session.id).await.unwrap(),
+                Some(session.clone())
             );
-        }
 
-        {
-            let mut session = Record {
-                id: Id::default(),
-                data: HashMap::default(),
-                expiry_date: OffsetDateTime::now_utc(),
-            };
-            store.create(&mut session).await.unwrap();
+            // Deletes and clears go through the cache.
+            store.save(&session).await.unwrap();
+            store.delete(&session.id).await.unwrap();
+            assert_eq!(store.load(&session.id).await.unwrap(), None);
+            store.save(&session).await.unwrap();
             store.clear().await.unwrap();
+            assert_eq!(store.load(&session.id).await.unwrap(), None);
+
+            // The oldest entry is evicted once the cache is full.
+            let mut sessions = [record(), record(), record()];
+            for session in &mut sessions {
+                store.create(session).await.unwrap();
+            }
+            storage.clear_sessions().await.unwrap();
+            assert_eq!(store.load(&sessions[0].id).await.unwrap(), None);
+            assert!(store.load(&sessions[1].id).await.unwrap().is_some());
+            assert!(store.load(&sessions[2].id).await.unwrap().is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_session_namespaces() {
+        for storage in test_backends().await {
+            let cache = SessionCache::new(Duration::from_secs(60), 10);
+            let store = StorageSessionStore::new(storage.clone()).with_cache(cache.clone());
+            let other_store = StorageSessionStore::new(storage.clone())
+                .with_cache(cache)
+                .with_namespace("other");
+
+            let mut session = record();
+            other_store.create(&mut session).await.unwrap();
             assert_eq!(
-                store
-                    .db
-                    .call(|conn| {
-                        let exists: usize = conn
-                            .query_row("select exists(select id from sessions)", [], |row| {
-                                row.get(0)
-                            })
-                            .unwrap();
-                        Ok(exists)
-                    })
-                    .await
-                    .unwrap(),
-                0
+                storage.list_session_ids().await.unwrap(),
+                vec![format!("other/{}", session.id)]
             );
+            assert!(other_store.load(&session.id).await.unwrap().is_some());
+            assert_eq!(store.load(&session.id).await.unwrap(), None);
+
+            // Finding, deleting and clearing only touch the store's own namespace.
+            let mut default_session = record();
+            store.create(&mut default_session).await.unwrap();
+            assert_eq!(store.find_matching(|_| true).await.unwrap().len(), 1);
+            assert_eq!(other_store.find_matching(|_| true).await.unwrap().len(), 1);
+            assert_eq!(store.delete_matching(|_| true).await.unwrap(), 1);
+            assert!(other_store.load(&session.id).await.unwrap().is_some());
+            store.create(&mut default_session).await.unwrap();
+            other_store.clear().await.unwrap();
+            assert_eq!(other_store.load(&session.id).await.unwrap(), None);
+            assert!(store.load(&default_session.id).await.unwrap().is_some());
+
+            // Unless the store spans all namespaces, including in the shared cache.
+            other_store.create(&mut session).await.unwrap();
+            let all_store = store.clone().across_namespaces();
+            assert_eq!(all_store.find_matching(|_| true).await.unwrap().len(), 2);
+            assert_eq!(all_store.delete_matching(|_| true).await.unwrap(), 2);
+            assert_eq!(other_store.load(&session.id).await.unwrap(), None);
+            assert_eq!(store.load(&default_session.id).await.unwrap(), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_session_cache_expiry() {
+        for storage in test_backends().await {
+            let store = StorageSessionStore::new(storage.clone())
+                .with_cache(SessionCache::new(Duration::ZERO, 10));
+
+            let mut session = record();
+            store.create(&mut session).await.unwrap();
+            storage
+                .delete_session(&session.id.to_string())
+                .await
+                .unwrap();
+            assert_eq!(store.load(&session.id).await.unwrap(), None);
         }
     }
 }
@@ -0,0 +1,904 @@
+//! Encryption at rest for credential and session values. Values are sealed with AES-256-GCM and
+//! stored as `enc:v1:<key ID>:<base64 of nonce, ciphertext and tag>`, with the row as associated
+//! data so that values cannot be moved between rows. Credentials are bound to their ID, user and
+//! relying party. Unencrypted values, which are always JSON objects, are still read so that
+//! existing databases can be migrated, until all values have been encrypted.
+
+use super::{
+    AuditEventQuery, AuditHasher, Storage, StorageError, StorageResult, StoredAuditCheckpoint,
+    StoredAuditEvent, StoredCounts, StoredCredential, StoredUser,
+};
+use async_trait::async_trait;
+use base64::{engine::general_purpose, Engine as _};
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use std::{
+    fmt::Display,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+const PREFIX: &str = "enc:v1:";
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// The metadata key recording that all values have been encrypted, after which plaintext values
+/// are rejected, since they can only have been written by someone bypassing the encryption.
+const ALL_ENCRYPTED: &str = "all_values_encrypted";
+
+/// A 256-bit key, identified by a prefix of its SHA-256 hash.
+#[derive(Clone)]
+pub struct StorageKey {
+    id: String,
+    key: [u8; 32],
+}
+
+impl std::fmt::Debug for StorageKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StorageKey").field("id", &self.id).finish()
+    }
+}
+
+impl StorageKey {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self {
+            id: hex::encode(&openssl::sha::sha256(&key)[..8]),
+            key,
+        }
+    }
+
+    /// Reads a key file containing 64 hex digits, as generated by `openssl rand -hex 32`.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| anyhow::anyhow!("reading {}: {err}", path.display()))?;
+        let key = hex::decode(contents.trim())
+            .ok()
+            .and_then(|key| <[u8; 32]>::try_from(key).ok())
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "{} does not contain a storage key of 64 hex digits",
+                    path.display()
+                )
+            })?;
+
+        Ok(Self::new(key))
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn seal(&self, aad: &str, plaintext: &str) -> StorageResult<String> {
+        let mut nonce = [0; NONCE_LEN];
+        let mut tag = [0; TAG_LEN];
+        openssl::rand::rand_bytes(&mut nonce).map_err(encryption_error)?;
+        let ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(&nonce),
+            aad.as_bytes(),
+            plaintext.as_bytes(),
+            &mut tag,
+        )
+        .map_err(encryption_error)?;
+
+        Ok(format!(
+            "{PREFIX}{}:{}",
+            self.id,
+            general_purpose::STANDARD.encode([&nonce[..], &ciphertext, &tag].concat())
+        ))
+    }
+
+    fn open(&self, aad: &str, sealed: &str) -> Option<String> {
+        let sealed = general_purpose::STANDARD.decode(sealed).ok()?;
+        if sealed.len() < NONCE_LEN + TAG_LEN {
+            return None;
+        }
+        let (nonce, rest) = sealed.split_at(NONCE_LEN);
+        let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+        let plaintext = decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(nonce),
+            aad.as_bytes(),
+            ciphertext,
+            tag,
+        )
+        .ok()?;
+
+        String::from_utf8(plaintext).ok()
+    }
+}
+
+fn encryption_error(err: openssl::error::ErrorStack) -> StorageError {
+    StorageError::Backend(format!("encryption failed: {err}"))
+}
+
+/// The key new values are encrypted with, and the keys it replaced, which are only used for
+/// decryption until all values have been re-encrypted.
+#[derive(Debug, Clone)]
+pub struct Keyring {
+    current: StorageKey,
+    previous: Vec<StorageKey>,
+}
+
+impl Keyring {
+    pub fn new(current: StorageKey, previous: Vec<StorageKey>) -> Self {
+        Self { current, previous }
+    }
+
+    pub fn current(&self) -> &StorageKey {
+        &self.current
+    }
+
+    fn find(&self, id: &str) -> Option<&StorageKey> {
+        std::iter::once(&self.current)
+            .chain(&self.previous)
+            .find(|key| key.id == id)
+    }
+
+    fn seal(&self, row: &ValueRow, value: &str) -> StorageResult<String> {
+        self.current.seal(&row.aad(), value)
+    }
+
+    fn open(
+        &self,
+        row: &ValueRow,
+        value: &str,
+        allow_plaintext: bool,
+    ) -> Result<String, DecryptError> {
+        let Some((key_id, sealed)) = parse(value) else {
+            if !allow_plaintext {
+                return Err(DecryptError::Plaintext);
+            }
+            return Ok(value.to_string());
+        };
+        let key = self
+            .find(key_id)
+            .ok_or_else(|| DecryptError::UnknownKey(key_id.to_string()))?;
+
+        key.open(&row.aad(), sealed)
+            .ok_or_else(|| DecryptError::Invalid(key_id.to_string()))
+    }
+}
+
+/// Splits an encrypted value into its key ID and sealed data, or returns none for plaintext.
+fn parse(value: &str) -> Option<(&str, &str)> {
+    value.strip_prefix(PREFIX)?.split_once(':')
+}
+
+#[derive(Debug)]
+enum DecryptError {
+    UnknownKey(String),
+    Invalid(String),
+    /// The value is not encrypted, although all values have been.
+    Plaintext,
+}
+
+/// The row a value is stored in, which it is bound to through the associated data.
+#[derive(Debug, Clone)]
+enum ValueRow {
+    Credential {
+        cred_id: String,
+        user_id: String,
+        relying_party: String,
+    },
+    Session(String),
+}
+
+impl ValueRow {
+    fn credential(credential: &StoredCredential) -> Self {
+        ValueRow::Credential {
+            cred_id: credential.cred_id.clone(),
+            user_id: credential.user_id.clone(),
+            relying_party: credential.relying_party.clone(),
+        }
+    }
+
+    /// None of the parts can contain a colon: credential IDs are base64url, user IDs UUIDs and
+    /// relying party names are restricted to letters, digits and dashes.
+    fn aad(&self) -> String {
+        match self {
+            ValueRow::Credential {
+                cred_id,
+                user_id,
+                relying_party,
+            } => format!("credential:{cred_id}:{user_id}:{relying_party}"),
+            ValueRow::Session(id) => format!("session:{id}"),
+        }
+    }
+}
+
+impl Display for ValueRow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValueRow::Credential { cred_id, .. } => write!(f, "credential {cred_id}"),
+            ValueRow::Session(id) => write!(f, "session {id}"),
+        }
+    }
+}
+
+struct StoredValue {
+    row: ValueRow,
+    value: String,
+}
+
+/// Reads every credential and session value as stored by the backend.
+async fn stored_values(storage: &dyn Storage) -> StorageResult<Vec<StoredValue>> {
+    let mut values = Vec::new();
+    for user in storage.list_users().await? {
+        for credential in storage.list_credentials(&user.id).await? {
+            values.push(StoredValue {
+                row: ValueRow::credential(&credential),
+                value: credential.value,
+            });
+        }
+    }
+    for id in storage.list_session_ids().await? {
+        // The session may have been deleted in the meantime.
+        if let Some(value) = storage.load_session(&id).await? {
+            values.push(StoredValue {
+                row: ValueRow::Session(id),
+                value,
+            });
+        }
+    }
+
+    Ok(values)
+}
+
+/// Whether all values of the unwrapped `storage` have been recorded as encrypted.
+async fn all_encrypted(storage: &dyn Storage) -> StorageResult<bool> {
+    Ok(storage.get_metadata(ALL_ENCRYPTED).await?.is_some())
+}
+
+/// How the values in the database are stored.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ValueStats {
+    pub plaintext: usize,
+    /// Encrypted with the current key.
+    pub current: usize,
+    /// Encrypted with a previous key.
+    pub previous: usize,
+}
+
+impl ValueStats {
+    pub fn needs_reencryption(&self) -> bool {
+        self.plaintext > 0 || self.previous > 0
+    }
+}
+
+/// Checks that every value in the unwrapped `storage` can be decrypted with the given keys, so that
+/// a wrong or missing key is reported at startup instead of on the first login. Once all values
+/// have been encrypted, plaintext values are reported as tampered with. Then backfills the backup
+/// eligibility of encrypted credentials, see [`backfill_backup_eligible`].
+pub async fn check_keys(
+    storage: &dyn Storage,
+    keyring: Option<&Keyring>,
+) -> anyhow::Result<ValueStats> {
+    let all_encrypted = all_encrypted(storage).await?;
+    if all_encrypted && keyring.is_none() {
+        anyhow::bail!("the stored values have been encrypted, but no --storage-key-file is set");
+    }
+
+    let mut stats = ValueStats::default();
+    for StoredValue { row, value } in stored_values(storage).await? {
+        let Some((key_id, _)) = parse(&value) else {
+            if all_encrypted {
+                anyhow::bail!(
+                    "{row} is not encrypted although all stored values have been, it has been tampered with"
+                );
+            }
+            stats.plaintext += 1;
+            continue;
+        };
+        let Some(keyring) = keyring else {
+            anyhow::bail!(
+                "{row} is encrypted with storage key {key_id}, but no --storage-key-file is set"
+            );
+        };
+        match keyring.open(&row, &value, false) {
+            Ok(_) if key_id == keyring.current.id => stats.current += 1,
+            Ok(_) => stats.previous += 1,
+            Err(DecryptError::UnknownKey(key_id)) => anyhow::bail!(
+                "{row} is encrypted with storage key {key_id}, but the configured storage key is {}{}; pass the key it was encrypted with as --storage-key-file or --previous-storage-key-file",
+                keyring.current.id,
+                keyring
+                    .previous
+                    .iter()
+                    .map(|key| format!(" (previous: {})", key.id))
+                    .collect::<String>(),
+            ),
+            Err(DecryptError::Invalid(key_id)) => anyhow::bail!(
+                "{row} cannot be decrypted with storage key {key_id}, it has been corrupted or tampered with"
+            ),
+            Err(DecryptError::Plaintext) => unreachable!("checked above"),
+        }
+    }
+    if let Some(keyring) = keyring {
+        backfill_backup_eligible(storage, keyring).await?;
+    }
+
+    Ok(stats)
+}
+
+/// Sets the backup eligibility of encrypted credentials from their decrypted values, since the
+/// migration adding the column could only read it from plaintext values. Returns the number of
+/// credentials updated.
+async fn backfill_backup_eligible(
+    storage: &dyn Storage,
+    keyring: &Keyring,
+) -> StorageResult<usize> {
+    let mut n_updated = 0;
+    for user in storage.list_users().await? {
+        for credential in storage.list_credentials(&user.id).await? {
+            if parse(&credential.value).is_none() {
+                continue;
+            }
+            let row = ValueRow::credential(&credential);
+            let plaintext = keyring
+                .open(&row, &credential.value, false)
+                .map_err(|err| StorageError::Backend(format!("cannot decrypt {row}: {err:?}")))?;
+            let backup_eligible = serde_json::from_str::<serde_json::Value>(&plaintext)
+                .ok()
+                .and_then(|value| value.pointer("/cred/backup_eligible")?.as_bool())
+                .unwrap_or(false);
+            if backup_eligible != credential.backup_eligible
+                && storage
+                    .set_credential_backup_eligible(&credential.cred_id, backup_eligible)
+                    .await?
+            {
+                n_updated += 1;
+            }
+        }
+    }
+
+    Ok(n_updated)
+}
+
+/// Re-encrypts all plaintext values and values encrypted with a previous key in the unwrapped
+/// `storage` with the current key. Values written concurrently through [`EncryptedStorage`] are
+/// left alone, as they are already encrypted with the current key. Returns the number of values
+/// re-encrypted.
+async fn reencrypt(storage: &dyn Storage, keyring: &Keyring) -> StorageResult<usize> {
+    let mut n_reencrypted = 0;
+    for StoredValue { row, value } in stored_values(storage).await? {
+        if parse(&value).is_some_and(|(key_id, _)| key_id == keyring.current.id) {
+            continue;
+        }
+        let plaintext = keyring
+            .open(&row, &value, true)
+            .map_err(|err| StorageError::Backend(format!("cannot decrypt {row}: {err:?}")))?;
+        let sealed = keyring.seal(&row, &plaintext)?;
+        let replaced = match &row {
+            ValueRow::Credential { cred_id, .. } => {
+                storage
+                    .replace_credential_value(cred_id, &value, &sealed)
+                    .await?
+            }
+            ValueRow::Session(id) => storage.replace_session(id, &value, &sealed).await?,
+        };
+        if replaced {
+            n_reencrypted += 1;
+        }
+    }
+
+    Ok(n_reencrypted)
+}
+
+/// Wraps a backend, encrypting credential and session values before they are written and
+/// decrypting them after they are read.
+#[derive(Debug)]
+pub struct EncryptedStorage {
+    inner: Arc<dyn Storage>,
+    keyring: Keyring,
+    reject_plaintext: AtomicBool,
+}
+
+impl EncryptedStorage {
+    /// Plaintext values are only read if the database has not been recorded as fully encrypted.
+    pub async fn new(inner: Arc<dyn Storage>, keyring: Keyring) -> StorageResult<Self> {
+        Ok(Self {
+            reject_plaintext: AtomicBool::new(all_encrypted(inner.as_ref()).await?),
+            inner,
+            keyring,
+        })
+    }
+
+    /// Re-encrypts all values that are not encrypted with the current key, then records that all
+    /// values are encrypted and rejects plaintext values from then on. Returns the number of values
+    /// re-encrypted.
+    pub async fn reencrypt(&self) -> StorageResult<usize> {
+        let n_reencrypted = reencrypt(self.inner.as_ref(), &self.keyring).await?;
+        self.reject_plaintext().await?;
+
+        Ok(n_reencrypted)
+    }
+
+    /// Records that all values are encrypted, which the caller has checked, and rejects plaintext
+    /// values from then on.
+    pub async fn reject_plaintext(&self) -> StorageResult<()> {
+        if !self.reject_plaintext.load(Ordering::Relaxed) {
+            self.inner.set_metadata(ALL_ENCRYPTED, "true").await?;
+            self.reject_plaintext.store(true, Ordering::Relaxed);
+        }
+
+        Ok(())
+    }
+
+    fn open(&self, row: &ValueRow, value: &str) -> StorageResult<String> {
+        let allow_plaintext = !self.reject_plaintext.load(Ordering::Relaxed);
+        self.keyring
+            .open(row, value, allow_plaintext)
+            .map_err(|err| {
+                StorageError::Backend(match err {
+                    DecryptError::UnknownKey(key_id) => {
+                        format!("{row} is encrypted with unknown storage key {key_id}")
+                    }
+                    DecryptError::Invalid(key_id) => {
+                        format!("{row} cannot be decrypted with storage key {key_id}")
+                    }
+                    DecryptError::Plaintext => {
+                        format!("{row} is not encrypted although all stored values have been")
+                    }
+                })
+            })
+    }
+
+    fn open_credential(&self, credential: StoredCredential) -> StorageResult<StoredCredential> {
+        Ok(StoredCredential {
+            value: self.open(&ValueRow::credential(&credential), &credential.value)?,
+            ..credential
+        })
+    }
+}
+
+#[async_trait]
+impl Storage for EncryptedStorage {
+    async fn migrate(&self) -> anyhow::Result<()> {
+        self.inner.migrate().await
+    }
+
+    async fn find_user(&self, username: &str) -> StorageResult<Option<StoredUser>> {
+        self.inner.find_user(username).await
+    }
+
+    async fn insert_user(&self, user: &StoredUser) -> StorageResult<()> {
+        self.inner.insert_user(user).await
+    }
+
+    async fn list_users(&self) -> StorageResult<Vec<StoredUser>> {
+        self.inner.list_users().await
+    }
+
+    async fn update_user(&self, user: &StoredUser) -> StorageResult<bool> {
+        self.inner.update_user(user).await
+    }
+
+    async fn delete_user(&self, user_id: &str) -> StorageResult<bool> {
+        self.inner.delete_user(user_id).await
+    }
+
+    async fn list_credentials(&self, user_id: &str) -> StorageResult<Vec<StoredCredential>> {
+        self.inner
+            .list_credentials(user_id)
+            .await?
+            .into_iter()
+            .map(|credential| self.open_credential(credential))
+            .collect()
+    }
+
+    async fn get_credential(&self, cred_id: &str) -> StorageResult<Option<StoredCredential>> {
+        self.inner
+            .get_credential(cred_id)
+            .await?
+            .map(|credential| self.open_credential(credential))
+            .transpose()
+    }
+
+    async fn insert_credential(&self, credential: &StoredCredential) -> StorageResult<()> {
+        self.inner
+            .insert_credential(&StoredCredential {
+                value: self
+                    .keyring
+                    .seal(&ValueRow::credential(credential), &credential.value)?,
+                ..credential.clone()
+            })
+            .await
+    }
+
+    async fn update_credential_value(&self, cred_id: &str, value: &str) -> StorageResult<bool> {
+        // The user and relying party the value is bound to are not known to the caller.
+        let Some(credential) = self.inner.get_credential(cred_id).await? else {
+            return Ok(false);
+        };
+        let value = self
+            .keyring
+            .seal(&ValueRow::credential(&credential), value)?;
+        self.inner.update_credential_value(cred_id, &value).await
+    }
+
+    async fn rename_credential(&self, cred_id: &str, name: &str) -> StorageResult<bool> {
+        self.inner.rename_credential(cred_id, name).await
+    }
+
+    async fn set_credential_disabled(&self, cred_id: &str, disabled: bool) -> StorageResult<bool> {
+        self.inner.set_credential_disabled(cred_id, disabled).await
+    }
+
+    async fn set_credential_backup_eligible(
+        &self,
+        cred_id: &str,
+        backup_eligible: bool,
+    ) -> StorageResult<bool> {
+        self.inner
+            .set_credential_backup_eligible(cred_id, backup_eligible)
+            .await
+    }
+
+    async fn delete_credential(&self, cred_id: &str) -> StorageResult<bool> {
+        self.inner.delete_credential(cred_id).await
+    }
+
+    async fn replace_credential_value(
+        &self,
+        cred_id: &str,
+        expected: &str,
+        value: &str,
+    ) -> StorageResult<bool> {
+        // Encryption is randomized, so the stored value has to be compared after decryption.
+        let Some(credential) = self.inner.get_credential(cred_id).await? else {
+            return Ok(false);
+        };
+        let row = ValueRow::credential(&credential);
+        if self.open(&row, &credential.value)? != expected {
+            return Ok(false);
+        }
+        let value = self.keyring.seal(&row, value)?;
+        self.inner
+            .replace_credential_value(cred_id, &credential.value, &value)
+            .await
+    }
+
+    async fn save_session(&self, id: &str, value: &str, expires_at: i64) -> StorageResult<()> {
+        let value = self
+            .keyring
+            .seal(&ValueRow::Session(id.to_string()), value)?;
+        self.inner.save_session(id, &value, expires_at).await
+    }
+
+    async fn load_session(&self, id: &str) -> StorageResult<Option<String>> {
+        self.inner
+            .load_session(id)
+            .await?
+            .map(|value| self.open(&ValueRow::Session(id.to_string()), &value))
+            .transpose()
+    }
+
+    async fn delete_session(&self, id: &str) -> StorageResult<()> {
+        self.inner.delete_session(id).await
+    }
+
+    async fn clear_sessions(&self) -> StorageResult<()> {
+        self.inner.clear_sessions().await
+    }
+
+    async fn list_session_ids(&self) -> StorageResult<Vec<String>> {
+        self.inner.list_session_ids().await
+    }
+
+    async fn replace_session(&self, id: &str, expected: &str, value: &str) -> StorageResult<bool> {
+        let Some(stored) = self.inner.load_session(id).await? else {
+            return Ok(false);
+        };
+        let row = ValueRow::Session(id.to_string());
+        if self.open(&row, &stored)? != expected {
+            return Ok(false);
+        }
+        let value = self.keyring.seal(&row, value)?;
+        self.inner.replace_session(id, &stored, &value).await
+    }
+
+    async fn insert_audit_event(
+        &self,
+        event: &StoredAuditEvent,
+        hasher: AuditHasher,
+    ) -> StorageResult<i64> {
+        self.inner.insert_audit_event(event, hasher).await
+    }
+
+    async fn list_audit_events(
+        &self,
+        query: &AuditEventQuery,
+    ) -> StorageResult<Vec<StoredAuditEvent>> {
+        self.inner.list_audit_events(query).await
+    }
+
+    async fn list_audit_events_after(
+        &self,
+        after: i64,
+        limit: usize,
+    ) -> StorageResult<Vec<StoredAuditEvent>> {
+        self.inner.list_audit_events_after(after, limit).await
+    }
+
+    async fn insert_audit_checkpoint(
+        &self,
+        checkpoint: &StoredAuditCheckpoint,
+    ) -> StorageResult<()> {
+        self.inner.insert_audit_checkpoint(checkpoint).await
+    }
+
+    async fn list_audit_checkpoints(&self) -> StorageResult<Vec<StoredAuditCheckpoint>> {
+        self.inner.list_audit_checkpoints().await
+    }
+
+    async fn count(&self, now: i64) -> StorageResult<StoredCounts> {
+        self.inner.count(now).await
+    }
+
+    async fn get_metadata(&self, key: &str) -> StorageResult<Option<String>> {
+        self.inner.get_metadata(key).await
+    }
+
+    async fn set_metadata(&self, key: &str, value: &str) -> StorageResult<()> {
+        self.inner.set_metadata(key, value).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{memory::MemoryStorage, test_storage, DEFAULT_RELYING_PARTY};
+
+    fn keyring(key: u8, previous: &[u8]) -> Keyring {
+        Keyring::new(
+            StorageKey::new([key; 32]),
+            previous.iter().map(|k| StorageKey::new([*k; 32])).collect(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_storage() {
+        test_storage(
+            &EncryptedStorage::new(Arc::new(MemoryStorage::default()), keyring(1, &[]))
+                .await
+                .unwrap(),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_key_rotation() {
+        let inner: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+        inner
+            .insert_user(&StoredUser {
+                id: "user".to_string(),
+                username: "user".to_string(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        // Written before encryption was enabled.
+        inner
+            .insert_credential(&StoredCredential {
+                cred_id: "Zm9v".to_string(),
+                user_id: "user".to_string(),
+                relying_party: DEFAULT_RELYING_PARTY.to_string(),
+                name: "foo".to_string(),
+                value: r#"{"cred":{}}"#.to_string(),
+                disabled: false,
+                backup_eligible: false,
+            })
+            .await
+            .unwrap();
+        inner.save_session("plain", r#"{"a":1}"#, 0).await.unwrap();
+
+        assert_eq!(
+            check_keys(inner.as_ref(), None).await.unwrap(),
+            ValueStats {
+                plaintext: 2,
+                ..Default::default()
+            }
+        );
+
+        let storage = EncryptedStorage::new(inner.clone(), keyring(1, &[]))
+            .await
+            .unwrap();
+        storage
+            .save_session("sealed", r#"{"a":2}"#, 0)
+            .await
+            .unwrap();
+        let stored = inner.load_session("sealed").await.unwrap().unwrap();
+        assert!(stored.starts_with(PREFIX));
+        assert!(!stored.contains(r#""a""#));
+        // Values are bound to their row.
+        inner.save_session("moved", &stored, 0).await.unwrap();
+        assert!(storage.load_session("moved").await.is_err());
+        inner.delete_session("moved").await.unwrap();
+
+        assert_eq!(
+            reencrypt(inner.as_ref(), &keyring(1, &[])).await.unwrap(),
+            2
+        );
+        assert_eq!(
+            check_keys(inner.as_ref(), Some(&keyring(1, &[])))
+                .await
+                .unwrap(),
+            ValueStats {
+                current: 3,
+                ..Default::default()
+            }
+        );
+
+        // Missing and wrong keys are reported.
+        assert!(check_keys(inner.as_ref(), None).await.is_err());
+        assert!(check_keys(inner.as_ref(), Some(&keyring(2, &[])))
+            .await
+            .is_err());
+
+        // Rotate to a new key.
+        let rotated = keyring(2, &[1]);
+        assert_eq!(
+            check_keys(inner.as_ref(), Some(&rotated)).await.unwrap(),
+            ValueStats {
+                previous: 3,
+                ..Default::default()
+            }
+        );
+        assert_eq!(reencrypt(inner.as_ref(), &rotated).await.unwrap(), 3);
+        assert_eq!(reencrypt(inner.as_ref(), &rotated).await.unwrap(), 0);
+        assert_eq!(
+            check_keys(inner.as_ref(), Some(&keyring(2, &[])))
+                .await
+                .unwrap()
+                .current,
+            3
+        );
+
+        let storage = EncryptedStorage::new(inner, keyring(2, &[])).await.unwrap();
+        assert_eq!(
+            storage.get_credential("Zm9v").await.unwrap().unwrap().value,
+            r#"{"cred":{}}"#
+        );
+        assert_eq!(
+            storage.load_session("plain").await.unwrap().unwrap(),
+            r#"{"a":1}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_plaintext_rejected_once_encrypted() {
+        let inner: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+        inner.save_session("plain", r#"{"a":1}"#, 0).await.unwrap();
+
+        let storage = EncryptedStorage::new(inner.clone(), keyring(1, &[]))
+            .await
+            .unwrap();
+        assert!(storage.load_session("plain").await.is_ok());
+        assert_eq!(storage.reencrypt().await.unwrap(), 1);
+
+        // A plaintext value written behind the encryption's back is refused, also after a restart.
+        inner.save_session("forged", r#"{"a":2}"#, 0).await.unwrap();
+        assert!(storage.load_session("forged").await.is_err());
+        let storage = EncryptedStorage::new(inner.clone(), keyring(1, &[]))
+            .await
+            .unwrap();
+        assert!(storage.load_session("forged").await.is_err());
+        assert!(check_keys(inner.as_ref(), Some(&keyring(1, &[])))
+            .await
+            .is_err());
+
+        // Disabling encryption is refused as well.
+        inner.delete_session("forged").await.unwrap();
+        assert!(check_keys(inner.as_ref(), None).await.is_err());
+        assert!(check_keys(inner.as_ref(), Some(&keyring(1, &[])))
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_credentials_bound_to_user_and_relying_party() {
+        let inner: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+        let storage = EncryptedStorage::new(inner.clone(), keyring(1, &[]))
+            .await
+            .unwrap();
+        for id in ["alice", "mallory"] {
+            inner
+                .insert_user(&StoredUser {
+                    id: id.to_string(),
+                    username: id.to_string(),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+        }
+        let credential = StoredCredential {
+            cred_id: "Zm9v".to_string(),
+            user_id: "alice".to_string(),
+            relying_party: DEFAULT_RELYING_PARTY.to_string(),
+            name: "foo".to_string(),
+            value: r#"{"cred":{}}"#.to_string(),
+            disabled: false,
+            backup_eligible: false,
+        };
+        storage.insert_credential(&credential).await.unwrap();
+        let sealed = inner.get_credential("Zm9v").await.unwrap().unwrap().value;
+        assert!(storage.get_credential("Zm9v").await.is_ok());
+
+        // The sealed value cannot be moved to another user or relying party.
+        for moved in [
+            StoredCredential {
+                user_id: "mallory".to_string(),
+                ..credential.clone()
+            },
+            StoredCredential {
+                relying_party: "other".to_string(),
+                ..credential.clone()
+            },
+        ] {
+            inner.delete_credential("Zm9v").await.unwrap();
+            inner
+                .insert_credential(&StoredCredential {
+                    value: sealed.clone(),
+                    ..moved
+                })
+                .await
+                .unwrap();
+            assert!(storage.get_credential("Zm9v").await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_backup_eligible_backfilled() {
+        let inner: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+        let storage = EncryptedStorage::new(inner.clone(), keyring(1, &[]))
+            .await
+            .unwrap();
+        inner
+            .insert_user(&StoredUser {
+                id: "user".to_string(),
+                username: "user".to_string(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        // Encrypted before the column was added, which the migration leaves false.
+        for (cred_id, value) in [
+            ("c3luY2Vk", r#"{"cred":{"backup_eligible":true}}"#),
+            ("Ym91bmQ", r#"{"cred":{"backup_eligible":false}}"#),
+        ] {
+            storage
+                .insert_credential(&StoredCredential {
+                    cred_id: cred_id.to_string(),
+                    user_id: "user".to_string(),
+                    relying_party: DEFAULT_RELYING_PARTY.to_string(),
+                    name: cred_id.to_string(),
+                    value: value.to_string(),
+                    disabled: false,
+                    backup_eligible: false,
+                })
+                .await
+                .unwrap();
+        }
+
+        check_keys(inner.as_ref(), Some(&keyring(1, &[])))
+            .await
+            .unwrap();
+        let backup_eligible = |cred_id| {
+            let inner = inner.clone();
+            async move {
+                inner
+                    .get_credential(cred_id)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .backup_eligible
+            }
+        };
+        assert!(backup_eligible("c3luY2Vk").await);
+        assert!(!backup_eligible("Ym91bmQ").await);
+    }
+}
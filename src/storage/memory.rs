@@ -0,0 +1,371 @@
+use super::{
+    AuditEventQuery, AuditHasher, Storage, StorageError, StorageResult, StoredAuditCheckpoint,
+    StoredAuditEvent, StoredCounts, StoredCredential, StoredUser,
+};
+use async_trait::async_trait;
+use std::{collections::HashMap, sync::Mutex};
+
+#[derive(Debug, Default)]
+struct MemoryState {
+    users: Vec<StoredUser>,
+    credentials: Vec<StoredCredential>,
+    /// Session values with their expiry.
+    sessions: HashMap<String, (String, i64)>,
+    audit_events: Vec<StoredAuditEvent>,
+    audit_checkpoints: Vec<StoredAuditCheckpoint>,
+    metadata: HashMap<String, String>,
+}
+
+/// A storage backend that keeps all state in memory and loses it when the program exits. This is
+/// intended for tests and local development.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    state: Mutex<MemoryState>,
+}
+
+impl MemoryStorage {
+    fn with_state<T>(&self, f: impl FnOnce(&mut MemoryState) -> T) -> T {
+        f(&mut self.state.lock().expect("memory storage lock poisoned"))
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn migrate(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn find_user(&self, username: &str) -> StorageResult<Option<StoredUser>> {
+        Ok(self.with_state(|state| state.users.iter().find(|u| u.username == username).cloned()))
+    }
+
+    async fn insert_user(&self, user: &StoredUser) -> StorageResult<()> {
+        self.with_state(|state| {
+            if state
+                .users
+                .iter()
+                .any(|u| u.id == user.id || u.username == user.username)
+            {
+                return Err(StorageError::Constraint);
+            }
+
+            state.users.push(user.clone());
+            Ok(())
+        })
+    }
+
+    async fn list_users(&self) -> StorageResult<Vec<StoredUser>> {
+        Ok(self.with_state(|state| state.users.clone()))
+    }
+
+    async fn update_user(&self, user: &StoredUser) -> StorageResult<bool> {
+        Ok(self.with_state(|state| {
+            state
+                .users
+                .iter_mut()
+                .find(|u| u.id == user.id)
+                .map(|u| {
+                    u.display_name = user.display_name.clone();
+                    u.email = user.email.clone();
+                    u.groups = user.groups.clone();
+                })
+                .is_some()
+        }))
+    }
+
+    async fn delete_user(&self, user_id: &str) -> StorageResult<bool> {
+        Ok(self.with_state(|state| {
+            state.credentials.retain(|c| c.user_id != user_id);
+            let n_users = state.users.len();
+            state.users.retain(|u| u.id != user_id);
+            state.users.len() != n_users
+        }))
+    }
+
+    async fn list_credentials(&self, user_id: &str) -> StorageResult<Vec<StoredCredential>> {
+        Ok(self.with_state(|state| {
+            state
+                .credentials
+                .iter()
+                .filter(|c| c.user_id == user_id)
+                .cloned()
+                .collect()
+        }))
+    }
+
+    async fn get_credential(&self, cred_id: &str) -> StorageResult<Option<StoredCredential>> {
+        Ok(self.with_state(|state| {
+            state
+                .credentials
+                .iter()
+                .find(|c| c.cred_id == cred_id)
+                .cloned()
+        }))
+    }
+
+    async fn insert_credential(&self, credential: &StoredCredential) -> StorageResult<()> {
+        self.with_state(|state| {
+            if state
+                .credentials
+                .iter()
+                .any(|c| c.cred_id == credential.cred_id)
+            {
+                return Err(StorageError::DuplicateCredential);
+            }
+
+            if !state.users.iter().any(|u| u.id == credential.user_id)
+                || state.credentials.iter().any(|c| {
+                    c.user_id == credential.user_id
+                        && c.relying_party == credential.relying_party
+                        && c.name == credential.name
+                })
+            {
+                return Err(StorageError::Constraint);
+            }
+
+            state.credentials.push(credential.clone());
+            Ok(())
+        })
+    }
+
+    async fn update_credential_value(&self, cred_id: &str, value: &str) -> StorageResult<bool> {
+        Ok(self.with_state(|state| {
+            state
+                .credentials
+                .iter_mut()
+                .find(|c| c.cred_id == cred_id)
+                .map(|c| c.value = value.to_string())
+                .is_some()
+        }))
+    }
+
+    async fn rename_credential(&self, cred_id: &str, name: &str) -> StorageResult<bool> {
+        self.with_state(|state| {
+            let Some((user_id, relying_party)) = state
+                .credentials
+                .iter()
+                .find(|c| c.cred_id == cred_id)
+                .map(|c| (c.user_id.clone(), c.relying_party.clone()))
+            else {
+                return Ok(false);
+            };
+
+            if state.credentials.iter().any(|c| {
+                c.user_id == user_id
+                    && c.relying_party == relying_party
+                    && c.name == name
+                    && c.cred_id != cred_id
+            }) {
+                return Err(StorageError::Constraint);
+            }
+
+            state
+                .credentials
+                .iter_mut()
+                .filter(|c| c.cred_id == cred_id)
+                .for_each(|c| c.name = name.to_string());
+            Ok(true)
+        })
+    }
+
+    async fn set_credential_disabled(&self, cred_id: &str, disabled: bool) -> StorageResult<bool> {
+        Ok(self.with_state(|state| {
+            state
+                .credentials
+                .iter_mut()
+                .find(|c| c.cred_id == cred_id)
+                .map(|c| c.disabled = disabled)
+                .is_some()
+        }))
+    }
+
+    async fn set_credential_backup_eligible(
+        &self,
+        cred_id: &str,
+        backup_eligible: bool,
+    ) -> StorageResult<bool> {
+        Ok(self.with_state(|state| {
+            state
+                .credentials
+                .iter_mut()
+                .find(|c| c.cred_id == cred_id)
+                .map(|c| c.backup_eligible = backup_eligible)
+                .is_some()
+        }))
+    }
+
+    async fn delete_credential(&self, cred_id: &str) -> StorageResult<bool> {
+        Ok(self.with_state(|state| {
+            let n_credentials = state.credentials.len();
+            state.credentials.retain(|c| c.cred_id != cred_id);
+            state.credentials.len() != n_credentials
+        }))
+    }
+
+    async fn replace_credential_value(
+        &self,
+        cred_id: &str,
+        expected: &str,
+        value: &str,
+    ) -> StorageResult<bool> {
+        Ok(self.with_state(|state| {
+            state
+                .credentials
+                .iter_mut()
+                .find(|c| c.cred_id == cred_id && c.value == expected)
+                .map(|c| c.value = value.to_string())
+                .is_some()
+        }))
+    }
+
+    async fn save_session(&self, id: &str, value: &str, expires_at: i64) -> StorageResult<()> {
+        self.with_state(|state| {
+            state
+                .sessions
+                .insert(id.to_string(), (value.to_string(), expires_at))
+        });
+        Ok(())
+    }
+
+    async fn load_session(&self, id: &str) -> StorageResult<Option<String>> {
+        Ok(self.with_state(|state| state.sessions.get(id).map(|(value, _)| value.clone())))
+    }
+
+    async fn delete_session(&self, id: &str) -> StorageResult<()> {
+        self.with_state(|state| state.sessions.remove(id));
+        Ok(())
+    }
+
+    async fn clear_sessions(&self) -> StorageResult<()> {
+        self.with_state(|state| state.sessions.clear());
+        Ok(())
+    }
+
+    async fn list_session_ids(&self) -> StorageResult<Vec<String>> {
+        Ok(self.with_state(|state| state.sessions.keys().cloned().collect()))
+    }
+
+    async fn replace_session(&self, id: &str, expected: &str, value: &str) -> StorageResult<bool> {
+        Ok(self.with_state(|state| {
+            state
+                .sessions
+                .get_mut(id)
+                .filter(|(v, _)| *v == expected)
+                .map(|(v, _)| *v = value.to_string())
+                .is_some()
+        }))
+    }
+
+    async fn insert_audit_event(
+        &self,
+        event: &StoredAuditEvent,
+        hasher: AuditHasher,
+    ) -> StorageResult<i64> {
+        Ok(self.with_state(|state| {
+            let mut event = StoredAuditEvent {
+                id: state.audit_events.len() as i64 + 1,
+                prev_hash: state.audit_events.iter().rev().find_map(|e| e.hash.clone()),
+                ..event.clone()
+            };
+            event.hash = Some(hasher(&event));
+
+            let id = event.id;
+            state.audit_events.push(event);
+            id
+        }))
+    }
+
+    async fn list_audit_events(
+        &self,
+        query: &AuditEventQuery,
+    ) -> StorageResult<Vec<StoredAuditEvent>> {
+        Ok(self.with_state(|state| {
+            state
+                .audit_events
+                .iter()
+                .rev()
+                .filter(|e| query.before.is_none_or(|before| e.id < before))
+                .filter(|e| query.username.is_none() || e.username == query.username)
+                .take(query.limit)
+                .cloned()
+                .collect()
+        }))
+    }
+
+    async fn list_audit_events_after(
+        &self,
+        after: i64,
+        limit: usize,
+    ) -> StorageResult<Vec<StoredAuditEvent>> {
+        Ok(self.with_state(|state| {
+            state
+                .audit_events
+                .iter()
+                .filter(|e| e.id > after)
+                .take(limit)
+                .cloned()
+                .collect()
+        }))
+    }
+
+    async fn insert_audit_checkpoint(
+        &self,
+        checkpoint: &StoredAuditCheckpoint,
+    ) -> StorageResult<()> {
+        self.with_state(|state| state.audit_checkpoints.push(checkpoint.clone()));
+        Ok(())
+    }
+
+    async fn list_audit_checkpoints(&self) -> StorageResult<Vec<StoredAuditCheckpoint>> {
+        Ok(self.with_state(|state| state.audit_checkpoints.clone()))
+    }
+
+    async fn count(&self, now: i64) -> StorageResult<StoredCounts> {
+        Ok(self.with_state(|state| {
+            let mut counts = StoredCounts {
+                users: state.users.len(),
+                ..Default::default()
+            };
+            for (_, expires_at) in state.sessions.values() {
+                if *expires_at > now {
+                    counts.active_sessions += 1;
+                } else {
+                    counts.expired_sessions += 1;
+                }
+            }
+            counts.users_without_credentials = state
+                .users
+                .iter()
+                .filter(|u| !state.credentials.iter().any(|c| c.user_id == u.id))
+                .count();
+            for c in &state.credentials {
+                *counts
+                    .credentials
+                    .entry((c.relying_party.clone(), c.backup_eligible))
+                    .or_default() += 1;
+            }
+
+            counts
+        }))
+    }
+
+    async fn get_metadata(&self, key: &str) -> StorageResult<Option<String>> {
+        Ok(self.with_state(|state| state.metadata.get(key).cloned()))
+    }
+
+    async fn set_metadata(&self, key: &str, value: &str) -> StorageResult<()> {
+        self.with_state(|state| state.metadata.insert(key.to_string(), value.to_string()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::test_storage;
+
+    #[tokio::test]
+    async fn test_memory_storage() {
+        test_storage(&MemoryStorage::default()).await;
+    }
+}
@@ -0,0 +1,634 @@
+pub mod encrypted;
+#[cfg(any(test, feature = "memory"))]
+pub mod memory;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+use async_trait::async_trait;
+use std::{collections::BTreeMap, fmt::Display, path::Path, sync::Arc};
+
+/// The name of the SQLite database in the state directory, used when no database URL is given.
+pub const SQLITE_FILE_NAME: &str = "webauthn-tiny.db";
+
+/// The relying party of credentials registered before several relying parties were supported,
+/// which is the one configured with `--rp-id`.
+pub const DEFAULT_RELYING_PARTY: &str = "default";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StoredUser {
+    pub id: String,
+    pub username: String,
+    pub display_name: Option<String>,
+    pub email: Option<String>,
+    pub groups: Vec<String>,
+}
+
+/// A credential as it is persisted. The value is the serialized passkey, which the storage
+/// backends treat as opaque.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredCredential {
+    /// The credential ID as unpadded base64url.
+    pub cred_id: String,
+    pub user_id: String,
+    /// The name of the relying party the credential was registered with.
+    pub relying_party: String,
+    pub name: String,
+    pub value: String,
+    pub disabled: bool,
+    /// Whether the credential is a synced passkey. It is stored next to the value so that
+    /// credentials can be counted without reading their values.
+    pub backup_eligible: bool,
+}
+
+/// Counts of the stored sessions, users and credentials, exported as gauges.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StoredCounts {
+    pub active_sessions: usize,
+    pub expired_sessions: usize,
+    pub users: usize,
+    pub users_without_credentials: usize,
+    /// Credentials by relying party and whether they are synced, i.e. backup eligible.
+    pub credentials: BTreeMap<(String, bool), usize>,
+}
+
+/// An entry of the append-only audit log. The timestamp is in milliseconds since the Unix epoch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredAuditEvent {
+    /// Assigned by the backend on insertion, increasing with every event.
+    pub id: i64,
+    pub timestamp: i64,
+    pub kind: String,
+    pub success: bool,
+    pub reason: Option<String>,
+    pub username: Option<String>,
+    pub credential: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    /// The hash of the previous chained event, or none for the first event of the chain.
+    pub prev_hash: Option<String>,
+    /// None for events recorded before the audit log was chained.
+    pub hash: Option<String>,
+}
+
+/// Computes the chain hash of an audit event, including its `prev_hash`.
+pub type AuditHasher = fn(&StoredAuditEvent) -> String;
+
+/// A signed statement that the audit chain ended with the given event and hash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredAuditCheckpoint {
+    pub event_id: i64,
+    pub hash: String,
+    pub timestamp: i64,
+    pub signature: String,
+}
+
+/// Selects a page of audit events, newest first.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuditEventQuery {
+    /// Only return events with an ID lower than this.
+    pub before: Option<i64>,
+    pub limit: usize,
+    pub username: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum StorageError {
+    /// A credential with the same credential ID already exists.
+    DuplicateCredential,
+    /// Any other uniqueness or integrity constraint was violated.
+    Constraint,
+    Backend(String),
+}
+
+impl Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageError::DuplicateCredential => write!(f, "credential already exists"),
+            StorageError::Constraint => write!(f, "constraint violation"),
+            StorageError::Backend(err) => write!(f, "storage backend error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+pub type StorageResult<T> = Result<T, StorageError>;
+
+/// Persistence for users, credentials and sessions. Implementations must enforce that usernames
+/// and credential IDs are unique, and that credential names are unique per user and relying
+/// party.
+#[async_trait]
+pub trait Storage: Send + Sync + std::fmt::Debug {
+    /// Brings the backend's schema up to date.
+    async fn migrate(&self) -> anyhow::Result<()>;
+
+    async fn find_user(&self, username: &str) -> StorageResult<Option<StoredUser>>;
+    async fn insert_user(&self, user: &StoredUser) -> StorageResult<()>;
+    async fn list_users(&self) -> StorageResult<Vec<StoredUser>>;
+    /// Updates the profile fields of the user with the given ID. Returns whether the user was
+    /// found.
+    async fn update_user(&self, user: &StoredUser) -> StorageResult<bool>;
+    /// Deletes the user together with all of their credentials. Returns whether the user was
+    /// found.
+    async fn delete_user(&self, user_id: &str) -> StorageResult<bool>;
+
+    async fn list_credentials(&self, user_id: &str) -> StorageResult<Vec<StoredCredential>>;
+    async fn get_credential(&self, cred_id: &str) -> StorageResult<Option<StoredCredential>>;
+    async fn insert_credential(&self, credential: &StoredCredential) -> StorageResult<()>;
+    /// Returns whether a credential with the given ID was found.
+    async fn update_credential_value(&self, cred_id: &str, value: &str) -> StorageResult<bool>;
+    /// Fails with a constraint violation if the user has another credential with the same name at
+    /// the same relying party. Returns whether a credential with the given ID was found.
+    async fn rename_credential(&self, cred_id: &str, name: &str) -> StorageResult<bool>;
+    /// Returns whether a credential with the given ID was found.
+    async fn set_credential_disabled(&self, cred_id: &str, disabled: bool) -> StorageResult<bool>;
+    /// Sets the flag that is otherwise only written on insert, for rows whose value could not be
+    /// read when the column was added. Returns whether a credential with the given ID was found.
+    async fn set_credential_backup_eligible(
+        &self,
+        cred_id: &str,
+        backup_eligible: bool,
+    ) -> StorageResult<bool>;
+    /// Returns whether a credential with the given ID was found.
+    async fn delete_credential(&self, cred_id: &str) -> StorageResult<bool>;
+    /// Updates the value only if it still equals `expected`. Returns whether it was updated.
+    async fn replace_credential_value(
+        &self,
+        cred_id: &str,
+        expected: &str,
+        value: &str,
+    ) -> StorageResult<bool>;
+
+    /// Inserts or replaces a session. The expiry, in seconds since the Unix epoch, is stored next
+    /// to the value so that sessions can be counted without reading their values.
+    async fn save_session(&self, id: &str, value: &str, expires_at: i64) -> StorageResult<()>;
+    async fn load_session(&self, id: &str) -> StorageResult<Option<String>>;
+    async fn delete_session(&self, id: &str) -> StorageResult<()>;
+    async fn clear_sessions(&self) -> StorageResult<()>;
+    async fn list_session_ids(&self) -> StorageResult<Vec<String>>;
+    /// Updates the value only if it still equals `expected`. Returns whether it was updated.
+    async fn replace_session(&self, id: &str, expected: &str, value: &str) -> StorageResult<bool>;
+
+    /// Appends an event to the audit log, ignoring its ID and hashes. The event is linked to the
+    /// hash of the latest chained event and then hashed with `hasher`, atomically with respect to
+    /// other writers so that the chain cannot fork. Returns the ID assigned to it.
+    async fn insert_audit_event(
+        &self,
+        event: &StoredAuditEvent,
+        hasher: AuditHasher,
+    ) -> StorageResult<i64>;
+    async fn list_audit_events(
+        &self,
+        query: &AuditEventQuery,
+    ) -> StorageResult<Vec<StoredAuditEvent>>;
+    /// Returns up to `limit` events with an ID greater than `after`, oldest first.
+    async fn list_audit_events_after(
+        &self,
+        after: i64,
+        limit: usize,
+    ) -> StorageResult<Vec<StoredAuditEvent>>;
+    async fn insert_audit_checkpoint(
+        &self,
+        checkpoint: &StoredAuditCheckpoint,
+    ) -> StorageResult<()>;
+    /// Returns all checkpoints, oldest first.
+    async fn list_audit_checkpoints(&self) -> StorageResult<Vec<StoredAuditCheckpoint>>;
+
+    /// Counts the stored rows without loading them. Sessions expiring at or before `now`, in
+    /// seconds since the Unix epoch, are counted as expired.
+    async fn count(&self, now: i64) -> StorageResult<StoredCounts>;
+
+    /// Returns a value the program records about the database itself, such as whether all values
+    /// are encrypted.
+    async fn get_metadata(&self, key: &str) -> StorageResult<Option<String>>;
+    /// Inserts or replaces a metadata value.
+    async fn set_metadata(&self, key: &str, value: &str) -> StorageResult<()>;
+}
+
+/// Opens the storage backend for the given database URL and migrates it. Without a URL the SQLite
+/// database in the state directory is used.
+pub async fn connect(
+    database_url: Option<&str>,
+    state_directory: &Path,
+) -> anyhow::Result<Arc<dyn Storage>> {
+    let storage = match database_url {
+        Some(url) => open(url).await?,
+        None => {
+            open(&format!(
+                "sqlite:{}",
+                state_directory.join(SQLITE_FILE_NAME).display()
+            ))
+            .await?
+        }
+    };
+
+    storage.migrate().await?;
+
+    Ok(storage)
+}
+
+/// Returns the path of the SQLite database the given URL refers to, or none if it refers to
+/// another backend.
+#[cfg(feature = "sqlite")]
+pub fn sqlite_path(
+    database_url: Option<&str>,
+    state_directory: &Path,
+) -> Option<std::path::PathBuf> {
+    match database_url {
+        Some(url) => url
+            .strip_prefix("sqlite:")
+            .map(|path| path.strip_prefix("//").unwrap_or(path).into()),
+        None => Some(state_directory.join(SQLITE_FILE_NAME)),
+    }
+}
+
+/// Supported URLs are `sqlite:<path>`, `postgres://...` (or `postgresql://...`) and `memory:`,
+/// depending on the enabled cargo features.
+async fn open(url: &str) -> anyhow::Result<Arc<dyn Storage>> {
+    #[cfg(feature = "sqlite")]
+    if let Some(path) = url.strip_prefix("sqlite:") {
+        let path = path.strip_prefix("//").unwrap_or(path);
+        return Ok(Arc::new(sqlite::SqliteStorage::open(path).await?));
+    }
+
+    #[cfg(feature = "postgres")]
+    if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+        return Ok(Arc::new(postgres::PostgresStorage::connect(url)?));
+    }
+
+    #[cfg(feature = "memory")]
+    if url == "memory:" {
+        return Ok(Arc::new(memory::MemoryStorage::default()));
+    }
+
+    anyhow::bail!("unsupported database URL: {url}")
+}
+
+/// The migrated backends that tests of the layers above the storage run against: the default
+/// SQLite backend, if enabled, and the in-memory one.
+#[cfg(test)]
+pub async fn test_backends() -> Vec<Arc<dyn Storage>> {
+    let backends: Vec<Arc<dyn Storage>> = vec![
+        #[cfg(feature = "sqlite")]
+        Arc::new(sqlite::SqliteStorage::open(":memory:").await.unwrap()),
+        Arc::new(memory::MemoryStorage::default()),
+    ];
+
+    for backend in &backends {
+        backend.migrate().await.unwrap();
+    }
+
+    backends
+}
+
+/// Exercises the behavior every storage backend must provide.
+#[cfg(test)]
+pub async fn test_storage(storage: &dyn Storage) {
+    storage.migrate().await.unwrap();
+    storage.migrate().await.unwrap(); // migrations are idempotent
+
+    assert_eq!(storage.find_user("foo_user").await.unwrap(), None);
+
+    let user = StoredUser {
+        id: "1a8d6f9e-7b6c-4a5e-9f3d-2c1b0a998877".to_string(),
+        username: "foo_user".to_string(),
+        ..Default::default()
+    };
+    storage.insert_user(&user).await.unwrap();
+    assert_eq!(
+        storage.find_user("foo_user").await.unwrap(),
+        Some(user.clone())
+    );
+    assert!(matches!(
+        storage.insert_user(&user).await,
+        Err(StorageError::Constraint)
+    ));
+
+    let user = StoredUser {
+        display_name: Some("Foo User".to_string()),
+        email: Some("foo@example.com".to_string()),
+        groups: vec!["admins".to_string(), "users".to_string()],
+        ..user
+    };
+    assert!(storage.update_user(&user).await.unwrap());
+    assert_eq!(
+        storage.find_user("foo_user").await.unwrap(),
+        Some(user.clone())
+    );
+    assert!(!storage
+        .update_user(&StoredUser {
+            id: "unknown".to_string(),
+            ..user.clone()
+        })
+        .await
+        .unwrap());
+
+    let credential = StoredCredential {
+        cred_id: "Zm9v".to_string(),
+        user_id: user.id.clone(),
+        relying_party: DEFAULT_RELYING_PARTY.to_string(),
+        name: "foo_credential".to_string(),
+        value: r#"{"cred":{}}"#.to_string(),
+        disabled: false,
+        backup_eligible: false,
+    };
+    storage.insert_credential(&credential).await.unwrap();
+    assert!(matches!(
+        storage.insert_credential(&credential).await,
+        Err(StorageError::DuplicateCredential)
+    ));
+    assert!(matches!(
+        storage
+            .insert_credential(&StoredCredential {
+                cred_id: "YmFy".to_string(),
+                ..credential.clone()
+            })
+            .await,
+        Err(StorageError::Constraint)
+    ));
+    assert_eq!(
+        storage.list_credentials(&user.id).await.unwrap(),
+        vec![credential.clone()]
+    );
+
+    assert!(storage
+        .update_credential_value("Zm9v", r#"{"cred":{"counter":1}}"#)
+        .await
+        .unwrap());
+    assert!(storage.set_credential_disabled("Zm9v", true).await.unwrap());
+    assert!(!storage
+        .replace_credential_value("Zm9v", r#"{"cred":{}}"#, r#"{"cred":{"counter":2}}"#)
+        .await
+        .unwrap());
+    assert_eq!(
+        storage.get_credential("Zm9v").await.unwrap(),
+        Some(StoredCredential {
+            value: r#"{"cred":{"counter":1}}"#.to_string(),
+            disabled: true,
+            ..credential.clone()
+        })
+    );
+
+    assert!(storage
+        .replace_credential_value(
+            "Zm9v",
+            r#"{"cred":{"counter":1}}"#,
+            r#"{"cred":{"counter":2}}"#
+        )
+        .await
+        .unwrap());
+    assert_eq!(
+        storage.get_credential("Zm9v").await.unwrap().unwrap().value,
+        r#"{"cred":{"counter":2}}"#
+    );
+
+    storage
+        .insert_credential(&StoredCredential {
+            cred_id: "YmF6".to_string(),
+            name: "baz_credential".to_string(),
+            ..credential.clone()
+        })
+        .await
+        .unwrap();
+    assert!(matches!(
+        storage.rename_credential("YmF6", "foo_credential").await,
+        Err(StorageError::Constraint)
+    ));
+    assert!(storage
+        .rename_credential("YmF6", "renamed_credential")
+        .await
+        .unwrap());
+    assert!(!storage
+        .rename_credential("unknown", "renamed_credential")
+        .await
+        .unwrap());
+    assert_eq!(
+        storage.get_credential("YmF6").await.unwrap().unwrap().name,
+        "renamed_credential"
+    );
+    assert!(storage.delete_credential("YmF6").await.unwrap());
+
+    // Names only have to be unique per relying party.
+    let other_credential = StoredCredential {
+        cred_id: "cXV4".to_string(),
+        relying_party: "other".to_string(),
+        ..credential.clone()
+    };
+    storage.insert_credential(&other_credential).await.unwrap();
+    assert_eq!(storage.list_credentials(&user.id).await.unwrap().len(), 2);
+    storage
+        .insert_user(&StoredUser {
+            id: "2b9e7f0a-8c7d-4b6f-a04e-3d2c1b0a9988".to_string(),
+            username: "bar_user".to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    storage
+        .insert_credential(&StoredCredential {
+            cred_id: "c3luY2Vk".to_string(),
+            name: "synced_credential".to_string(),
+            ..credential.clone()
+        })
+        .await
+        .unwrap();
+    assert!(storage
+        .set_credential_backup_eligible("c3luY2Vk", true)
+        .await
+        .unwrap());
+    storage.save_session("active", "{}", 1_000).await.unwrap();
+    storage.save_session("expired", "{}", 999).await.unwrap();
+    assert_eq!(
+        storage.count(999).await.unwrap(),
+        StoredCounts {
+            active_sessions: 1,
+            expired_sessions: 1,
+            users: 2,
+            users_without_credentials: 1,
+            credentials: [
+                ((DEFAULT_RELYING_PARTY.to_string(), false), 1),
+                ((DEFAULT_RELYING_PARTY.to_string(), true), 1),
+                (("other".to_string(), false), 1),
+            ]
+            .into(),
+        }
+    );
+    storage.clear_sessions().await.unwrap();
+    assert!(storage.delete_credential("c3luY2Vk").await.unwrap());
+    assert!(storage
+        .delete_user("2b9e7f0a-8c7d-4b6f-a04e-3d2c1b0a9988")
+        .await
+        .unwrap());
+    assert_eq!(
+        storage.get_credential("cXV4").await.unwrap(),
+        Some(other_credential)
+    );
+    assert!(storage.delete_credential("cXV4").await.unwrap());
+
+    assert!(storage.delete_credential("Zm9v").await.unwrap());
+    assert!(!storage.delete_credential("Zm9v").await.unwrap());
+    assert!(!storage
+        .set_credential_disabled("Zm9v", false)
+        .await
+        .unwrap());
+    assert!(!storage
+        .set_credential_backup_eligible("Zm9v", true)
+        .await
+        .unwrap());
+    assert_eq!(storage.get_credential("Zm9v").await.unwrap(), None);
+
+    storage.insert_credential(&credential).await.unwrap();
+    assert_eq!(storage.list_users().await.unwrap(), vec![user.clone()]);
+    assert!(storage.delete_user(&user.id).await.unwrap());
+    assert!(!storage.delete_user(&user.id).await.unwrap());
+    assert_eq!(storage.list_users().await.unwrap(), vec![]);
+    assert_eq!(storage.get_credential("Zm9v").await.unwrap(), None);
+
+    storage.save_session("session", "{}", 0).await.unwrap();
+    storage
+        .save_session("session", r#"{"a":1}"#, 0)
+        .await
+        .unwrap();
+    assert_eq!(storage.list_session_ids().await.unwrap(), vec!["session"]);
+    assert!(!storage
+        .replace_session("session", "{}", r#"{"a":2}"#)
+        .await
+        .unwrap());
+    assert!(storage
+        .replace_session("session", r#"{"a":1}"#, r#"{"a":2}"#)
+        .await
+        .unwrap());
+    assert!(storage
+        .replace_session("session", r#"{"a":2}"#, r#"{"a":1}"#)
+        .await
+        .unwrap());
+    assert_eq!(
+        storage.load_session("session").await.unwrap().as_deref(),
+        Some(r#"{"a":1}"#)
+    );
+    storage.delete_session("session").await.unwrap();
+    assert_eq!(storage.load_session("session").await.unwrap(), None);
+    storage.save_session("session", "{}", 0).await.unwrap();
+    storage.clear_sessions().await.unwrap();
+    assert_eq!(storage.load_session("session").await.unwrap(), None);
+
+    let event = StoredAuditEvent {
+        id: 0,
+        timestamp: 1_700_000_000_000,
+        kind: "authentication".to_string(),
+        success: true,
+        reason: None,
+        username: Some("foo_user".to_string()),
+        credential: Some("Zm9v".to_string()),
+        ip: Some("::1".to_string()),
+        user_agent: Some("curl/8.0".to_string()),
+        prev_hash: None,
+        hash: None,
+    };
+    let hasher: AuditHasher = |event| {
+        format!(
+            "{}>{}",
+            event.prev_hash.as_deref().unwrap_or_default(),
+            event.username.as_deref().unwrap_or_default()
+        )
+    };
+    let mut ids = Vec::new();
+    for username in ["foo_user", "bar_user", "foo_user"] {
+        ids.push(
+            storage
+                .insert_audit_event(
+                    &StoredAuditEvent {
+                        username: Some(username.to_string()),
+                        ..event.clone()
+                    },
+                    hasher,
+                )
+                .await
+                .unwrap(),
+        );
+    }
+    assert!(ids.windows(2).all(|ids| ids[0] < ids[1]));
+
+    let chain = storage.list_audit_events_after(0, 10).await.unwrap();
+    assert_eq!(chain.iter().map(|e| e.id).collect::<Vec<_>>(), ids);
+    assert_eq!(chain[0].prev_hash, None);
+    assert_eq!(chain[0].hash.as_deref(), Some(">foo_user"));
+    assert_eq!(chain[1].prev_hash, chain[0].hash);
+    assert_eq!(
+        chain[2].hash.as_deref(),
+        Some(">foo_user>bar_user>foo_user")
+    );
+    assert_eq!(
+        storage.list_audit_events_after(ids[0], 1).await.unwrap(),
+        vec![chain[1].clone()]
+    );
+
+    let events = storage
+        .list_audit_events(&AuditEventQuery {
+            limit: 2,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(
+        events.iter().map(|e| e.id).collect::<Vec<_>>(),
+        vec![ids[2], ids[1]]
+    );
+    assert_eq!(events[0], chain[2]);
+    assert_eq!(
+        events[0],
+        StoredAuditEvent {
+            id: ids[2],
+            prev_hash: chain[1].hash.clone(),
+            hash: chain[2].hash.clone(),
+            ..event.clone()
+        }
+    );
+    let events = storage
+        .list_audit_events(&AuditEventQuery {
+            before: Some(ids[1]),
+            limit: 2,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(
+        events.iter().map(|e| e.id).collect::<Vec<_>>(),
+        vec![ids[0]]
+    );
+    let events = storage
+        .list_audit_events(&AuditEventQuery {
+            limit: 10,
+            username: Some("foo_user".to_string()),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(
+        events.iter().map(|e| e.id).collect::<Vec<_>>(),
+        vec![ids[2], ids[0]]
+    );
+
+    assert_eq!(storage.list_audit_checkpoints().await.unwrap(), vec![]);
+    let checkpoint = StoredAuditCheckpoint {
+        event_id: ids[2],
+        hash: chain[2].hash.clone().unwrap(),
+        timestamp: 1_700_000_000_000,
+        signature: "c2lnbmF0dXJl".to_string(),
+    };
+    storage.insert_audit_checkpoint(&checkpoint).await.unwrap();
+    assert_eq!(
+        storage.list_audit_checkpoints().await.unwrap(),
+        vec![checkpoint]
+    );
+
+    assert_eq!(storage.get_metadata("foo").await.unwrap(), None);
+    storage.set_metadata("foo", "1").await.unwrap();
+    storage.set_metadata("foo", "2").await.unwrap();
+    assert_eq!(
+        storage.get_metadata("foo").await.unwrap().as_deref(),
+        Some("2")
+    );
+}
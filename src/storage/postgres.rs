@@ -0,0 +1,751 @@
+use super::{
+    AuditEventQuery, AuditHasher, Storage, StorageError, StorageResult, StoredAuditCheckpoint,
+    StoredAuditEvent, StoredCounts, StoredCredential, StoredUser,
+};
+use async_trait::async_trait;
+use deadpool_postgres::{Manager, ManagerConfig, Object, Pool, PoolError, RecyclingMethod};
+use tokio_postgres::{error::SqlState, NoTls, Row};
+
+/// Ordered schema migrations, tracked in the `schema_version` table. New migrations must only ever
+/// be appended to this list.
+const MIGRATIONS: &[&str] = &[
+    r#"
+    create table users (
+      id text primary key not null,
+      username text not null unique
+    );
+
+    create table credentials (
+      cred_id text primary key not null,
+      user_id text not null references users(id),
+      name text not null,
+      value text not null,
+      disabled boolean not null default false,
+      unique(user_id, name)
+    );
+
+    create table sessions (
+      id text primary key not null,
+      value text not null
+    );
+"#,
+    r#"
+    alter table users
+      add column display_name text,
+      add column email text,
+      add column groups text[] not null default '{}';
+"#,
+    r#"
+    create table audit_events (
+      id bigserial primary key,
+      timestamp bigint not null,
+      kind text not null,
+      success boolean not null,
+      reason text,
+      username text,
+      credential text,
+      ip text,
+      user_agent text
+    );
+
+    create index audit_events_username on audit_events(username, id);
+
+    create function audit_events_append_only() returns trigger as $$
+    begin
+      raise exception 'audit_events is append-only';
+    end;
+    $$ language plpgsql;
+
+    create trigger audit_events_append_only before update or delete or truncate on audit_events
+      for each statement execute function audit_events_append_only();
+"#,
+    r#"
+    alter table audit_events
+      add column prev_hash text,
+      add column hash text;
+
+    create table audit_checkpoints (
+      id bigserial primary key,
+      event_id bigint not null,
+      hash text not null,
+      timestamp bigint not null,
+      signature text not null
+    );
+
+    create trigger audit_checkpoints_append_only
+      before update or delete or truncate on audit_checkpoints
+      for each statement execute function audit_events_append_only();
+"#,
+    r#"
+    alter table credentials
+      add column relying_party text not null default 'default',
+      drop constraint credentials_user_id_name_key,
+      add unique(user_id, relying_party, name);
+"#,
+    r#"
+    create table metadata (
+      key text primary key not null,
+      value text not null
+    );
+"#,
+    // The expiry is serialized by tower-sessions as [year, ordinal day, hour, minute, second,
+    // nanosecond, offset hours, offset minutes, offset seconds]. Encrypted values cannot be read
+    // here and are counted as expired until they are saved again.
+    r#"
+    alter table sessions add column expires_at bigint not null default 0;
+
+    update sessions
+    set expires_at = extract(epoch from
+      make_timestamptz((e->>0)::int, 1, 1, 0, 0, 0, 'UTC')
+      + make_interval(
+          days => (e->>1)::int - 1,
+          hours => (e->>2)::int - (e->>6)::int,
+          mins => (e->>3)::int - (e->>7)::int,
+          secs => (e->>4)::int - (e->>8)::int
+        ))::bigint
+    from (select id, value::jsonb->'expiry_date' as e from sessions where value like '{%') parsed
+    where sessions.id = parsed.id;
+"#,
+    r#"
+    alter table credentials add column backup_eligible boolean not null default false;
+
+    -- Encrypted values are backfilled when the storage keys are checked.
+    update credentials
+    set backup_eligible = coalesce((value::jsonb->'cred'->>'backup_eligible')::boolean, false)
+    where value like '{%';
+"#,
+];
+
+/// Arbitrary key for the advisory lock that serializes migrations across replicas.
+const MIGRATION_LOCK_KEY: i64 = 0x7765_6261_7574_686e;
+
+/// Arbitrary key for the advisory lock that serializes appends to the audit chain across replicas.
+const AUDIT_CHAIN_LOCK_KEY: i64 = 0x6175_6469_745f_6c6f;
+
+/// A storage backend backed by a PostgreSQL database, which allows several instances to share
+/// state.
+#[derive(Debug)]
+pub struct PostgresStorage {
+    pool: Pool,
+}
+
+impl PostgresStorage {
+    pub fn connect(url: &str) -> anyhow::Result<Self> {
+        let manager = Manager::from_config(
+            url.parse::<tokio_postgres::Config>()?,
+            NoTls,
+            ManagerConfig {
+                recycling_method: RecyclingMethod::Fast,
+            },
+        );
+
+        Ok(Self {
+            pool: Pool::builder(manager).build()?,
+        })
+    }
+
+    async fn client(&self) -> StorageResult<Object> {
+        Ok(self.pool.get().await?)
+    }
+}
+
+impl From<tokio_postgres::Error> for StorageError {
+    fn from(error: tokio_postgres::Error) -> Self {
+        match error.as_db_error() {
+            Some(db_error)
+                if *db_error.code() == SqlState::UNIQUE_VIOLATION
+                    && db_error.constraint() == Some("credentials_pkey") =>
+            {
+                StorageError::DuplicateCredential
+            }
+            // class 23 is "integrity constraint violation"
+            Some(db_error) if db_error.code().code().starts_with("23") => StorageError::Constraint,
+            // the error's own message is only "db error"
+            Some(db_error) => StorageError::Backend(db_error.to_string()),
+            None => StorageError::Backend(error.to_string()),
+        }
+    }
+}
+
+impl From<PoolError> for StorageError {
+    fn from(error: PoolError) -> Self {
+        StorageError::Backend(error.to_string())
+    }
+}
+
+fn user_from_row(row: &Row) -> StoredUser {
+    StoredUser {
+        id: row.get(0),
+        username: row.get(1),
+        display_name: row.get(2),
+        email: row.get(3),
+        groups: row.get(4),
+    }
+}
+
+fn credential_from_row(row: &Row) -> StoredCredential {
+    StoredCredential {
+        cred_id: row.get(0),
+        user_id: row.get(1),
+        name: row.get(2),
+        value: row.get(3),
+        disabled: row.get(4),
+        relying_party: row.get(5),
+        backup_eligible: row.get(6),
+    }
+}
+
+fn audit_event_from_row(row: &Row) -> StoredAuditEvent {
+    StoredAuditEvent {
+        id: row.get(0),
+        timestamp: row.get(1),
+        kind: row.get(2),
+        success: row.get(3),
+        reason: row.get(4),
+        username: row.get(5),
+        credential: row.get(6),
+        ip: row.get(7),
+        user_agent: row.get(8),
+        prev_hash: row.get(9),
+        hash: row.get(10),
+    }
+}
+
+#[async_trait]
+impl Storage for PostgresStorage {
+    async fn migrate(&self) -> anyhow::Result<()> {
+        let mut client = self.client().await?;
+        let tx = client.transaction().await?;
+
+        tx.execute("select pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK_KEY])
+            .await?;
+        tx.batch_execute("create table if not exists schema_version (version integer not null)")
+            .await?;
+
+        let version = tx
+            .query_opt("select version from schema_version", &[])
+            .await?
+            .map(|row| row.get::<_, i32>(0) as usize);
+
+        if version.is_some_and(|version| version > MIGRATIONS.len()) {
+            anyhow::bail!(
+                "database schema version {} is newer than the latest supported version {}",
+                version.unwrap_or_default(),
+                MIGRATIONS.len()
+            );
+        }
+
+        for migration in MIGRATIONS.iter().skip(version.unwrap_or_default()) {
+            tx.batch_execute(migration).await?;
+        }
+
+        if version.is_none() {
+            tx.execute(
+                "insert into schema_version (version) values ($1)",
+                &[&(MIGRATIONS.len() as i32)],
+            )
+            .await?;
+        } else {
+            tx.execute(
+                "update schema_version set version = $1",
+                &[&(MIGRATIONS.len() as i32)],
+            )
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn find_user(&self, username: &str) -> StorageResult<Option<StoredUser>> {
+        Ok(self
+            .client()
+            .await?
+            .query_opt(
+                r#"select id, username, display_name, email, groups
+                   from users
+                   where username = $1"#,
+                &[&username],
+            )
+            .await?
+            .as_ref()
+            .map(user_from_row))
+    }
+
+    async fn insert_user(&self, user: &StoredUser) -> StorageResult<()> {
+        self.client()
+            .await?
+            .execute(
+                r#"insert into users (id, username, display_name, email, groups)
+                   values ($1, $2, $3, $4, $5)"#,
+                &[
+                    &user.id,
+                    &user.username,
+                    &user.display_name,
+                    &user.email,
+                    &user.groups,
+                ],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn list_users(&self) -> StorageResult<Vec<StoredUser>> {
+        Ok(self
+            .client()
+            .await?
+            .query(
+                r#"select id, username, display_name, email, groups
+                   from users
+                   order by username"#,
+                &[],
+            )
+            .await?
+            .iter()
+            .map(user_from_row)
+            .collect())
+    }
+
+    async fn update_user(&self, user: &StoredUser) -> StorageResult<bool> {
+        let n_updated = self
+            .client()
+            .await?
+            .execute(
+                r#"update users set display_name = $1, email = $2, groups = $3
+                   where id = $4"#,
+                &[&user.display_name, &user.email, &user.groups, &user.id],
+            )
+            .await?;
+
+        Ok(n_updated == 1)
+    }
+
+    async fn delete_user(&self, user_id: &str) -> StorageResult<bool> {
+        let mut client = self.client().await?;
+        let tx = client.transaction().await?;
+        tx.execute("delete from credentials where user_id = $1", &[&user_id])
+            .await?;
+        let n_deleted = tx
+            .execute("delete from users where id = $1", &[&user_id])
+            .await?;
+        tx.commit().await?;
+
+        Ok(n_deleted == 1)
+    }
+
+    async fn list_credentials(&self, user_id: &str) -> StorageResult<Vec<StoredCredential>> {
+        Ok(self
+            .client()
+            .await?
+            .query(
+                r#"select cred_id, user_id, name, value, disabled, relying_party, backup_eligible
+                   from credentials
+                   where user_id = $1
+                   order by name"#,
+                &[&user_id],
+            )
+            .await?
+            .iter()
+            .map(credential_from_row)
+            .collect())
+    }
+
+    async fn get_credential(&self, cred_id: &str) -> StorageResult<Option<StoredCredential>> {
+        Ok(self
+            .client()
+            .await?
+            .query_opt(
+                r#"select cred_id, user_id, name, value, disabled, relying_party, backup_eligible
+                   from credentials
+                   where cred_id = $1"#,
+                &[&cred_id],
+            )
+            .await?
+            .as_ref()
+            .map(credential_from_row))
+    }
+
+    async fn insert_credential(&self, credential: &StoredCredential) -> StorageResult<()> {
+        self.client()
+            .await?
+            .execute(
+                r#"insert into credentials
+                     (cred_id, user_id, name, value, disabled, relying_party, backup_eligible)
+                   values ($1, $2, $3, $4, $5, $6, $7)"#,
+                &[
+                    &credential.cred_id,
+                    &credential.user_id,
+                    &credential.name,
+                    &credential.value,
+                    &credential.disabled,
+                    &credential.relying_party,
+                    &credential.backup_eligible,
+                ],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn update_credential_value(&self, cred_id: &str, value: &str) -> StorageResult<bool> {
+        let n_updated = self
+            .client()
+            .await?
+            .execute(
+                "update credentials set value = $1 where cred_id = $2",
+                &[&value, &cred_id],
+            )
+            .await?;
+
+        Ok(n_updated == 1)
+    }
+
+    async fn rename_credential(&self, cred_id: &str, name: &str) -> StorageResult<bool> {
+        let n_updated = self
+            .client()
+            .await?
+            .execute(
+                "update credentials set name = $1 where cred_id = $2",
+                &[&name, &cred_id],
+            )
+            .await?;
+
+        Ok(n_updated == 1)
+    }
+
+    async fn set_credential_disabled(&self, cred_id: &str, disabled: bool) -> StorageResult<bool> {
+        let n_updated = self
+            .client()
+            .await?
+            .execute(
+                "update credentials set disabled = $1 where cred_id = $2",
+                &[&disabled, &cred_id],
+            )
+            .await?;
+
+        Ok(n_updated == 1)
+    }
+
+    async fn set_credential_backup_eligible(
+        &self,
+        cred_id: &str,
+        backup_eligible: bool,
+    ) -> StorageResult<bool> {
+        let n_updated = self
+            .client()
+            .await?
+            .execute(
+                "update credentials set backup_eligible = $1 where cred_id = $2",
+                &[&backup_eligible, &cred_id],
+            )
+            .await?;
+
+        Ok(n_updated == 1)
+    }
+
+    async fn delete_credential(&self, cred_id: &str) -> StorageResult<bool> {
+        let n_deleted = self
+            .client()
+            .await?
+            .execute("delete from credentials where cred_id = $1", &[&cred_id])
+            .await?;
+
+        Ok(n_deleted == 1)
+    }
+
+    async fn replace_credential_value(
+        &self,
+        cred_id: &str,
+        expected: &str,
+        value: &str,
+    ) -> StorageResult<bool> {
+        let n_updated = self
+            .client()
+            .await?
+            .execute(
+                "update credentials set value = $1 where cred_id = $2 and value = $3",
+                &[&value, &cred_id, &expected],
+            )
+            .await?;
+
+        Ok(n_updated == 1)
+    }
+
+    async fn save_session(&self, id: &str, value: &str, expires_at: i64) -> StorageResult<()> {
+        self.client()
+            .await?
+            .execute(
+                r#"insert into sessions (id, value, expires_at) values ($1, $2, $3)
+                   on conflict (id)
+                   do update set value = excluded.value, expires_at = excluded.expires_at"#,
+                &[&id, &value, &expires_at],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn load_session(&self, id: &str) -> StorageResult<Option<String>> {
+        Ok(self
+            .client()
+            .await?
+            .query_opt("select value from sessions where id = $1", &[&id])
+            .await?
+            .map(|row| row.get(0)))
+    }
+
+    async fn delete_session(&self, id: &str) -> StorageResult<()> {
+        self.client()
+            .await?
+            .execute("delete from sessions where id = $1", &[&id])
+            .await?;
+
+        Ok(())
+    }
+
+    async fn clear_sessions(&self) -> StorageResult<()> {
+        self.client()
+            .await?
+            .execute("delete from sessions", &[])
+            .await?;
+
+        Ok(())
+    }
+
+    async fn list_session_ids(&self) -> StorageResult<Vec<String>> {
+        Ok(self
+            .client()
+            .await?
+            .query("select id from sessions", &[])
+            .await?
+            .iter()
+            .map(|row| row.get(0))
+            .collect())
+    }
+
+    async fn replace_session(&self, id: &str, expected: &str, value: &str) -> StorageResult<bool> {
+        let n_updated = self
+            .client()
+            .await?
+            .execute(
+                "update sessions set value = $1 where id = $2 and value = $3",
+                &[&value, &id, &expected],
+            )
+            .await?;
+
+        Ok(n_updated == 1)
+    }
+
+    async fn insert_audit_event(
+        &self,
+        event: &StoredAuditEvent,
+        hasher: AuditHasher,
+    ) -> StorageResult<i64> {
+        let mut client = self.client().await?;
+        let tx = client.transaction().await?;
+
+        tx.execute("select pg_advisory_xact_lock($1)", &[&AUDIT_CHAIN_LOCK_KEY])
+            .await?;
+
+        let mut event = event.clone();
+        event.prev_hash = tx
+            .query_opt(
+                r#"select hash from audit_events
+                   where hash is not null
+                   order by id desc
+                   limit 1"#,
+                &[],
+            )
+            .await?
+            .map(|row| row.get(0));
+        event.hash = Some(hasher(&event));
+
+        let id = tx
+            .query_one(
+                r#"insert into audit_events
+                     (timestamp, kind, success, reason, username, credential, ip, user_agent,
+                      prev_hash, hash)
+                   values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+                   returning id"#,
+                &[
+                    &event.timestamp,
+                    &event.kind,
+                    &event.success,
+                    &event.reason,
+                    &event.username,
+                    &event.credential,
+                    &event.ip,
+                    &event.user_agent,
+                    &event.prev_hash,
+                    &event.hash,
+                ],
+            )
+            .await?
+            .get(0);
+        tx.commit().await?;
+
+        Ok(id)
+    }
+
+    async fn list_audit_events(
+        &self,
+        query: &AuditEventQuery,
+    ) -> StorageResult<Vec<StoredAuditEvent>> {
+        Ok(self
+            .client()
+            .await?
+            .query(
+                r#"select id, timestamp, kind, success, reason, username, credential, ip,
+                          user_agent, prev_hash, hash
+                   from audit_events
+                   where ($1::bigint is null or id < $1) and ($2::text is null or username = $2)
+                   order by id desc
+                   limit $3"#,
+                &[&query.before, &query.username, &(query.limit as i64)],
+            )
+            .await?
+            .iter()
+            .map(audit_event_from_row)
+            .collect())
+    }
+
+    async fn list_audit_events_after(
+        &self,
+        after: i64,
+        limit: usize,
+    ) -> StorageResult<Vec<StoredAuditEvent>> {
+        Ok(self
+            .client()
+            .await?
+            .query(
+                r#"select id, timestamp, kind, success, reason, username, credential, ip,
+                          user_agent, prev_hash, hash
+                   from audit_events
+                   where id > $1
+                   order by id
+                   limit $2"#,
+                &[&after, &(limit as i64)],
+            )
+            .await?
+            .iter()
+            .map(audit_event_from_row)
+            .collect())
+    }
+
+    async fn insert_audit_checkpoint(
+        &self,
+        checkpoint: &StoredAuditCheckpoint,
+    ) -> StorageResult<()> {
+        self.client()
+            .await?
+            .execute(
+                r#"insert into audit_checkpoints (event_id, hash, timestamp, signature)
+                   values ($1, $2, $3, $4)"#,
+                &[
+                    &checkpoint.event_id,
+                    &checkpoint.hash,
+                    &checkpoint.timestamp,
+                    &checkpoint.signature,
+                ],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn list_audit_checkpoints(&self) -> StorageResult<Vec<StoredAuditCheckpoint>> {
+        Ok(self
+            .client()
+            .await?
+            .query(
+                r#"select event_id, hash, timestamp, signature
+                   from audit_checkpoints
+                   order by id"#,
+                &[],
+            )
+            .await?
+            .iter()
+            .map(|row| StoredAuditCheckpoint {
+                event_id: row.get(0),
+                hash: row.get(1),
+                timestamp: row.get(2),
+                signature: row.get(3),
+            })
+            .collect())
+    }
+
+    async fn count(&self, now: i64) -> StorageResult<StoredCounts> {
+        let client = self.client().await?;
+        let row = client
+            .query_one(
+                r#"select
+                     (select count(*) from sessions where expires_at > $1),
+                     (select count(*) from sessions where expires_at <= $1),
+                     (select count(*) from users),
+                     (select count(*) from users
+                      where not exists (select 1 from credentials where user_id = users.id))"#,
+                &[&now],
+            )
+            .await?;
+        let credentials = client
+            .query(
+                r#"select relying_party, backup_eligible, count(*)
+                   from credentials
+                   group by relying_party, backup_eligible"#,
+                &[],
+            )
+            .await?
+            .iter()
+            .map(|row| ((row.get(0), row.get(1)), row.get::<_, i64>(2) as usize))
+            .collect();
+
+        Ok(StoredCounts {
+            active_sessions: row.get::<_, i64>(0) as usize,
+            expired_sessions: row.get::<_, i64>(1) as usize,
+            users: row.get::<_, i64>(2) as usize,
+            users_without_credentials: row.get::<_, i64>(3) as usize,
+            credentials,
+        })
+    }
+
+    async fn get_metadata(&self, key: &str) -> StorageResult<Option<String>> {
+        Ok(self
+            .client()
+            .await?
+            .query_opt("select value from metadata where key = $1", &[&key])
+            .await?
+            .map(|row| row.get(0)))
+    }
+
+    async fn set_metadata(&self, key: &str, value: &str) -> StorageResult<()> {
+        self.client()
+            .await?
+            .execute(
+                r#"insert into metadata (key, value) values ($1, $2)
+                   on conflict (key) do update set value = excluded.value"#,
+                &[&key, &value],
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::test_storage;
+
+    /// Runs against the database in `WEBAUTHN_TINY_TEST_POSTGRES_URL`, if set. The database is
+    /// expected to be empty.
+    #[tokio::test]
+    async fn test_postgres_storage() {
+        let Ok(url) = std::env::var("WEBAUTHN_TINY_TEST_POSTGRES_URL") else {
+            return;
+        };
+
+        test_storage(&PostgresStorage::connect(&url).unwrap()).await;
+    }
+}
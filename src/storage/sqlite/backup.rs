@@ -0,0 +1,310 @@
+use super::{migrations::LATEST_VERSION, BUSY_TIMEOUT};
+use rusqlite::{
+    backup::{Backup, StepResult},
+    Connection, OpenFlags,
+};
+use std::{
+    fs::OpenOptions,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use time::OffsetDateTime;
+
+/// Prefix and suffix of the files written by scheduled backups, between which the UTC time of the
+/// backup is inserted so that the files sort chronologically.
+const SCHEDULED_PREFIX: &str = "webauthn-tiny-";
+const SCHEDULED_SUFFIX: &str = ".db";
+
+const RETRY_DELAY: Duration = Duration::from_millis(50);
+
+fn open_read_only(path: &Path) -> anyhow::Result<Connection> {
+    let conn = Connection::open_with_flags(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )
+    .map_err(|err| anyhow::anyhow!("opening {}: {err}", path.display()))?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+
+    Ok(conn)
+}
+
+/// Copies the whole database in a single step, so that the copy is a consistent snapshot. With
+/// the database in WAL mode this only holds a read transaction, which does not block writers.
+fn copy(source: &Connection, target: &mut Connection) -> rusqlite::Result<()> {
+    let backup = Backup::new(source, target)?;
+    loop {
+        match backup.step(-1)? {
+            StepResult::Done => return Ok(()),
+            // Locks are retried; the busy timeout does not apply to the backup's connections.
+            _ => std::thread::sleep(RETRY_DELAY),
+        }
+    }
+}
+
+/// Checks that the database is intact and has a schema this program can migrate. Returns the
+/// schema version.
+fn validate(conn: &Connection) -> anyhow::Result<u32> {
+    let problems = conn
+        .prepare("pragma integrity_check")?
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    if problems != ["ok"] {
+        anyhow::bail!(
+            "database failed the integrity check: {}",
+            problems.join("; ")
+        );
+    }
+
+    if !conn
+        .prepare("select 1 from sqlite_master where type = 'table' and name = 'users'")?
+        .exists([])?
+    {
+        anyhow::bail!("not a webauthn-tiny database");
+    }
+
+    let version: u32 = conn.query_row("pragma user_version", [], |row| row.get(0))?;
+    if version > LATEST_VERSION {
+        anyhow::bail!(
+            "database schema version {version} is newer than the latest supported version {LATEST_VERSION}"
+        );
+    }
+
+    Ok(version)
+}
+
+fn backup_blocking(source: &Path, target: &Path) -> anyhow::Result<()> {
+    let source = open_read_only(source)?;
+
+    // Write to a temporary file next to the target so that a failed backup never leaves a partial
+    // file at the target path. The file is created first so that it is only readable by its owner.
+    let file_name = target
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("{} is not a file path", target.display()))?;
+    let partial = target.with_file_name(format!(".{}.partial", file_name.to_string_lossy()));
+    _ = std::fs::remove_file(&partial);
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&partial)
+        .map_err(|err| anyhow::anyhow!("creating {}: {err}", partial.display()))?;
+
+    let result = (|| {
+        let mut conn = Connection::open(&partial)?;
+        copy(&source, &mut conn)?;
+        // The copy inherits WAL mode from the live database, switch back so that the backup is a
+        // single self-contained file.
+        conn.pragma_update(None, "journal_mode", "delete")?;
+        validate(&conn)?;
+        drop(conn);
+
+        std::fs::rename(&partial, target)?;
+
+        Ok(())
+    })();
+    if result.is_err() {
+        _ = std::fs::remove_file(&partial);
+    }
+
+    result
+}
+
+/// Writes a consistent copy of the live database at `source` to `target`, replacing it if it
+/// exists. Safe to run while the database is in use.
+pub async fn backup(source: &Path, target: &Path) -> anyhow::Result<()> {
+    let (source, target) = (source.to_path_buf(), target.to_path_buf());
+
+    tokio::task::spawn_blocking(move || backup_blocking(&source, &target)).await?
+}
+
+fn restore_blocking(backup: &Path, target: &Path) -> anyhow::Result<u32> {
+    let backup_conn = open_read_only(backup)?;
+    let version = validate(&backup_conn)
+        .map_err(|err| anyhow::anyhow!("refusing to restore {}: {err}", backup.display()))?;
+
+    // Keep the database that is about to be replaced, in case the wrong backup was picked.
+    if target.exists() {
+        backup_blocking(target, &replaced_path(target))?;
+    }
+
+    let mut conn = Connection::open(target)?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    copy(&backup_conn, &mut conn)?;
+    conn.pragma_update(None, "journal_mode", "wal")?;
+
+    Ok(version)
+}
+
+/// Where [`restore`] keeps the database it replaced.
+pub fn replaced_path(database: &Path) -> PathBuf {
+    let mut path = database.as_os_str().to_owned();
+    path.push(".before-restore");
+    path.into()
+}
+
+/// Validates the backup and replaces the database at `target` with it, keeping the replaced
+/// database at [`replaced_path`]. Returns the schema version of the backup, which is migrated on
+/// the next start. The service must not be running.
+pub async fn restore(backup: &Path, target: &Path) -> anyhow::Result<u32> {
+    let (backup, target) = (backup.to_path_buf(), target.to_path_buf());
+
+    tokio::task::spawn_blocking(move || restore_blocking(&backup, &target)).await?
+}
+
+/// Backs the database up into a new timestamped file in `directory` and deletes all but the
+/// `retention` most recent scheduled backups. Returns the path of the new backup.
+pub async fn scheduled_backup(
+    source: &Path,
+    directory: &Path,
+    retention: usize,
+) -> anyhow::Result<PathBuf> {
+    let now = OffsetDateTime::now_utc();
+    let target = directory.join(format!(
+        "{SCHEDULED_PREFIX}{:04}{:02}{:02}T{:02}{:02}{:02}Z{SCHEDULED_SUFFIX}",
+        now.year(),
+        now.month() as u8,
+        now.day(),
+        now.hour(),
+        now.minute(),
+        now.second()
+    ));
+    backup(source, &target).await?;
+
+    let mut backups = std::fs::read_dir(directory)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| {
+                    name.starts_with(SCHEDULED_PREFIX) && name.ends_with(SCHEDULED_SUFFIX)
+                })
+        })
+        .collect::<Vec<_>>();
+    backups.sort();
+    for old in &backups[..backups.len().saturating_sub(retention)] {
+        std::fs::remove_file(old)
+            .map_err(|err| anyhow::anyhow!("removing {}: {err}", old.display()))?;
+    }
+
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{sqlite::SqliteStorage, Storage, StoredUser};
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("webauthn-tiny-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    async fn insert_user(storage: &SqliteStorage, username: &str) {
+        storage
+            .insert_user(&StoredUser {
+                id: uuid::Uuid::new_v4().to_string(),
+                username: username.to_string(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_backup_and_restore() {
+        let dir = temp_dir();
+        let live = dir.join("live.db");
+        let storage = SqliteStorage::open(&live).await.unwrap();
+        storage.migrate().await.unwrap();
+        insert_user(&storage, "foo_user").await;
+
+        let target = dir.join("backup.db");
+        backup(&live, &target).await.unwrap();
+        insert_user(&storage, "bar_user").await;
+        drop(storage);
+
+        assert_eq!(restore(&target, &live).await.unwrap(), LATEST_VERSION);
+
+        let storage = SqliteStorage::open(&live).await.unwrap();
+        storage.migrate().await.unwrap();
+        assert!(storage.find_user("foo_user").await.unwrap().is_some());
+        assert!(storage.find_user("bar_user").await.unwrap().is_none());
+        drop(storage);
+
+        // The replaced database is kept.
+        let storage = SqliteStorage::open(replaced_path(&live)).await.unwrap();
+        assert!(storage.find_user("bar_user").await.unwrap().is_some());
+        drop(storage);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_restore_rejects_invalid_backups() {
+        let dir = temp_dir();
+        let live = dir.join("live.db");
+
+        let garbage = dir.join("garbage.db");
+        std::fs::write(&garbage, [0x42; 4096]).unwrap();
+        assert!(restore(&garbage, &live).await.is_err());
+
+        let newer = dir.join("newer.db");
+        let conn = Connection::open(&newer).unwrap();
+        conn.execute_batch("create table users (id text)").unwrap();
+        conn.pragma_update(None, "user_version", LATEST_VERSION + 1)
+            .unwrap();
+        drop(conn);
+        assert!(restore(&newer, &live).await.is_err());
+
+        let unrelated = dir.join("unrelated.db");
+        Connection::open(&unrelated)
+            .unwrap()
+            .execute_batch("create table foo (bar text)")
+            .unwrap();
+        assert!(restore(&unrelated, &live).await.is_err());
+
+        assert!(!live.exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_scheduled_backup_retention() {
+        let dir = temp_dir();
+        let live = dir.join("live.db");
+        SqliteStorage::open(&live)
+            .await
+            .unwrap()
+            .migrate()
+            .await
+            .unwrap();
+
+        let backups = dir.join("backups");
+        std::fs::create_dir(&backups).unwrap();
+        for old in ["20200101T000000Z", "20200102T000000Z", "20200103T000000Z"] {
+            std::fs::write(backups.join(format!("webauthn-tiny-{old}.db")), []).unwrap();
+        }
+        std::fs::write(backups.join("unrelated"), []).unwrap();
+
+        let target = scheduled_backup(&live, &backups, 2).await.unwrap();
+
+        let mut remaining = std::fs::read_dir(&backups)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        remaining.sort();
+        assert_eq!(
+            remaining,
+            vec![
+                backups.join("unrelated"),
+                backups.join("webauthn-tiny-20200103T000000Z.db"),
+                target,
+            ]
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,369 @@
+use rusqlite::Transaction;
+use std::fmt::Display;
+use tokio_rusqlite::Connection;
+
+type Migration = fn(&Transaction) -> rusqlite::Result<()>;
+
+/// Ordered schema migrations. The database's `user_version` pragma holds the number of migrations
+/// that have been applied, so new migrations must only ever be appended to this list.
+const MIGRATIONS: &[Migration] = &[
+    initial_schema,
+    add_credentials_disabled,
+    add_credentials_cred_id,
+    add_users_profile,
+    add_audit_events,
+    add_audit_chain,
+    add_credentials_relying_party,
+    add_metadata,
+    add_sessions_expires_at,
+    add_credentials_backup_eligible,
+];
+
+/// The schema version of a database with all known migrations applied.
+pub const LATEST_VERSION: u32 = MIGRATIONS.len() as u32;
+
+#[derive(Debug)]
+pub enum MigrationError {
+    /// The database was migrated by a newer version of this program.
+    UnsupportedVersion(u32),
+    Sqlite(rusqlite::Error),
+}
+
+impl Display for MigrationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MigrationError::UnsupportedVersion(version) => write!(
+                f,
+                "database schema version {version} is newer than the latest supported version {LATEST_VERSION}"
+            ),
+            MigrationError::Sqlite(err) => write!(f, "failed to migrate database: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {}
+
+impl From<rusqlite::Error> for MigrationError {
+    fn from(error: rusqlite::Error) -> Self {
+        MigrationError::Sqlite(error)
+    }
+}
+
+/// Brings the database schema up to date. Each migration runs in its own transaction together with
+/// the update of `user_version`, so a failed migration leaves the database at the previous version.
+pub fn migrate(conn: &mut rusqlite::Connection) -> Result<u32, MigrationError> {
+    let version: u32 = conn.query_row("pragma user_version", [], |row| row.get(0))?;
+
+    if version > LATEST_VERSION {
+        return Err(MigrationError::UnsupportedVersion(version));
+    }
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let tx = conn.transaction()?;
+        migration(&tx)?;
+        tx.pragma_update(None, "user_version", index as u32 + 1)?;
+        tx.commit()?;
+    }
+
+    Ok(LATEST_VERSION)
+}
+
+pub async fn run(db: &Connection) -> anyhow::Result<()> {
+    db.call(|conn| Ok(migrate(conn))).await??;
+
+    Ok(())
+}
+
+fn initial_schema(tx: &Transaction) -> rusqlite::Result<()> {
+    // Databases created before migrations were introduced already have these tables, so they must
+    // only be created if they do not exist.
+    tx.execute_batch(
+        r#"create table if not exists users (
+             id uuid primary key not null,
+             username text not null unique
+           );
+
+           create table if not exists credentials (
+             name text not null,
+             user uuid not null,
+             value json not null,
+             foreign key(user) references users(id),
+             unique(name, user)
+           );
+
+           create table if not exists sessions (
+             id text primary key not null,
+             value json not null
+           );"#,
+    )
+}
+
+fn add_credentials_disabled(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch("alter table credentials add column disabled boolean not null default false;")
+}
+
+fn add_credentials_cred_id(tx: &Transaction) -> rusqlite::Result<()> {
+    // The credential ID is serialized as base64url by webauthn-rs, so the backfilled column matches
+    // the encoding used for new rows. The table is rebuilt so that the column can be `not null`.
+    tx.execute_batch(
+        r#"create table credentials_new (
+             name text not null,
+             user uuid not null,
+             value json not null,
+             disabled boolean not null default false,
+             cred_id text not null,
+             foreign key(user) references users(id),
+             unique(name, user)
+           );
+
+           insert into credentials_new (name, user, value, disabled, cred_id)
+           select name, user, value, disabled, value->>'$.cred.cred_id' from credentials;
+
+           drop table credentials;
+
+           alter table credentials_new rename to credentials;
+
+           create unique index credentials_cred_id on credentials(cred_id);"#,
+    )
+}
+
+fn add_users_profile(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        r#"alter table users add column display_name text;
+           alter table users add column email text;
+           alter table users add column groups json not null default '[]';"#,
+    )
+}
+
+fn add_audit_events(tx: &Transaction) -> rusqlite::Result<()> {
+    // The audit log is append-only, which the triggers enforce for anything writing to the
+    // database directly.
+    tx.execute_batch(
+        r#"create table audit_events (
+             id integer primary key autoincrement,
+             timestamp integer not null,
+             kind text not null,
+             success boolean not null,
+             reason text,
+             username text,
+             credential text,
+             ip text,
+             user_agent text
+           );
+
+           create index audit_events_username on audit_events(username, id);
+
+           create trigger audit_events_no_update before update on audit_events
+           begin
+             select raise(abort, 'audit_events is append-only');
+           end;
+
+           create trigger audit_events_no_delete before delete on audit_events
+           begin
+             select raise(abort, 'audit_events is append-only');
+           end;"#,
+    )
+}
+
+fn add_audit_chain(tx: &Transaction) -> rusqlite::Result<()> {
+    // Events recorded before this migration are left without hashes and precede the chain.
+    tx.execute_batch(
+        r#"alter table audit_events add column prev_hash text;
+           alter table audit_events add column hash text;
+
+           create table audit_checkpoints (
+             id integer primary key autoincrement,
+             event_id integer not null,
+             hash text not null,
+             timestamp integer not null,
+             signature text not null
+           );
+
+           create trigger audit_checkpoints_no_update before update on audit_checkpoints
+           begin
+             select raise(abort, 'audit_checkpoints is append-only');
+           end;
+
+           create trigger audit_checkpoints_no_delete before delete on audit_checkpoints
+           begin
+             select raise(abort, 'audit_checkpoints is append-only');
+           end;"#,
+    )
+}
+
+fn add_credentials_relying_party(tx: &Transaction) -> rusqlite::Result<()> {
+    // Existing credentials were registered with the relying party configured with `--rp-id`. The
+    // table is rebuilt so that names only have to be unique per relying party.
+    tx.execute_batch(
+        r#"create table credentials_new (
+             name text not null,
+             user uuid not null,
+             value json not null,
+             disabled boolean not null default false,
+             cred_id text not null,
+             relying_party text not null default 'default',
+             foreign key(user) references users(id),
+             unique(name, user, relying_party)
+           );
+
+           insert into credentials_new (name, user, value, disabled, cred_id)
+           select name, user, value, disabled, cred_id from credentials;
+
+           drop table credentials;
+
+           alter table credentials_new rename to credentials;
+
+           create unique index credentials_cred_id on credentials(cred_id);"#,
+    )
+}
+
+fn add_metadata(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        r#"create table metadata (
+             key text primary key not null,
+             value text not null
+           );"#,
+    )
+}
+
+fn add_sessions_expires_at(tx: &Transaction) -> rusqlite::Result<()> {
+    // The expiry is serialized by tower-sessions as [year, ordinal day, hour, minute, second,
+    // nanosecond, offset hours, offset minutes, offset seconds]. Encrypted values cannot be read
+    // here and are counted as expired until they are saved again.
+    tx.execute_batch(
+        r#"alter table sessions add column expires_at integer not null default 0;
+
+           update sessions
+           set expires_at =
+             unixepoch(
+               printf('%04d-01-01', value->>'$.expiry_date[0]'),
+               printf('%+d days', value->>'$.expiry_date[1]' - 1)
+             )
+             + value->>'$.expiry_date[2]' * 3600
+             + value->>'$.expiry_date[3]' * 60
+             + value->>'$.expiry_date[4]'
+             - value->>'$.expiry_date[6]' * 3600
+             - value->>'$.expiry_date[7]' * 60
+             - value->>'$.expiry_date[8]'
+           where json_valid(value);"#,
+    )
+}
+
+fn add_credentials_backup_eligible(tx: &Transaction) -> rusqlite::Result<()> {
+    // Encrypted values cannot be read here, they are backfilled when the storage keys are checked.
+    tx.execute_batch(
+        r#"alter table credentials add column backup_eligible boolean not null default false;
+
+           update credentials
+           set backup_eligible = coalesce(value->>'$.cred.backup_eligible', false)
+           where json_valid(value);"#,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_version(conn: &rusqlite::Connection) -> u32 {
+        conn.query_row("pragma user_version", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_migrate_is_idempotent() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+
+        assert_eq!(migrate(&mut conn).unwrap(), LATEST_VERSION);
+        assert_eq!(migrate(&mut conn).unwrap(), LATEST_VERSION);
+        assert_eq!(user_version(&conn), LATEST_VERSION);
+    }
+
+    #[test]
+    fn test_migrate_unversioned_database() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            r#"create table users (
+                 id uuid primary key not null,
+                 username text not null unique
+               );
+               create table credentials (
+                 name text not null,
+                 user uuid not null,
+                 value json not null,
+                 foreign key(user) references users(id),
+                 unique(name, user)
+               );
+               create table sessions (
+                 id text primary key not null,
+                 value json not null
+               );
+               insert into users (id, username) values ('id', 'foo_user');
+               insert into credentials (name, user, value)
+               values ('foo_credential', 'id', '{"cred":{"cred_id":"Zm9v","backup_eligible":true}}');
+               insert into sessions (id, value)
+               values ('session', '{"expiry_date":[2024,60,12,30,15,500000000,1,0,0]}');"#,
+        )
+        .unwrap();
+
+        migrate(&mut conn).unwrap();
+
+        assert_eq!(user_version(&conn), LATEST_VERSION);
+        assert_eq!(
+            conn.query_row("select username from users", [], |row| row
+                .get::<_, String>(0))
+                .unwrap(),
+            "foo_user"
+        );
+        assert_eq!(
+            conn.query_row(
+                "select cred_id, disabled, relying_party, backup_eligible from credentials",
+                [],
+                |row| Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, bool>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, bool>(3)?
+                ))
+            )
+            .unwrap(),
+            ("Zm9v".to_string(), false, "default".to_string(), true)
+        );
+        // 2024-02-29T12:30:15.5+01:00
+        assert_eq!(
+            conn.query_row("select expires_at from sessions", [], |row| row
+                .get::<_, i64>(0))
+                .unwrap(),
+            1_709_206_215
+        );
+    }
+
+    #[test]
+    fn test_audit_events_are_append_only() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+
+        conn.execute(
+            r#"insert into audit_events (timestamp, kind, success)
+               values (0, 'authentication', true)"#,
+            [],
+        )
+        .unwrap();
+
+        assert!(conn
+            .execute("update audit_events set success = false", [])
+            .is_err());
+        assert!(conn.execute("delete from audit_events", []).is_err());
+    }
+
+    #[test]
+    fn test_migrate_refuses_newer_database() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "user_version", LATEST_VERSION + 1)
+            .unwrap();
+
+        assert!(matches!(
+            migrate(&mut conn),
+            Err(MigrationError::UnsupportedVersion(_))
+        ));
+    }
+}
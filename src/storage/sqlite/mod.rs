@@ -0,0 +1,753 @@
+pub mod backup;
+pub mod migrations;
+
+use super::{
+    AuditEventQuery, AuditHasher, Storage, StorageError, StorageResult, StoredAuditCheckpoint,
+    StoredAuditEvent, StoredCounts, StoredCredential, StoredUser,
+};
+use async_trait::async_trait;
+use libsqlite3_sys::ErrorCode::ConstraintViolation;
+use rusqlite::{Error::SqliteFailure, OpenFlags, OptionalExtension, Row, TransactionBehavior};
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio_rusqlite::Connection;
+
+/// Number of read-only connections used for queries that do not modify the database.
+const READ_CONNECTIONS: usize = 4;
+
+/// How long a connection waits for a lock held by another connection before giving up.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// SQLite storage in WAL mode. All writes go through a single connection, while reads are spread
+/// over a small pool of read-only connections so that they can run concurrently with each other
+/// and with writes.
+#[derive(Clone, Debug)]
+pub struct SqliteStorage {
+    db: Connection,
+    readers: Vec<Connection>,
+    next_reader: Arc<AtomicUsize>,
+}
+
+impl SqliteStorage {
+    pub async fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+
+        let db = Connection::open(path).await?;
+        db.call(|conn| {
+            conn.busy_timeout(BUSY_TIMEOUT)?;
+            conn.pragma_update(None, "journal_mode", "wal")?;
+            conn.pragma_update(None, "synchronous", "normal")?;
+            Ok(())
+        })
+        .await?;
+
+        // Every connection to an in-memory database is its own database, so reads have to go
+        // through the writer.
+        let mut readers = Vec::new();
+        if path != Path::new(":memory:") {
+            for _ in 0..READ_CONNECTIONS {
+                let reader = Connection::open_with_flags(
+                    path,
+                    OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+                )
+                .await?;
+                reader
+                    .call(|conn| Ok(conn.busy_timeout(BUSY_TIMEOUT)?))
+                    .await?;
+                readers.push(reader);
+            }
+        }
+
+        Ok(Self {
+            db,
+            readers,
+            next_reader: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    /// Returns the next read-only connection, in round-robin order.
+    fn reader(&self) -> &Connection {
+        if self.readers.is_empty() {
+            return &self.db;
+        }
+
+        &self.readers[self.next_reader.fetch_add(1, Ordering::Relaxed) % self.readers.len()]
+    }
+}
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(error: rusqlite::Error) -> Self {
+        match error {
+            SqliteFailure(err, _) if err.code == ConstraintViolation => StorageError::Constraint,
+            err => StorageError::Backend(err.to_string()),
+        }
+    }
+}
+
+impl From<tokio_rusqlite::Error> for StorageError {
+    fn from(error: tokio_rusqlite::Error) -> Self {
+        match error {
+            tokio_rusqlite::Error::Rusqlite(error) => error.into(),
+            err => StorageError::Backend(err.to_string()),
+        }
+    }
+}
+
+/// Groups are stored as a JSON array.
+fn user_from_row(row: &Row) -> rusqlite::Result<StoredUser> {
+    let groups: String = row.get(4)?;
+
+    Ok(StoredUser {
+        id: row.get(0)?,
+        username: row.get(1)?,
+        display_name: row.get(2)?,
+        email: row.get(3)?,
+        groups: serde_json::from_str(&groups).map_err(|err| {
+            rusqlite::Error::FromSqlConversionFailure(4, rusqlite::types::Type::Text, err.into())
+        })?,
+    })
+}
+
+fn groups_to_json(groups: &[String]) -> String {
+    serde_json::to_string(groups).expect("string array always serializes")
+}
+
+fn credential_from_row(row: &Row) -> rusqlite::Result<StoredCredential> {
+    Ok(StoredCredential {
+        cred_id: row.get(0)?,
+        user_id: row.get(1)?,
+        name: row.get(2)?,
+        value: row.get(3)?,
+        disabled: row.get(4)?,
+        relying_party: row.get(5)?,
+        backup_eligible: row.get(6)?,
+    })
+}
+
+fn audit_event_from_row(row: &Row) -> rusqlite::Result<StoredAuditEvent> {
+    Ok(StoredAuditEvent {
+        id: row.get(0)?,
+        timestamp: row.get(1)?,
+        kind: row.get(2)?,
+        success: row.get(3)?,
+        reason: row.get(4)?,
+        username: row.get(5)?,
+        credential: row.get(6)?,
+        ip: row.get(7)?,
+        user_agent: row.get(8)?,
+        prev_hash: row.get(9)?,
+        hash: row.get(10)?,
+    })
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn migrate(&self) -> anyhow::Result<()> {
+        migrations::run(&self.db).await
+    }
+
+    async fn find_user(&self, username: &str) -> StorageResult<Option<StoredUser>> {
+        let username = username.to_string();
+
+        Ok(self
+            .reader()
+            .call(move |conn| {
+                Ok(conn
+                    .query_row(
+                        r#"select id, username, display_name, email, groups
+                           from users
+                           where username = ?1"#,
+                        (username,),
+                        user_from_row,
+                    )
+                    .optional())
+            })
+            .await??)
+    }
+
+    async fn insert_user(&self, user: &StoredUser) -> StorageResult<()> {
+        let user = user.clone();
+
+        self.db
+            .call(move |conn| {
+                Ok(conn.execute(
+                    r#"insert into users (id, username, display_name, email, groups)
+                       values (?1, ?2, ?3, ?4, ?5)"#,
+                    (
+                        user.id,
+                        user.username,
+                        user.display_name,
+                        user.email,
+                        groups_to_json(&user.groups),
+                    ),
+                ))
+            })
+            .await??;
+
+        Ok(())
+    }
+
+    async fn list_users(&self) -> StorageResult<Vec<StoredUser>> {
+        Ok(self
+            .reader()
+            .call(|conn| {
+                Ok(conn
+                    .prepare(
+                        r#"select id, username, display_name, email, groups
+                           from users
+                           order by username"#,
+                    )?
+                    .query_map([], user_from_row)?
+                    .collect::<rusqlite::Result<Vec<_>>>())
+            })
+            .await??)
+    }
+
+    async fn update_user(&self, user: &StoredUser) -> StorageResult<bool> {
+        let user = user.clone();
+
+        let n_updated = self
+            .db
+            .call(move |conn| {
+                Ok(conn.execute(
+                    r#"update users set display_name = ?1, email = ?2, groups = ?3
+                       where id = ?4"#,
+                    (
+                        user.display_name,
+                        user.email,
+                        groups_to_json(&user.groups),
+                        user.id,
+                    ),
+                ))
+            })
+            .await??;
+
+        Ok(n_updated == 1)
+    }
+
+    async fn delete_user(&self, user_id: &str) -> StorageResult<bool> {
+        let user_id = user_id.to_string();
+
+        let n_deleted = self
+            .db
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                tx.execute(r#"delete from credentials where user = ?1"#, (&user_id,))?;
+                let n_deleted = tx.execute(r#"delete from users where id = ?1"#, (&user_id,))?;
+                tx.commit()?;
+                Ok(n_deleted)
+            })
+            .await?;
+
+        Ok(n_deleted == 1)
+    }
+
+    async fn list_credentials(&self, user_id: &str) -> StorageResult<Vec<StoredCredential>> {
+        let user_id = user_id.to_string();
+
+        Ok(self
+            .reader()
+            .call(move |conn| {
+                Ok(conn
+                    .prepare(
+                        r#"select cred_id, user, name, value, disabled, relying_party, backup_eligible
+                           from credentials
+                           where user = ?1
+                           order by rowid"#,
+                    )?
+                    .query_map((user_id,), credential_from_row)?
+                    .collect::<rusqlite::Result<Vec<_>>>())
+            })
+            .await??)
+    }
+
+    async fn get_credential(&self, cred_id: &str) -> StorageResult<Option<StoredCredential>> {
+        let cred_id = cred_id.to_string();
+
+        Ok(self
+            .reader()
+            .call(move |conn| {
+                Ok(conn
+                    .query_row(
+                        r#"select cred_id, user, name, value, disabled, relying_party, backup_eligible
+                           from credentials
+                           where cred_id = ?1"#,
+                        (cred_id,),
+                        credential_from_row,
+                    )
+                    .optional())
+            })
+            .await??)
+    }
+
+    async fn insert_credential(&self, credential: &StoredCredential) -> StorageResult<()> {
+        let credential = credential.clone();
+
+        let inserted = self
+            .db
+            .call(move |conn| {
+                // Duplicate credential IDs are told apart from other constraint violations by
+                // looking for the existing row, with the write lock held until the insert.
+                let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                if tx
+                    .prepare("select 1 from credentials where cred_id = ?1")?
+                    .exists((&credential.cred_id,))?
+                {
+                    return Ok(false);
+                }
+
+                tx.execute(
+                    r#"insert into credentials
+                         (cred_id, user, name, value, disabled, relying_party, backup_eligible)
+                       values (?1, ?2, ?3, ?4, ?5, ?6, ?7)"#,
+                    (
+                        credential.cred_id,
+                        credential.user_id,
+                        credential.name,
+                        credential.value,
+                        credential.disabled,
+                        credential.relying_party,
+                        credential.backup_eligible,
+                    ),
+                )?;
+                tx.commit()?;
+                Ok(true)
+            })
+            .await?;
+
+        if !inserted {
+            return Err(StorageError::DuplicateCredential);
+        }
+
+        Ok(())
+    }
+
+    async fn update_credential_value(&self, cred_id: &str, value: &str) -> StorageResult<bool> {
+        let (cred_id, value) = (cred_id.to_string(), value.to_string());
+
+        let n_updated = self
+            .db
+            .call(move |conn| {
+                Ok(conn.execute(
+                    r#"update credentials set value = ?1 where cred_id = ?2"#,
+                    (value, cred_id),
+                ))
+            })
+            .await??;
+
+        Ok(n_updated == 1)
+    }
+
+    async fn rename_credential(&self, cred_id: &str, name: &str) -> StorageResult<bool> {
+        let (cred_id, name) = (cred_id.to_string(), name.to_string());
+
+        let n_updated = self
+            .db
+            .call(move |conn| {
+                Ok(conn.execute(
+                    r#"update credentials set name = ?1 where cred_id = ?2"#,
+                    (name, cred_id),
+                ))
+            })
+            .await??;
+
+        Ok(n_updated == 1)
+    }
+
+    async fn set_credential_disabled(&self, cred_id: &str, disabled: bool) -> StorageResult<bool> {
+        let cred_id = cred_id.to_string();
+
+        let n_updated = self
+            .db
+            .call(move |conn| {
+                Ok(conn.execute(
+                    r#"update credentials set disabled = ?1 where cred_id = ?2"#,
+                    (disabled, cred_id),
+                ))
+            })
+            .await??;
+
+        Ok(n_updated == 1)
+    }
+
+    async fn set_credential_backup_eligible(
+        &self,
+        cred_id: &str,
+        backup_eligible: bool,
+    ) -> StorageResult<bool> {
+        let cred_id = cred_id.to_string();
+
+        let n_updated = self
+            .db
+            .call(move |conn| {
+                Ok(conn.execute(
+                    r#"update credentials set backup_eligible = ?1 where cred_id = ?2"#,
+                    (backup_eligible, cred_id),
+                ))
+            })
+            .await??;
+
+        Ok(n_updated == 1)
+    }
+
+    async fn delete_credential(&self, cred_id: &str) -> StorageResult<bool> {
+        let cred_id = cred_id.to_string();
+
+        let n_deleted = self
+            .db
+            .call(move |conn| {
+                Ok(conn.execute(r#"delete from credentials where cred_id = ?1"#, (cred_id,)))
+            })
+            .await??;
+
+        Ok(n_deleted == 1)
+    }
+
+    async fn replace_credential_value(
+        &self,
+        cred_id: &str,
+        expected: &str,
+        value: &str,
+    ) -> StorageResult<bool> {
+        let (cred_id, expected, value) =
+            (cred_id.to_string(), expected.to_string(), value.to_string());
+
+        let n_updated = self
+            .db
+            .call(move |conn| {
+                Ok(conn.execute(
+                    r#"update credentials set value = ?1 where cred_id = ?2 and value = ?3"#,
+                    (value, cred_id, expected),
+                ))
+            })
+            .await??;
+
+        Ok(n_updated == 1)
+    }
+
+    async fn save_session(&self, id: &str, value: &str, expires_at: i64) -> StorageResult<()> {
+        let (id, value) = (id.to_string(), value.to_string());
+
+        self.db
+            .call(move |conn| {
+                Ok(conn.execute(
+                    r#"insert or replace into sessions (id, value, expires_at) values(?1, ?2, ?3)"#,
+                    (id, value, expires_at),
+                ))
+            })
+            .await??;
+
+        Ok(())
+    }
+
+    async fn load_session(&self, id: &str) -> StorageResult<Option<String>> {
+        let id = id.to_string();
+
+        Ok(self
+            .reader()
+            .call(move |conn| {
+                Ok(conn
+                    .query_row(
+                        r#"select value from sessions where id = ?1"#,
+                        (id,),
+                        |row| row.get::<_, String>(0),
+                    )
+                    .optional())
+            })
+            .await??)
+    }
+
+    async fn delete_session(&self, id: &str) -> StorageResult<()> {
+        let id = id.to_string();
+
+        self.db
+            .call(move |conn| Ok(conn.execute(r#"delete from sessions where id = ?1"#, (id,))))
+            .await??;
+
+        Ok(())
+    }
+
+    async fn clear_sessions(&self) -> StorageResult<()> {
+        self.db
+            .call(|conn| Ok(conn.execute(r#"delete from sessions"#, [])))
+            .await??;
+
+        Ok(())
+    }
+
+    async fn list_session_ids(&self) -> StorageResult<Vec<String>> {
+        Ok(self
+            .reader()
+            .call(|conn| {
+                Ok(conn
+                    .prepare(r#"select id from sessions"#)?
+                    .query_map([], |row| row.get(0))?
+                    .collect::<rusqlite::Result<Vec<_>>>())
+            })
+            .await??)
+    }
+
+    async fn replace_session(&self, id: &str, expected: &str, value: &str) -> StorageResult<bool> {
+        let (id, expected, value) = (id.to_string(), expected.to_string(), value.to_string());
+
+        let n_updated = self
+            .db
+            .call(move |conn| {
+                Ok(conn.execute(
+                    r#"update sessions set value = ?1 where id = ?2 and value = ?3"#,
+                    (value, id, expected),
+                ))
+            })
+            .await??;
+
+        Ok(n_updated == 1)
+    }
+
+    async fn insert_audit_event(
+        &self,
+        event: &StoredAuditEvent,
+        hasher: AuditHasher,
+    ) -> StorageResult<i64> {
+        let mut event = event.clone();
+
+        Ok(self
+            .db
+            .call(move |conn| {
+                // Take the write lock up front, other processes such as the admin commands may be
+                // appending to the chain at the same time.
+                let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+                event.prev_hash = tx
+                    .query_row(
+                        r#"select hash from audit_events
+                           where hash is not null
+                           order by id desc
+                           limit 1"#,
+                        [],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                event.hash = Some(hasher(&event));
+
+                let id = tx.query_row(
+                    r#"insert into audit_events
+                         (timestamp, kind, success, reason, username, credential, ip, user_agent,
+                          prev_hash, hash)
+                       values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+                       returning id"#,
+                    (
+                        event.timestamp,
+                        event.kind,
+                        event.success,
+                        event.reason,
+                        event.username,
+                        event.credential,
+                        event.ip,
+                        event.user_agent,
+                        event.prev_hash,
+                        event.hash,
+                    ),
+                    |row| row.get(0),
+                )?;
+                tx.commit()?;
+
+                Ok(id)
+            })
+            .await?)
+    }
+
+    async fn list_audit_events(
+        &self,
+        query: &AuditEventQuery,
+    ) -> StorageResult<Vec<StoredAuditEvent>> {
+        let query = query.clone();
+
+        Ok(self
+            .reader()
+            .call(move |conn| {
+                Ok(conn
+                    .prepare(
+                        r#"select id, timestamp, kind, success, reason, username, credential, ip,
+                                  user_agent, prev_hash, hash
+                           from audit_events
+                           where (?1 is null or id < ?1) and (?2 is null or username = ?2)
+                           order by id desc
+                           limit ?3"#,
+                    )?
+                    .query_map(
+                        (query.before, query.username, query.limit as i64),
+                        audit_event_from_row,
+                    )?
+                    .collect::<rusqlite::Result<Vec<_>>>())
+            })
+            .await??)
+    }
+
+    async fn list_audit_events_after(
+        &self,
+        after: i64,
+        limit: usize,
+    ) -> StorageResult<Vec<StoredAuditEvent>> {
+        Ok(self
+            .reader()
+            .call(move |conn| {
+                Ok(conn
+                    .prepare(
+                        r#"select id, timestamp, kind, success, reason, username, credential, ip,
+                                  user_agent, prev_hash, hash
+                           from audit_events
+                           where id > ?1
+                           order by id
+                           limit ?2"#,
+                    )?
+                    .query_map((after, limit as i64), audit_event_from_row)?
+                    .collect::<rusqlite::Result<Vec<_>>>())
+            })
+            .await??)
+    }
+
+    async fn insert_audit_checkpoint(
+        &self,
+        checkpoint: &StoredAuditCheckpoint,
+    ) -> StorageResult<()> {
+        let checkpoint = checkpoint.clone();
+
+        self.db
+            .call(move |conn| {
+                Ok(conn.execute(
+                    r#"insert into audit_checkpoints (event_id, hash, timestamp, signature)
+                       values (?1, ?2, ?3, ?4)"#,
+                    (
+                        checkpoint.event_id,
+                        checkpoint.hash,
+                        checkpoint.timestamp,
+                        checkpoint.signature,
+                    ),
+                ))
+            })
+            .await??;
+
+        Ok(())
+    }
+
+    async fn list_audit_checkpoints(&self) -> StorageResult<Vec<StoredAuditCheckpoint>> {
+        Ok(self
+            .reader()
+            .call(|conn| {
+                Ok(conn
+                    .prepare(
+                        r#"select event_id, hash, timestamp, signature
+                           from audit_checkpoints
+                           order by id"#,
+                    )?
+                    .query_map([], |row| {
+                        Ok(StoredAuditCheckpoint {
+                            event_id: row.get(0)?,
+                            hash: row.get(1)?,
+                            timestamp: row.get(2)?,
+                            signature: row.get(3)?,
+                        })
+                    })?
+                    .collect::<rusqlite::Result<Vec<_>>>())
+            })
+            .await??)
+    }
+
+    async fn count(&self, now: i64) -> StorageResult<StoredCounts> {
+        Ok(self
+            .reader()
+            .call(move |conn| {
+                let mut counts = conn.query_row(
+                    r#"select
+                         (select count(*) from sessions where expires_at > ?1),
+                         (select count(*) from sessions where expires_at <= ?1),
+                         (select count(*) from users),
+                         (select count(*) from users
+                          where not exists (select 1 from credentials where user = users.id))"#,
+                    (now,),
+                    |row| {
+                        Ok(StoredCounts {
+                            active_sessions: row.get(0)?,
+                            expired_sessions: row.get(1)?,
+                            users: row.get(2)?,
+                            users_without_credentials: row.get(3)?,
+                            ..Default::default()
+                        })
+                    },
+                )?;
+                counts.credentials = conn
+                    .prepare(
+                        r#"select relying_party, backup_eligible, count(*)
+                           from credentials
+                           group by relying_party, backup_eligible"#,
+                    )?
+                    .query_map([], |row| Ok(((row.get(0)?, row.get(1)?), row.get(2)?)))?
+                    .collect::<rusqlite::Result<_>>()?;
+                Ok(counts)
+            })
+            .await?)
+    }
+
+    async fn get_metadata(&self, key: &str) -> StorageResult<Option<String>> {
+        let key = key.to_string();
+
+        Ok(self
+            .reader()
+            .call(move |conn| {
+                Ok(conn
+                    .query_row(
+                        r#"select value from metadata where key = ?1"#,
+                        (key,),
+                        |row| row.get::<_, String>(0),
+                    )
+                    .optional())
+            })
+            .await??)
+    }
+
+    async fn set_metadata(&self, key: &str, value: &str) -> StorageResult<()> {
+        let (key, value) = (key.to_string(), value.to_string());
+
+        self.db
+            .call(move |conn| {
+                Ok(conn.execute(
+                    r#"insert or replace into metadata (key, value) values (?1, ?2)"#,
+                    (key, value),
+                ))
+            })
+            .await??;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::test_storage;
+
+    #[tokio::test]
+    async fn test_sqlite_storage() {
+        test_storage(&SqliteStorage::open(":memory:").await.unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_storage_with_readers() {
+        let path = std::env::temp_dir().join(format!("webauthn-tiny-{}.db", uuid::Uuid::new_v4()));
+
+        let storage = SqliteStorage::open(&path).await.unwrap();
+        assert_eq!(storage.readers.len(), READ_CONNECTIONS);
+        test_storage(&storage).await;
+        drop(storage);
+
+        for suffix in ["", "-wal", "-shm"] {
+            _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+        }
+    }
+}
@@ -0,0 +1,72 @@
+//! Notifications to the systemd service manager, see sd_notify(3). They are only sent when the
+//! service is started with `Type=notify`, which sets `NOTIFY_SOCKET`.
+
+use std::{
+    os::{
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixDatagram},
+    },
+    time::Duration,
+};
+
+fn notify_to(socket: &str, state: &str) -> std::io::Result<()> {
+    // Sockets starting with @ are in the abstract namespace.
+    let addr = match socket.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(socket)?,
+    };
+
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)?;
+
+    Ok(())
+}
+
+/// Sends the state, like `READY=1`, to the service manager if there is one.
+pub fn notify(state: &str) -> std::io::Result<()> {
+    match std::env::var("NOTIFY_SOCKET") {
+        Ok(socket) => notify_to(&socket, state),
+        Err(_) => Ok(()),
+    }
+}
+
+/// The interval to send `WATCHDOG=1` at if the service manager expects it, which is half of its
+/// timeout as recommended by sd_watchdog_enabled(3).
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+
+    std::env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse::<u64>().ok())
+        .filter(|usec| *usec > 0)
+        .map(|usec| Duration::from_micros(usec) / 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notify_to() {
+        let path = std::env::temp_dir().join(format!("webauthn-tiny-{}", uuid::Uuid::new_v4()));
+        let socket = UnixDatagram::bind(&path).unwrap();
+
+        notify_to(path.to_str().unwrap(), "READY=1").unwrap();
+        let mut buf = [0; 16];
+        let n = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+
+        let name = format!("webauthn-tiny-{}", uuid::Uuid::new_v4());
+        let abstract_socket =
+            UnixDatagram::bind_addr(&SocketAddr::from_abstract_name(&name).unwrap()).unwrap();
+        notify_to(&format!("@{name}"), "STOPPING=1").unwrap();
+        let n = abstract_socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"STOPPING=1");
+
+        assert!(notify_to(path.with_extension("missing").to_str().unwrap(), "READY=1").is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
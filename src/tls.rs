@@ -0,0 +1,212 @@
+//! HTTPS served directly with rustls, for deployments without a reverse proxy in front.
+
+use crate::config::Reloadable;
+use axum::{
+    http::{uri::Authority, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Redirect, Response},
+    Router,
+};
+use rustls::{
+    crypto::ring,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    ServerConfig,
+};
+use std::{
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+use tracing::{debug, error, info};
+
+/// Time a client has to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Interval between checks of the certificate and key files for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Loads the PEM certificate chain and private key into a server configuration.
+pub fn load_config(cert: &Path, key: &Path) -> anyhow::Result<ServerConfig> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| anyhow::anyhow!("reading TLS certificate {}: {err}", cert.display()))?;
+    if certs.is_empty() {
+        anyhow::bail!(
+            "TLS certificate {} contains no certificates",
+            cert.display()
+        );
+    }
+    let key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|err| anyhow::anyhow!("reading TLS key {}: {err}", key.display()))?;
+
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| anyhow::anyhow!("TLS certificate and key: {err}"))?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(config)
+}
+
+fn modified(paths: &[&Path]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// Reloads the certificate and key when either file changes, such as after a renewal. Files
+/// that cannot be loaded are reported and the previous certificate is kept.
+pub fn watch(cert: PathBuf, key: PathBuf, config: Reloadable<ServerConfig>) {
+    tokio::spawn(async move {
+        let mut last_modified = modified(&[&cert, &key]);
+        let mut interval = tokio::time::interval(WATCH_INTERVAL);
+        loop {
+            interval.tick().await;
+            let current = modified(&[&cert, &key]);
+            if current == last_modified {
+                continue;
+            }
+            last_modified = current;
+
+            match load_config(&cert, &key) {
+                Ok(new_config) => {
+                    config.set(new_config);
+                    info!("reloaded TLS certificate {}", cert.display());
+                }
+                Err(err) => {
+                    error!("failed to reload TLS certificate, keeping the previous one: {err}")
+                }
+            }
+        }
+    });
+}
+
+/// A listener yielding connections that completed the TLS handshake. Handshakes run in their own
+/// tasks, so that slow clients do not hold up accepting other connections, and use the current
+/// certificate.
+pub struct TlsListener {
+    connections: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl TlsListener {
+    pub fn new(listener: TcpListener, config: Reloadable<ServerConfig>) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (sender, connections) = mpsc::channel(64);
+
+        tokio::spawn(async move {
+            while !sender.is_closed() {
+                let (stream, addr) = match listener.accept().await {
+                    Ok(connection) => connection,
+                    Err(err) => {
+                        // Errors like running out of file descriptors are usually transient.
+                        error!("accepting connection: {err}");
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+
+                let acceptor = TlsAcceptor::from(config.get());
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => _ = sender.send((stream, addr)).await,
+                        Ok(Err(err)) => debug!("TLS handshake with {addr} failed: {err}"),
+                        Err(_) => debug!("TLS handshake with {addr} timed out"),
+                    }
+                });
+            }
+        });
+
+        Ok(Self {
+            connections,
+            local_addr,
+        })
+    }
+}
+
+impl axum::serve::Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.connections.recv().await {
+            Some(connection) => connection,
+            // The accepting task only stops once the listener is dropped.
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+/// Returns the HTTPS URL for a plain HTTP request, on the same host.
+fn https_url(headers: &HeaderMap, uri: &Uri, https_port: u16) -> Option<String> {
+    let host = headers
+        .get(axum::http::header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| host.parse::<Authority>().ok())?;
+    let port = if https_port == 443 {
+        String::new()
+    } else {
+        format!(":{https_port}")
+    };
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+
+    Some(format!("https://{}{port}{path}", host.host()))
+}
+
+/// Serves redirects of every request to the same URL over HTTPS on the given port.
+pub async fn redirect_to_https(listener: TcpListener, https_port: u16) -> io::Result<()> {
+    let router = Router::new().fallback(move |headers: HeaderMap, uri: Uri| async move {
+        let response: Response = match https_url(&headers, &uri, https_port) {
+            Some(url) => Redirect::permanent(&url).into_response(),
+            None => StatusCode::BAD_REQUEST.into_response(),
+        };
+        response
+    });
+
+    axum::serve(listener, router).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn test_https_url() {
+        let headers = |host: &str| {
+            HeaderMap::from_iter([(
+                axum::http::header::HOST,
+                HeaderValue::from_str(host).unwrap(),
+            )])
+        };
+        let uri = "/credentials?foo=bar".parse::<Uri>().unwrap();
+
+        assert_eq!(
+            https_url(&headers("auth.foo.com"), &uri, 443).as_deref(),
+            Some("https://auth.foo.com/credentials?foo=bar")
+        );
+        assert_eq!(
+            https_url(&headers("auth.foo.com:80"), &uri, 8443).as_deref(),
+            Some("https://auth.foo.com:8443/credentials?foo=bar")
+        );
+        assert_eq!(
+            https_url(&headers("[::1]:8080"), &"/".parse().unwrap(), 443).as_deref(),
+            Some("https://[::1]/")
+        );
+        assert_eq!(https_url(&HeaderMap::new(), &uri, 443), None);
+        assert_eq!(https_url(&headers("foo.com/bar"), &uri, 443), None);
+    }
+}